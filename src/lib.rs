@@ -1,16 +1,43 @@
 use kqueue2_sys::{kqueue, kevent};
-use libc::{pid_t, timespec, uintptr_t};
+#[cfg(not(target_os = "macos"))]
+use kqueue2_sys::EventListSize;
+use libc::{c_void, pid_t, timespec};
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::convert::{AsRef, Into};
 use std::default::Default;
+use std::fmt;
+use std::ops::ControlFlow;
 use std::fs::File;
-use std::io::{self, Error, Result};
-use std::path::Path;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Error, Read, Result, Seek, SeekFrom};
+use std::mem::MaybeUninit;
+use std::path::{Path, PathBuf};
+use std::process::Child;
 use std::ptr;
-use std::time::Duration;
-use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{AsRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::os::unix::process::ExitStatusExt;
 
 pub use kqueue2_sys::constants::*;
 
+/// What a watch is on.
+///
+/// Descriptors are identified by number, as the kernel does: once a watched
+/// fd is closed its number may be handed out again, and nothing tells the
+/// old and the new descriptor apart. The watcher drops its stale entries
+/// when it opens (or is handed) a descriptor with a number still in use by
+/// one, and reports borrowed descriptors closed behind its back as
+/// `EventData::Invalidated` once `watch()` finds them closed or
+/// `Watcher::prune` is called, but a borrowed fd closed and reused in
+/// between is indistinguishable from the original. Remove
+/// watches before closing their descriptors.
 #[derive(Debug, Eq, Clone)]
 pub enum Ident {
     Filename(RawFd, String),
@@ -18,75 +45,634 @@ pub enum Ident {
     Pid(pid_t),
     Signal(i32),
     Timer(i32),
+    /// The virtual memory system as a whole (macOS only), see
+    /// `Watcher::add_vm_pressure`.
+    #[cfg(target_os = "macos")]
+    Vm,
+    /// A user event, see `Watcher::add_user`.
+    #[cfg(any(target_os = "freebsd", target_os = "dragonfly"))]
+    User(usize),
+    /// Filesystems as a whole, see `Watcher::add_fs`.
+    #[cfg(any(target_os = "freebsd", target_os = "macos", target_os = "dragonfly"))]
+    Fs,
 }
 
+/// An ident as a `poll_grouped` key. Unlike `Ident`'s `==`, which matches
+/// an fd against a filename opened as it and a pid against a timer of the
+/// same number, keys are equal only for the same kind of ident: the same
+/// path, or the same value.
+#[derive(Debug, Clone)]
+pub struct GroupKey(pub Ident);
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct Watched {
     filter: EventFilter,
     flags: FilterFlag,
     ident: Ident,
+    persistent: bool,
+    owned: bool,
+    reap: bool,
+    data: i64,
+    event_flags: EventFlag,
+    deadline: Option<Instant>,
+    key: usize,
+    creating: Option<(String, FilterFlag)>,
+    triggers: usize,
+    udata: usize,
+    clear: Option<bool>,
+    device: bool,
+    pgid: Option<pid_t>,
+    leeway: Option<Duration>,
+    ack: bool,
+    tree: Option<usize>,
+    shutdown: bool,
+    lines: bool,
+}
+
+/// Refers to one registration in the `Watcher` that created it.
+///
+/// Every `add_*` method returns one. Keys can only be used with the watcher
+/// that issued them (not even with its clones), so a stale or hand-built
+/// ident can't accidentally address an unrelated watch.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct WatchKey {
+    watcher: usize,
+    id: usize,
 }
 
+/// A kqueue and the set of things watched through it.
+///
+/// `Watcher` is `Send` but not `Sync`: polling takes `&self` yet updates
+/// buffered events and the watch set (reopening files, reaping children,
+/// ...) through unsynchronized interior mutability. To drain events on some
+/// threads while registering on others, share a `SharedWatcher` instead.
+///
+/// Watches added with empty `FilterFlag`s get the filter's default notes
+/// instead, see `default_flags`, so a vnode or process watch fires without
+/// naming any. Notes the filter doesn't understand are rejected with
+/// `InvalidInput`. Notes of different filters share values, though
+/// (`NOTE_DELETE` is `NOTE_LOWAT`), so a note of another filter that has
+/// the value of a valid one passes as that one.
+///
+/// `clone()` panics if the queue descriptor can't be duplicated, which
+/// running out of file descriptors is enough for. Servers and anything else
+/// that may hit the descriptor limit should call `try_clone` and handle the
+/// error instead.
 #[derive(Debug)]
 pub struct Watcher {
-    watched: Vec<Watched>,
+    watched: RefCell<Vec<Watched>>,
     queue: RawFd,
     started: bool,
     opts: KqueueOpts,
+    id: usize,
+    next_key: Cell<usize>,
+    pending: RefCell<VecDeque<Event>>,
+    watch_errors: Vec<(Ident, Error)>,
+    unacked: RefCell<Vec<Owed>>,
+    shutdown: RefCell<Option<ShutdownToken>>,
+    tap: RefCell<Option<Tap>>,
+    emptied: Cell<bool>,
+    lines: RefCell<HashMap<RawFd, Vec<u8>>>,
+    // leeway of each timer ident, last seen by `to_kevent`
+    #[cfg(target_os = "macos")]
+    leeways: RefCell<HashMap<usize, u64>>,
+    #[cfg(any(test, feature = "debug_counters"))]
+    syscalls: Cell<u64>,
+}
+
+/// Observes events on their way out, see `Watcher::set_tap`.
+pub type EventTap = Box<dyn FnMut(&Event) + Send>;
+
+/// The files `Watcher::add_dir_contents` couldn't open, with the error for
+/// each.
+pub type OpenFailures = Vec<(PathBuf, Error)>;
+
+struct Tap(EventTap);
+
+impl fmt::Debug for Tap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Tap")
+    }
+}
+
+// A delivered kevent kept for re-delivery; the udata pointer is stored as an
+// integer so the watcher stays `Send`
+#[derive(Debug, Clone, Copy)]
+struct Owed {
+    ident: usize,
+    filter: EventFilter,
+    flags: EventFlag,
+    fflags: FilterFlag,
+    data: i64,
+    udata: usize,
+}
+
+impl Owed {
+    fn new(kev: &kevent) -> Owed {
+        Owed {
+            ident: kev.ident,
+            filter: kev.filter,
+            flags: kev.flags,
+            fflags: kev.fflags,
+            data: kev.data,
+            udata: kev.udata as usize,
+        }
+    }
+
+    fn to_kevent(self) -> kevent {
+        kevent {
+            ident: self.ident,
+            filter: self.filter,
+            flags: self.flags,
+            fflags: self.fflags,
+            data: self.data,
+            udata: to_udata(self.udata),
+        }
+    }
 }
 
+/// What happened to a file watched with `EVFILT_VNODE`.
+///
+/// The kernel only reports *what* changed: none of the supported platforms
+/// tells which process caused a vnode event, neither through the event
+/// data nor any `NOTE_*` extension. Audit tooling has to correlate events
+/// with `audit(4)` records (or, on macOS, the Endpoint Security framework)
+/// to learn the actor.
+///
+/// Nor does it count: vnode events carry no data, and changes that happen
+/// before the event is polled coalesce into a single event, so two quick
+/// writes are usually reported as one `Write`. Consumers that must not miss
+/// a change re-check the file's state (size, mtime, contents) on every event
+/// rather than counting events; see `Watcher::add_filename_level`.
 #[derive(Debug)]
 pub enum Vnode {
+    /// A file watched with `Watcher::watch_path_create` appeared and is
+    /// being watched now.
+    Create,
+    /// The watched path was replaced by a new file (delete or rename over
+    /// it) and a persistent watch has been moved onto the new file.
+    Replace,
     Delete,
-    Write,
-    Extend,
+    /// The file was written to (`NOTE_WRITE`). `new_size` is its size when
+    /// the event was read, see `Watcher::skip_vnode_sizes`.
+    Write { new_size: u64 },
+    /// The file grew (`NOTE_EXTEND`), to `new_size` bytes when the event was
+    /// read.
+    Extend { new_size: u64 },
+    /// The file was truncated (`NOTE_TRUNCATE`, OpenBSD only).
+    #[cfg(target_os = "openbsd")]
     Truncate,
     Attrib,
     Link,
     Rename,
     Revoke,
+    /// The file was opened (`NOTE_OPEN`, FreeBSD only).
+    #[cfg(target_os = "freebsd")]
+    Open,
+    /// A descriptor opened read-only was closed (`NOTE_CLOSE`, FreeBSD
+    /// only).
+    #[cfg(target_os = "freebsd")]
+    Close,
+    /// A descriptor opened for writing was closed (`NOTE_CLOSE_WRITE`,
+    /// FreeBSD only).
+    #[cfg(target_os = "freebsd")]
+    CloseWrite,
+    /// The file was read (`NOTE_READ`, FreeBSD only).
+    #[cfg(target_os = "freebsd")]
+    Read,
+    /// Notes this crate doesn't decode on this platform, as raw bits.
+    Other(u32),
 }
 
 #[derive(Debug)]
 pub enum Proc {
+    /// The process exited. For watches added with `Watcher::add_child` this
+    /// is the `wait(2)` status of the reaped child (see `libc::WEXITSTATUS`
+    /// and friends), otherwise the kernel-provided exit data.
     Exit(usize),
     Fork,
     Exec,
+    /// `NOTE_TRACK` was reported, carrying the kernel's data. Process
+    /// tracking (this, `Trackerr` and `Child`) is available everywhere but
+    /// on macOS, which rejects `NOTE_TRACK`.
+    #[cfg(not(target_os = "macos"))]
     Track(libc::pid_t),
+    /// A forked child could not be tracked.
+    #[cfg(not(target_os = "macos"))]
     Trackerr,
+    /// A child of a process watched with `NOTE_TRACK` forked and is now
+    /// watched itself; carries the parent's pid.
+    #[cfg(not(target_os = "macos"))]
     Child(libc::pid_t),
+    /// Notes this crate doesn't decode on this platform, as raw bits.
+    Other(u32),
+}
+
+/// How a process ended, decoded from `Proc::Exit` by `Proc::exit_info`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExitInfo {
+    /// The exit code, if the process exited normally.
+    pub code: Option<i32>,
+    /// The signal that terminated the process, if one did.
+    pub signal: Option<i32>,
+    /// Why the kernel terminated the process, if it did so itself (macOS
+    /// only).
+    #[cfg(target_os = "macos")]
+    pub detail: Option<ExitDetail>,
+}
+
+/// Reasons macOS reports for terminating a process (`NOTE_EXIT_DETAIL`).
+#[cfg(target_os = "macos")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitDetail {
+    /// Decrypting the executable failed.
+    DecryptFail,
+    /// The process was killed to relieve memory pressure.
+    Memory,
+    /// The process violated code signing.
+    CodeSigning,
+}
+
+/// Memory pressure levels reported by `EVFILT_VM` (macOS only).
+#[cfg(target_os = "macos")]
+#[derive(Debug)]
+pub enum Vm {
+    /// The system is short on memory and caches should be trimmed.
+    Pressure,
+    /// Pressure is high enough that the process may be asked to exit.
+    Terminate,
+    /// The process is about to be killed to relieve pressure.
+    SuddenTerminate,
+    /// The kernel failed to track the pressure state.
+    Error,
+}
+
+/// Filesystem events reported by `EVFILT_FS`, see `Watcher::add_fs`.
+///
+/// The kernel may report several conditions at once; the first matching
+/// one in declaration order is reported.
+#[cfg(any(target_os = "freebsd", target_os = "macos", target_os = "dragonfly"))]
+#[derive(Debug)]
+pub enum Fs {
+    Mount,
+    Unmount,
+    /// A mounted filesystem was updated (e.g. remounted read-only). macOS only.
+    Update,
+    LowDisk,
+    /// Free space dropped below the critical threshold. macOS only.
+    VeryLowDisk,
+    /// A network filesystem server stopped responding.
+    NotResponding,
+    NeedAuth,
+    Dead,
+    Assist,
+    /// Conditions this crate doesn't decode, as raw `VQ_*` bits.
+    Other(u32),
 }
 
+#[cfg(any(target_os = "freebsd", target_os = "macos", target_os = "dragonfly"))]
+const VQ_NOTRESP: u32 = 0x0001;
+#[cfg(any(target_os = "freebsd", target_os = "macos", target_os = "dragonfly"))]
+const VQ_NEEDAUTH: u32 = 0x0002;
+#[cfg(any(target_os = "freebsd", target_os = "macos", target_os = "dragonfly"))]
+const VQ_LOWDISK: u32 = 0x0004;
+#[cfg(any(target_os = "freebsd", target_os = "macos", target_os = "dragonfly"))]
+const VQ_MOUNT: u32 = 0x0008;
+#[cfg(any(target_os = "freebsd", target_os = "macos", target_os = "dragonfly"))]
+const VQ_UNMOUNT: u32 = 0x0010;
+#[cfg(any(target_os = "freebsd", target_os = "macos", target_os = "dragonfly"))]
+const VQ_DEAD: u32 = 0x0020;
+#[cfg(any(target_os = "freebsd", target_os = "macos", target_os = "dragonfly"))]
+const VQ_ASSIST: u32 = 0x0040;
+#[cfg(target_os = "macos")]
+const VQ_UPDATE: u32 = 0x0100;
+#[cfg(target_os = "macos")]
+const VQ_VERYLOWDISK: u32 = 0x0200;
+
+// NOTE_EXIT_DETAIL reasons, which kqueue2-sys has no constants for
+#[cfg(target_os = "macos")]
+const NOTE_EXIT_DECRYPTFAIL: u32 = 0x0001_0000;
+#[cfg(target_os = "macos")]
+const NOTE_EXIT_MEMORY: u32 = 0x0002_0000;
+#[cfg(target_os = "macos")]
+const NOTE_EXIT_CSERROR: u32 = 0x0004_0000;
+
 // These need to be OS specific
 #[derive(Debug)]
 pub enum EventData {
     Vnode(Vnode),
     Proc(Proc),
+    /// The descriptor is readable, carrying the bytes available when the
+    /// event was reported (0 at end of file once everything is read).
+    ///
+    /// Watches are edge-triggered by default (`EV_CLEAR`): after a partial
+    /// read the event doesn't fire again until *more* data arrives, so the
+    /// rest would sit unread. Read until the descriptor would block (see
+    /// `drain_fully`) or watch it level-triggered with
+    /// `Watcher::add_read_level`.
     ReadReady(usize),
+    /// The descriptor is writable, carrying the free space in its send
+    /// buffer (for sockets and pipes) in bytes. A socket whose connection
+    /// failed or broke reports the socket error as `Error` instead.
     WriteReady(usize),
+    /// A watched signal was delivered, carrying how many times since it was
+    /// last reported (at least 1); kqueue coalesces repeated deliveries.
+    /// None of the supported kernels pass siginfo (sender pid, uid, ...)
+    /// through `EVFILT_SIGNAL`, so the sender can't be told from this.
     Signal(usize),
+    /// A timer fired, carrying how many times it expired since it was last
+    /// reported (at least 1). More than one means a periodic timer ticked
+    /// again before its event was drained; see `Event::timer_overrun`.
     Timer(usize),
+    /// The system came under memory pressure (macOS only).
+    #[cfg(target_os = "macos")]
+    VmPressure(Vm),
+    /// A user event fired, carrying how many `Watcher::trigger` calls were
+    /// coalesced into this delivery (at least 1).
+    #[cfg(any(target_os = "freebsd", target_os = "dragonfly"))]
+    User(usize),
+    #[cfg(any(target_os = "freebsd", target_os = "macos", target_os = "dragonfly"))]
+    Fs(Fs),
+    /// Out-of-band data is pending on a socket watched with
+    /// `Watcher::add_except` (DragonFly only).
+    #[cfg(target_os = "dragonfly")]
+    Except,
+    /// A borrowed descriptor (see `Watcher::add_file`) was closed behind the
+    /// watcher's back, as found by `Watcher::watch` or `Watcher::prune`.
+    /// The kernel dropped its registrations and the watch has been removed;
+    /// nothing more is reported for this ident.
+    Invalidated,
+    /// A `ShutdownToken` of the watcher was signaled. `Watcher::iter` ends
+    /// and `Watcher::run` returns instead of delivering this.
+    Shutdown,
+    /// A complete line read by a watch from `Watcher::add_lines`, without
+    /// its newline.
+    Line(String),
+    /// The last watch went away on its own: a one-shot watch fired, a
+    /// watched process exited, or a deleted or closed file was dropped.
+    /// Reported once, after the event that emptied the set, with the
+    /// queue's descriptor as ident. `Watcher::iter` ends and `Watcher::run`
+    /// returns instead of delivering this.
+    ///
+    /// Removing watches by hand doesn't report it, and neither does a
+    /// watcher with a `shutdown_token`, whose wakeup socket stays watched.
+    WatchSetEmpty,
+    /// An event of a filter this crate doesn't decode, carrying the notes
+    /// (`fflags`) and data the kernel reported with it.
+    Other { notes: u32, data: i64 },
     Error(Error),
 }
 
+/// An event reported by a `Watcher`.
+///
+/// Besides `ident` and `data` it keeps what the kernel reported with them,
+/// through accessors such as `filter` and `udata`. More may be added, so
+/// outside this crate events can't be built with a struct literal nor
+/// matched exhaustively; see `Event::from_parts` to build one by hand.
 #[derive(Debug)]
+#[non_exhaustive]
 pub struct Event {
     pub ident: Ident,
     pub data: EventData,
+    filter: EventFilter,
+    udata: usize,
+    notes: FilterFlag,
+    raw_data: i64,
+    last: bool,
+}
+
+/// A borrowed view of an `Ident`, see `Watcher::poll_ref`.
+#[derive(Debug, PartialEq, Clone)]
+pub enum IdentRef<'a> {
+    Filename(RawFd, Cow<'a, str>),
+    Fd(RawFd),
+    Pid(pid_t),
+    Signal(i32),
+    Timer(i32),
+    #[cfg(target_os = "macos")]
+    Vm,
+    #[cfg(any(target_os = "freebsd", target_os = "dragonfly"))]
+    User(usize),
+    #[cfg(any(target_os = "freebsd", target_os = "macos", target_os = "dragonfly"))]
+    Fs,
+}
+
+/// An `Event` whose ident borrows from the watcher rather than owning a
+/// copy of the watched filename. Like `Event` it can't be built with a
+/// struct literal; convert an `Event` into one instead.
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct EventRef<'a> {
+    pub ident: IdentRef<'a>,
+    pub data: EventData,
+    filter: EventFilter,
+    udata: usize,
+}
+
+/// A `Watcher` that can be shared between threads, e.g. in an `Arc`.
+///
+/// The kernel queue itself is safe to use concurrently; only the watcher's
+/// bookkeeping needs a lock. `poll` therefore waits for the queue to become
+/// readable without holding it, and locks only to collect the event, so
+/// registering through `lock` on one thread never waits for another thread
+/// blocked in `poll`. With several threads polling, each event is delivered
+/// to exactly one of them.
+#[derive(Debug)]
+pub struct SharedWatcher {
+    queue: RawFd,
+    watcher: Mutex<Watcher>,
+}
+
+/// Wakes a watcher up for shutting down, see `Watcher::shutdown_token`.
+#[derive(Debug, Clone)]
+pub struct ShutdownToken {
+    wake: Arc<WakeSocket>,
+}
+
+// The sending end of a shutdown socket pair, closed with the last token
+#[derive(Debug)]
+struct WakeSocket(RawFd);
+
+/// Collects the options of a single fd watch, see `Watcher::watch_fd`.
+///
+/// The filter defaults to `EVFILT_READ` and edge/level triggering to the
+/// watcher's `disable_clears`/`enable_clears` setting.
+#[derive(Debug)]
+pub struct WatchBuilder<'a> {
+    watcher: &'a mut Watcher,
+    watch: Watched,
 }
 
 pub struct EventIter<'a> {
     watcher: &'a Watcher,
+    reported: bool,
 }
 
-#[derive(Debug)]
+/// The timer or signal events of a watcher, see `Watcher::timers` and
+/// `Watcher::signals`.
+pub struct Counts<'a> {
+    watcher: &'a Watcher,
+    filter: EventFilter,
+    done: bool,
+}
+
+/// Follows a growing file, see `Watcher::tail`.
+pub struct Tail<'a> {
+    watcher: &'a Watcher,
+    path: String,
+    file: File,
+    offset: u64,
+    done: bool,
+}
+
+/// The part of a watch set that stays meaningful across restarts: watched
+/// paths and pids, see `Watcher::export_watch_config`. With the `serde`
+/// feature it can be serialized, e.g. to persist a daemon's watch set.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct WatchConfig {
+    pub files: Vec<FileConfig>,
+    pub pids: Vec<PidConfig>,
+}
+
+/// A watched path in a `WatchConfig`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FileConfig {
+    pub path: String,
+    /// The raw `EVFILT_*` value: read, write or vnode.
+    pub filter: i16,
+    /// The raw `NOTE_*` bits.
+    pub flags: u32,
+}
+
+/// A process watched with `EVFILT_PROC` in a `WatchConfig`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PidConfig {
+    pub pid: pid_t,
+    /// The raw `NOTE_*` bits.
+    pub flags: u32,
+}
+
+#[derive(Debug, Clone)]
 pub struct KqueueOpts {
     clear: bool,
+    remove_on_delete: bool,
+    batch_size: usize,
+    max_pending: usize,
+    metrics: Option<MetricsSink>,
+    tree_depth: usize,
+    tree_dirs: usize,
+    quiet_start: bool,
+    eintr: EintrPolicy,
+    retry: RetryPolicy,
+    order: Option<EventOrder>,
+    vnode_sizes: bool,
+}
+
+/// What waiting for events does when a signal handler interrupts
+/// `kevent(2)`, see `Watcher::eintr_policy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EintrPolicy {
+    /// Fail with an `Interrupted` error, the default.
+    Surface,
+    /// Wait again for what is left of the timeout.
+    Retry,
+    /// Wait again up to this many times, then fail like `Surface`.
+    RetryLimited(u32),
+}
+
+impl EintrPolicy {
+    // Whether to wait again after `retries` retries were interrupted too
+    fn retries(&self, retries: u32) -> bool {
+        match *self {
+            EintrPolicy::Surface => false,
+            EintrPolicy::Retry => true,
+            EintrPolicy::RetryLimited(limit) => retries < limit,
+        }
+    }
+}
+
+/// How often `kevent(2)` calls are repeated after transient failures, see
+/// `Watcher::retry_policy`.
+///
+/// Only `ENOMEM` (the kernel is short of memory for knotes) and `EAGAIN` are
+/// transient; every other error is surfaced right away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RetryPolicy {
+    /// Retries after the first failure; 0, the default, never retries.
+    pub retries: u32,
+    /// The pause before each retry.
+    pub backoff: Duration,
+}
+
+impl RetryPolicy {
+    // Whether to try again after `err`, with `retries` retries done already
+    fn retries_after(&self, err: &Error, retries: u32) -> bool {
+        retries < self.retries && is_transient(err)
+    }
+}
+
+fn is_transient(err: &Error) -> bool {
+    match err.raw_os_error() {
+        Some(libc::ENOMEM) | Some(libc::EAGAIN) => true,
+        _ => false,
+    }
 }
 
 impl Default for KqueueOpts {
     fn default() -> KqueueOpts {
-        KqueueOpts { clear: true }
+        KqueueOpts {
+            clear: true,
+            remove_on_delete: false,
+            batch_size: 64,
+            max_pending: 4096,
+            metrics: None,
+            tree_depth: 32,
+            tree_dirs: 1024,
+            quiet_start: false,
+            eintr: EintrPolicy::Surface,
+            retry: RetryPolicy::default(),
+            order: None,
+            vnode_sizes: true,
+        }
+    }
+}
+
+/// Receives counters from a `Watcher`, see `Watcher::metrics`.
+///
+/// Every hook does nothing by default, so a sink only implements what it
+/// records. Hooks run synchronously on the polling thread and should be
+/// cheap.
+pub trait Metrics: Send + Sync {
+    /// An event from `filter` was handed out.
+    fn on_event(&self, _filter: EventFilter) {}
+
+    /// A `kevent(2)` call was made.
+    fn on_syscall(&self) {}
+
+    /// A `kevent(2)` call failed, or the kernel rejected a registration.
+    fn on_error(&self, _err: &Error) {}
+}
+
+#[derive(Clone)]
+struct MetricsSink(Arc<dyn Metrics>);
+
+impl fmt::Debug for MetricsSink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("MetricsSink")
+    }
+}
+
+type EventCmp = dyn Fn(&Event, &Event) -> cmp::Ordering + Send + Sync;
+
+#[derive(Clone)]
+struct EventOrder(Arc<EventCmp>);
+
+impl fmt::Debug for EventOrder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("EventOrder")
     }
 }
 
@@ -98,10 +684,31 @@ impl Into<usize> for Ident {
             Ident::Pid(pid) => pid as usize,
             Ident::Signal(sig) => sig as usize,
             Ident::Timer(timer) => timer as usize,
+            #[cfg(target_os = "macos")]
+            Ident::Vm => 0,
+            #[cfg(any(target_os = "freebsd", target_os = "dragonfly"))]
+            Ident::User(id) => id,
+            #[cfg(any(target_os = "freebsd", target_os = "macos", target_os = "dragonfly"))]
+            Ident::Fs => 0,
         }
     }
 }
 
+impl From<RawFd> for Ident {
+    fn from(fd: RawFd) -> Ident {
+        Ident::Fd(fd)
+    }
+}
+
+/// The file's descriptor, as an `Ident::Fd`; the watcher treats it as
+/// borrowed and matches it against watches on that fd, including ones
+/// opened by filename.
+impl<'a> From<&'a File> for Ident {
+    fn from(file: &'a File) -> Ident {
+        Ident::Fd(file.as_raw_fd())
+    }
+}
+
 impl PartialEq<Ident> for Ident {
     fn eq(&self, other: &Ident) -> bool {
         match self {
@@ -117,7 +724,57 @@ impl PartialEq<Ident> for Ident {
     }
 }
 
+impl PartialEq for GroupKey {
+    fn eq(&self, other: &GroupKey) -> bool {
+        self.0.same_ident(&other.0)
+    }
+}
+
+impl Eq for GroupKey {}
+
+// Consistent with `same_ident`: the variant, then the path or the value
+impl Hash for GroupKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(&self.0).hash(state);
+        match self.0 {
+            Ident::Filename(_, ref name) => name.hash(state),
+            ref ident => ident.as_usize().hash(state),
+        }
+    }
+}
+
 impl Ident {
+    fn as_fd(&self) -> Option<RawFd> {
+        match *self {
+            Ident::Filename(fd, _) | Ident::Fd(fd) => Some(fd),
+            _ => None,
+        }
+    }
+
+    // Whether both refer to the same kernel ident, a filename counting as
+    // the fd it was opened as
+    fn same_source(&self, other: &Ident) -> bool {
+        match (self.as_fd(), other.as_fd()) {
+            (Some(fd), Some(other_fd)) => fd == other_fd,
+            (None, None) => self.same_ident(other),
+            _ => false,
+        }
+    }
+
+    // Stricter than `==`: the variants must agree and filenames are compared
+    // by path only
+    fn same_ident(&self, other: &Ident) -> bool {
+        match (self, other) {
+            (&Ident::Filename(_, ref name), &Ident::Filename(_, ref other_name)) => {
+                name == other_name
+            }
+            _ => {
+                std::mem::discriminant(self) == std::mem::discriminant(other) &&
+                self.as_usize() == other.as_usize()
+            }
+        }
+    }
+
     fn as_usize(&self) -> usize {
         match self {
             &Ident::Filename(fd, _) => fd as usize,
@@ -125,514 +782,8233 @@ impl Ident {
             &Ident::Pid(pid) => pid as usize,
             &Ident::Signal(sig) => sig as usize,
             &Ident::Timer(timer) => timer as usize,
+            #[cfg(target_os = "macos")]
+            &Ident::Vm => 0,
+            #[cfg(any(target_os = "freebsd", target_os = "dragonfly"))]
+            &Ident::User(id) => id,
+            #[cfg(any(target_os = "freebsd", target_os = "macos", target_os = "dragonfly"))]
+            &Ident::Fs => 0,
         }
     }
 }
 
-impl Watcher {
-    pub fn new() -> Result<Watcher> {
-        let queue = unsafe { kqueue() };
-
-        if queue == -1 {
-            Err(Error::last_os_error())
-        } else {
-            Ok(Watcher {
-                watched: Vec::new(),
-                queue: queue,
-                started: false,
-                opts: Default::default(),
-            })
+impl SharedWatcher {
+    pub fn new(watcher: Watcher) -> SharedWatcher {
+        SharedWatcher {
+            queue: watcher.queue,
+            watcher: Mutex::new(watcher),
         }
     }
 
-    pub fn disable_clears(&mut self) -> &mut Self {
-        self.opts.clear = false;
-        self
+    /// Locks the watcher for registering, removing or reconfiguring
+    /// watches. Polling through the guard blocks every other user of the
+    /// lock for the duration of the wait; use `SharedWatcher::poll` instead.
+    ///
+    /// A panic while the lock was held doesn't poison it for good, the
+    /// watcher is handed out regardless.
+    pub fn lock(&self) -> MutexGuard<Watcher> {
+        self.watcher.lock().unwrap_or_else(|err| err.into_inner())
     }
 
-    pub fn add_pid(&mut self,
-                   pid: libc::pid_t,
-                   filter: EventFilter,
-                   flags: FilterFlag)
-                   -> Result<()> {
-        let watch = Watched {
-            filter: filter,
-            flags: flags,
-            ident: Ident::Pid(pid),
-        };
-
-        if !self.watched.contains(&watch) {
-            self.watched.push(watch);
-        }
-
-        Ok(())
-    }
+    /// Waits up to `timeout` for an event (`None` returns immediately, as
+    /// with `Watcher::poll`) without holding the lock while blocked.
+    pub fn poll(&self, timeout: Option<Duration>) -> Result<Option<Event>> {
+        let timeout = timeout.unwrap_or_default();
+        let deadline = Instant::now().checked_add(timeout);
 
-    pub fn add_filename<P: AsRef<Path>>(&mut self,
-                                        filename: P,
-                                        filter: EventFilter,
-                                        flags: FilterFlag)
-                                        -> Result<()> {
-        let file = File::open(filename.as_ref())?;
-        let watch = Watched {
-            filter: filter,
-            flags: flags,
-            ident: Ident::Filename(file.into_raw_fd(),
-                                   filename.as_ref().to_string_lossy().into_owned()),
-        };
+        loop {
+            if let Some(ev) = self.lock().poll(None) {
+                return Ok(Some(ev));
+            }
 
-        if !self.watched.contains(&watch) {
-            self.watched.push(watch);
+            // another thread may take the event first, so recheck on wakeup
+            let wait = deadline.map_or(timeout, |at| at.saturating_duration_since(Instant::now()));
+            if wait == Duration::new(0, 0) || !wait_queue(self.queue, wait)? {
+                return Ok(None);
+            }
         }
-
-        Ok(())
     }
 
-    pub fn add_fd(&mut self, fd: RawFd, filter: EventFilter, flags: FilterFlag) -> Result<()> {
-        let watch = Watched {
-            filter: filter,
-            flags: flags,
-            ident: Ident::Fd(fd),
-        };
-
-        if !self.watched.contains(&watch) {
-            self.watched.push(watch);
-        }
-
-        Ok(())
+    pub fn into_inner(self) -> Watcher {
+        self.watcher.into_inner().unwrap_or_else(|err| err.into_inner())
     }
+}
 
-    pub fn add_file(&mut self, file: &File, filter: EventFilter, flags: FilterFlag) -> Result<()> {
-        self.add_fd(file.as_raw_fd(), filter, flags)
+impl<'a> WatchBuilder<'a> {
+    /// Watches for readability.
+    pub fn read(mut self) -> WatchBuilder<'a> {
+        self.watch.filter = EventFilter::EVFILT_READ;
+        self
     }
 
-    fn delete_kevents(&self, ident: Ident, filter: EventFilter) -> Result<()> {
-        let mut kev: Vec<kevent> = Vec::with_capacity(1);
-        kev.push(kevent {
-            ident: ident.as_usize(),
-            filter: filter,
-            flags: EV_DELETE,
-            fflags: FilterFlag::empty(),
-            data: 0,
-            udata: ptr::null_mut(),
-        });
-
-        let ret = unsafe {
-            kevent(self.queue,
-                   kev.as_ptr(),
-                   kev.len() as i32,
-                   ptr::null_mut(),
-                   0,
-                   ptr::null())
-        };
+    /// Watches for writability.
+    pub fn write(mut self) -> WatchBuilder<'a> {
+        self.watch.filter = EventFilter::EVFILT_WRITE;
+        self
+    }
 
-        match ret {
-            -1 => Err(Error::last_os_error()),
-            _ => Ok(()),
-        }
+    /// Watches for vnode events (`flags`).
+    pub fn vnode(mut self, flags: FilterFlag) -> WatchBuilder<'a> {
+        self.watch.filter = EventFilter::EVFILT_VNODE;
+        self.watch.flags = flags;
+        self
     }
 
-    pub fn remove_pid(&mut self, pid: libc::pid_t, filter: EventFilter) -> Result<()> {
-        let new_watched = self.watched
-            .drain(..)
-            .filter(|x| {
-                if let Ident::Pid(iterpid) = x.ident {
-                    iterpid != pid
-                } else {
-                    true
-                }
-            })
-            .collect();
+    /// Adds raw filter notes, checked against the filter on `submit`.
+    pub fn flags(mut self, flags: FilterFlag) -> WatchBuilder<'a> {
+        self.watch.flags.insert(flags);
+        self
+    }
 
-        self.watched = new_watched;
-        self.delete_kevents(Ident::Pid(pid), filter)
+    /// Only fires once at least `bytes` can be read or written
+    /// (`NOTE_LOWAT`).
+    pub fn low_water(mut self, bytes: usize) -> WatchBuilder<'a> {
+        self.watch.flags.insert(NOTE_LOWAT);
+        self.watch.data = bytes as i64;
+        self
     }
 
-    pub fn remove_filename<P: AsRef<Path>>(&mut self,
-                                           filename: P,
-                                           filter: EventFilter)
-                                           -> Result<()> {
-        let mut fd: RawFd = 0;
-        let new_watched = self.watched
-            .drain(..)
-            .filter(|x| {
-                if let Ident::Filename(iterfd, ref iterfile) = x.ident {
-                    if iterfile == filename.as_ref().to_str().unwrap() {
-                        fd = iterfd;
-                        false
-                    } else {
-                        true
-                    }
-                } else {
-                    true
-                }
-            })
-            .collect();
+    /// Reports each change once (`EV_CLEAR`).
+    pub fn edge(mut self) -> WatchBuilder<'a> {
+        self.watch.clear = Some(true);
+        self
+    }
 
-        self.watched = new_watched;
-        self.delete_kevents(Ident::Fd(fd), filter)
+    /// Keeps reporting for as long as the condition holds (no `EV_CLEAR`).
+    pub fn level(mut self) -> WatchBuilder<'a> {
+        self.watch.clear = Some(false);
+        self
     }
 
-    pub fn remove_fd(&mut self, fd: RawFd, filter: EventFilter) -> Result<()> {
-        let new_watched = self.watched
-            .drain(..)
-            .filter(|x| {
-                if let Ident::Fd(iterfd) = x.ident {
-                    iterfd != fd
-                } else {
-                    true
-                }
-            })
-            .collect();
+    /// Removes the watch after its first event (`EV_ONESHOT`).
+    pub fn oneshot(mut self) -> WatchBuilder<'a> {
+        self.watch.event_flags.insert(EV_ONESHOT);
+        self
+    }
 
-        self.watched = new_watched;
-        self.delete_kevents(Ident::Fd(fd), filter)
+    /// Registers the watch disabled, see `Watcher::enable`.
+    pub fn disabled(mut self) -> WatchBuilder<'a> {
+        self.watch.event_flags.insert(EV_DISABLE);
+        self
     }
 
-    pub fn remove_file(&mut self, file: &File, filter: EventFilter) -> Result<()> {
-        self.remove_fd(file.as_raw_fd(), filter)
+    /// Attaches `token` to the watch, handed back by `Event::token` on each
+    /// of its events and by `Watcher::watch_data`. The token travels through
+    /// the kernel's pointer-sized udata, so on 32-bit platforms only its low
+    /// 32 bits are kept, and it is the same slot `Watcher::add_fd_with_udata`
+    /// fills: a watch carries either a token or a pointer.
+    pub fn token(mut self, token: u64) -> WatchBuilder<'a> {
+        self.watch.udata = token as usize;
+        self
     }
 
-    pub fn watch(&mut self) -> Result<()> {
-        let mut kevs: Vec<kevent> = Vec::new();
-
-        for watched in &self.watched {
-            let raw_ident = match watched.ident {
-                Ident::Fd(fd) => fd as uintptr_t,
-                Ident::Filename(fd, _) => fd as uintptr_t,
-                Ident::Pid(pid) => pid as uintptr_t,
-                Ident::Signal(sig) => sig as uintptr_t,
-                Ident::Timer(ident) => ident as uintptr_t,
-            };
+    /// Adds the watch, registering it right away if the watcher is already
+    /// started.
+    pub fn submit(self) -> Result<WatchKey> {
+        let WatchBuilder { watcher, mut watch } = self;
+        watch.flags = checked_flags(watch.filter, watch.flags)?;
 
-            kevs.push(kevent {
-                ident: raw_ident,
-                filter: watched.filter,
-                flags: if self.opts.clear {
-                    EV_ADD | EV_CLEAR
-                } else {
-                    EV_ADD
-                },
-                fflags: watched.flags,
-                data: 0,
-                udata: ptr::null_mut(),
-            });
+        if watcher.started {
+            watcher.submit(&[watcher.to_kevent(&watch)])?;
         }
+        Ok(watcher.push_watch(watch))
+    }
+}
 
-        let ret = unsafe {
-            kevent(self.queue,
-                   kevs.as_ptr(),
-                   kevs.len() as i32,
-                   ptr::null_mut(),
-                   0,
-                   ptr::null())
-        };
+// Waits for the queue to have events, false on timeout; an interrupted wait
+// counts as a wakeup
+fn wait_queue(queue: RawFd, timeout: Duration) -> Result<bool> {
+    let millis = timeout.as_secs()
+        .saturating_mul(1000)
+        .saturating_add(u64::from(timeout.subsec_nanos() + 999_999) / 1_000_000);
+    let mut pfd = libc::pollfd {
+        fd: queue,
+        events: libc::POLLIN,
+        revents: 0,
+    };
 
-        self.started = true;
-        match ret {
-            -1 => Err(Error::last_os_error()),
-            _ => Ok(()),
+    match unsafe { libc::poll(&mut pfd, 1, cmp::min(millis, i32::MAX as u64) as i32) } {
+        -1 => {
+            let err = Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                Ok(true)
+            } else {
+                Err(err)
+            }
         }
+        0 => Ok(false),
+        _ => Ok(true),
     }
+}
 
-    pub fn poll(&self, timeout: Option<Duration>) -> Option<Event> {
+impl<'a> IdentRef<'a> {
+    pub fn to_ident(&self) -> Ident {
+        match *self {
+            IdentRef::Filename(fd, ref name) => Ident::Filename(fd, name.clone().into_owned()),
+            IdentRef::Fd(fd) => Ident::Fd(fd),
+            IdentRef::Pid(pid) => Ident::Pid(pid),
+            IdentRef::Signal(sig) => Ident::Signal(sig),
+            IdentRef::Timer(timer) => Ident::Timer(timer),
+            #[cfg(target_os = "macos")]
+            IdentRef::Vm => Ident::Vm,
+            #[cfg(any(target_os = "freebsd", target_os = "dragonfly"))]
+            IdentRef::User(id) => Ident::User(id),
+            #[cfg(any(target_os = "freebsd", target_os = "macos", target_os = "dragonfly"))]
+            IdentRef::Fs => Ident::Fs,
+        }
+    }
+}
+
+impl<'a> From<Event> for EventRef<'a> {
+    fn from(ev: Event) -> EventRef<'a> {
+        EventRef {
+            ident: ev.ident.into(),
+            data: ev.data,
+            filter: ev.filter,
+            udata: ev.udata,
+        }
+    }
+}
+
+impl<'a> From<Ident> for IdentRef<'a> {
+    fn from(ident: Ident) -> IdentRef<'a> {
+        match ident {
+            Ident::Filename(fd, name) => IdentRef::Filename(fd, Cow::Owned(name)),
+            Ident::Fd(fd) => IdentRef::Fd(fd),
+            Ident::Pid(pid) => IdentRef::Pid(pid),
+            Ident::Signal(sig) => IdentRef::Signal(sig),
+            Ident::Timer(timer) => IdentRef::Timer(timer),
+            #[cfg(target_os = "macos")]
+            Ident::Vm => IdentRef::Vm,
+            #[cfg(any(target_os = "freebsd", target_os = "dragonfly"))]
+            Ident::User(id) => IdentRef::User(id),
+            #[cfg(any(target_os = "freebsd", target_os = "macos", target_os = "dragonfly"))]
+            Ident::Fs => IdentRef::Fs,
+        }
+    }
+}
+
+impl Watched {
+    fn new(ident: Ident, filter: EventFilter, flags: FilterFlag) -> Watched {
+        Watched {
+            filter: filter,
+            flags: flags,
+            ident: ident,
+            persistent: false,
+            owned: false,
+            reap: false,
+            data: 0,
+            event_flags: EventFlag::empty(),
+            deadline: None,
+            key: 0,
+            creating: None,
+            triggers: 0,
+            udata: 0,
+            clear: None,
+            device: false,
+            pgid: None,
+            leeway: None,
+            ack: false,
+            tree: None,
+            shutdown: false,
+            lines: false,
+        }
+    }
+
+    /// What the watch is registered on. A file still waiting to be created
+    /// (see `Watcher::watch_path_create`) reports its parent directory.
+    pub fn ident(&self) -> &Ident {
+        &self.ident
+    }
+
+    pub fn filter(&self) -> EventFilter {
+        self.filter
+    }
+
+    /// The notes the watch is registered with, after defaults were applied.
+    pub fn flags(&self) -> FilterFlag {
+        self.flags
+    }
+
+    fn same_watch(&self, other: &Watched) -> bool {
+        self.ident == other.ident && self.filter == other.filter && self.flags == other.flags &&
+        self.data == other.data && self.creating == other.creating && self.udata == other.udata &&
+        self.clear == other.clear && self.event_flags == other.event_flags &&
+        self.leeway == other.leeway
+    }
+
+    // The (ident, filter, flags) triple this watch stands for in configs; a
+    // file waiting to be created is represented by its own path
+    fn config_entry(&self) -> (Ident, EventFilter, FilterFlag) {
+        match self.creating {
+            Some((ref path, flags)) => (Ident::Filename(-1, path.clone()), self.filter, flags),
+            None => (self.ident.clone(), self.filter, self.flags),
+        }
+    }
+}
+
+type ConfigEntry = (Ident, EventFilter, FilterFlag);
+
+// Whether two config entries name the same registration, ignoring flags
+fn same_entry(a: &ConfigEntry, b: &ConfigEntry) -> bool {
+    a.1 == b.1 && a.0.same_ident(&b.0)
+}
+
+static NEXT_WATCHER_ID: AtomicUsize = AtomicUsize::new(0);
+
+static FILTER_SUPPORT: Mutex<Vec<(EventFilter, bool)>> = Mutex::new(Vec::new());
+
+// Registers `filter` on a fresh queue; Err only if the probe couldn't be set up
+fn probe_filter(filter: EventFilter) -> Result<bool> {
+    let queue = Watcher::new()?;
+    let root = File::open("/")?;
+
+    let ident = match filter {
+        EventFilter::EVFILT_READ | EventFilter::EVFILT_WRITE | EventFilter::EVFILT_VNODE => {
+            root.as_raw_fd() as usize
+        }
+        EventFilter::EVFILT_PROC => (unsafe { libc::getpid() }) as usize,
+        EventFilter::EVFILT_SIGNAL => libc::SIGURG as usize,
+        _ => 0,
+    };
+    let (fflags, data) = match filter {
+        EventFilter::EVFILT_TIMER => timer_data(Duration::from_secs(3600)),
+        _ => (default_flags(filter), 0),
+    };
+
+    let probe = queue.submit(&[kevent {
+                                   ident: ident,
+                                   filter: filter,
+                                   flags: EV_ADD,
+                                   fflags: fflags,
+                                   data: data,
+                                   udata: to_udata(0),
+                               }]);
+
+    match probe {
+        Err(ref err) if err.raw_os_error() == Some(libc::EINVAL) ||
+                        err.raw_os_error() == Some(libc::ENOTSUP) => Ok(false),
+        _ => Ok(true),
+    }
+}
+
+/// Why `Watcher::new` couldn't create a kernel queue, carried inside the
+/// `io::Error` it returns (of the same kind) for callers that want to tell
+/// it apart:
+///
+/// ```no_run
+/// # use kqueue2::{QueueCreationError, Watcher};
+/// if let Err(err) = Watcher::new() {
+///     if let Some(cause) = err.get_ref().and_then(|e| e.downcast_ref::<QueueCreationError>()) {
+///         println!("no kqueue: {}", cause.os_error());
+///     }
+/// }
+/// ```
+#[derive(Debug)]
+pub struct QueueCreationError(Error);
+
+impl QueueCreationError {
+    /// The error `kqueue(2)` failed with.
+    pub fn os_error(&self) -> &Error {
+        &self.0
+    }
+
+    /// Whether the process or the system ran out of descriptors.
+    pub fn out_of_descriptors(&self) -> bool {
+        match self.0.raw_os_error() {
+            Some(libc::EMFILE) | Some(libc::ENFILE) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for QueueCreationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.out_of_descriptors() {
+            write!(f,
+                   "cannot create a kqueue: {}; the queue needs a descriptor of its own, so \
+                    close some or raise RLIMIT_NOFILE",
+                   self.0)
+        } else {
+            write!(f, "cannot create a kqueue: {}", self.0)
+        }
+    }
+}
+
+impl std::error::Error for QueueCreationError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+// The error of a failed kqueue(2), explained if descriptors ran out
+fn queue_error() -> Error {
+    let err = Error::last_os_error();
+    Error::new(err.kind(), QueueCreationError(err))
+}
+
+fn next_watcher_id() -> usize {
+    NEXT_WATCHER_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+impl Watcher {
+    /// Creates a watcher on a new kernel queue.
+    ///
+    /// The queue takes a descriptor of its own, so this fails with a hint
+    /// at the descriptor limit (`RLIMIT_NOFILE`) when the process or the
+    /// system is out of descriptors; the error wraps a `QueueCreationError`.
+    /// The descriptor is close-on-exec, as are the ones the watcher opens
+    /// for watches.
+    pub fn new() -> Result<Watcher> {
+        let queue = unsafe { kqueue() };
+
+        if queue == -1 {
+            Err(queue_error())
+        } else if unsafe { libc::fcntl(queue, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+            let err = Error::last_os_error();
+            unsafe { libc::close(queue) };
+            Err(err)
+        } else {
+            Ok(Watcher {
+                watched: RefCell::new(Vec::new()),
+                queue: queue,
+                started: false,
+                opts: Default::default(),
+                id: next_watcher_id(),
+                next_key: Cell::new(0),
+                pending: RefCell::new(VecDeque::new()),
+                watch_errors: Vec::new(),
+                unacked: RefCell::new(Vec::new()),
+                shutdown: RefCell::new(None),
+                tap: RefCell::new(None),
+                emptied: Cell::new(false),
+                lines: RefCell::new(HashMap::new()),
+                #[cfg(target_os = "macos")]
+                leeways: RefCell::new(HashMap::new()),
+                #[cfg(any(test, feature = "debug_counters"))]
+                syscalls: Cell::new(0),
+            })
+        }
+    }
+
+    /// Same as `new`, which marks the queue descriptor close-on-exec as
+    /// well.
+    #[deprecated(note = "`Watcher::new` already makes the queue close-on-exec")]
+    pub fn new_with_cloexec() -> Result<Watcher> {
+        Watcher::new()
+    }
+
+    /// Probes whether the running kernel supports `filter`, by making a
+    /// throwaway registration on a temporary queue. The answer is cached for
+    /// the life of the process.
+    ///
+    /// This is a best-effort check: only `EINVAL` and `ENOTSUP` count as
+    /// unsupported, and a filter that accepts the probe may still reject
+    /// idents or flags of a real watch.
+    pub fn supports(filter: EventFilter) -> bool {
+        let mut cache = FILTER_SUPPORT.lock().unwrap_or_else(|err| err.into_inner());
+        if let Some(&(_, supported)) = cache.iter().find(|&&(cached, _)| cached == filter) {
+            return supported;
+        }
+
+        match probe_filter(filter) {
+            Ok(supported) => {
+                cache.push((filter, supported));
+                supported
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Creates a second handle onto the same kernel queue by duplicating
+    /// the queue descriptor (close-on-exec, like the original) and copying
+    /// the watch metadata.
+    ///
+    /// Both handles drain from, and register into, the *same* queue: an
+    /// event consumed through one will not be seen by the other. Each handle
+    /// closes its own queue descriptor on drop, but the watched descriptors
+    /// stay owned by the original watcher; dropping the original closes them
+    /// and so removes their registrations for every clone (`is_owned`
+    /// reports `false` for every watch of a clone). Watches added to one
+    /// handle after cloning are not known to the other, which then reports
+    /// their events with the raw descriptor as `Ident::Fd`. The clone
+    /// doesn't accept `WatchKey`s issued by the original. A persistent or
+    /// `watch_path_create` watch the clone moves onto a new file leaves the
+    /// original's descriptor open and owns the new one.
+    pub fn try_clone(&self) -> Result<Watcher> {
+        let queue = unsafe { libc::fcntl(self.queue, libc::F_DUPFD_CLOEXEC, 0) };
+
+        if queue == -1 {
+            Err(Error::last_os_error())
+        } else {
+            let watched = self.watched
+                .borrow()
+                .iter()
+                .cloned()
+                .map(|mut watch| {
+                    watch.owned = false;
+                    watch
+                })
+                .collect();
+
+            Ok(Watcher {
+                watched: RefCell::new(watched),
+                queue: queue,
+                started: self.started,
+                opts: self.opts.clone(),
+                id: next_watcher_id(),
+                next_key: self.next_key.clone(),
+                pending: RefCell::new(VecDeque::new()),
+                watch_errors: Vec::new(),
+                unacked: RefCell::new(Vec::new()),
+                shutdown: RefCell::new(None),
+                tap: RefCell::new(None),
+                emptied: Cell::new(false),
+                lines: RefCell::new(HashMap::new()),
+                #[cfg(target_os = "macos")]
+                leeways: RefCell::new(HashMap::new()),
+                #[cfg(any(test, feature = "debug_counters"))]
+                syscalls: Cell::new(0),
+            })
+        }
+    }
+
+    /// Registers watches level-triggered (without `EV_CLEAR`).
+    ///
+    /// Like every option, this only affects watches submitted afterwards by
+    /// `watch()`; call `reapply()` to switch watches that are already
+    /// registered.
+    pub fn disable_clears(&mut self) -> &mut Self {
+        self.opts.clear = false;
+        self
+    }
+
+    /// Registers watches edge-triggered (with `EV_CLEAR`), the default. See
+    /// `disable_clears` for when this takes effect.
+    pub fn enable_clears(&mut self) -> &mut Self {
+        self.opts.clear = true;
+        self
+    }
+
+    /// Sets how many events are fetched from the kernel per `kevent(2)`
+    /// call (64 by default). Events beyond the one being returned are kept
+    /// in the watcher and handed out by later calls.
+    pub fn batch_size(&mut self, size: usize) -> &mut Self {
+        self.opts.batch_size = cmp::max(1, size);
+        self
+    }
+
+    /// Sorts every batch of events by `cmp` before handing it out.
+    ///
+    /// The kernel returns events that are ready at the same time in no
+    /// particular order, which differs between platforms and runs. Sorting
+    /// applies to the batches of `poll_up_to`, `poll_all`, `poll_iter`,
+    /// `poll_grouped`, `poll_into`, `run` and `watch_and_collect`; `poll`
+    /// and `iter` hand out events one at a time in kernel order. The sort is
+    /// stable, so events comparing equal keep that order.
+    pub fn sort_events_by<F>(&mut self, cmp: F) -> &mut Self
+        where F: Fn(&Event, &Event) -> cmp::Ordering + Send + Sync + 'static
+    {
+        self.opts.order = Some(EventOrder(Arc::new(cmp)));
+        self
+    }
+
+    /// Sorts batches of events by the number of their ident (fd, pid,
+    /// signal, ...), see `sort_events_by`.
+    pub fn sort_events_by_ident(&mut self) -> &mut Self {
+        self.sort_events_by(|a, b| a.ident.as_usize().cmp(&b.ident.as_usize()))
+    }
+
+    /// Caps how many events `wait_for` and `poll_matching` set aside while
+    /// looking for a match (4096 by default).
+    ///
+    /// Once that many are buffered they stop draining the kernel queue, so
+    /// further events stay queued there rather than in memory, and fail with
+    /// a `WouldBlock` error until the buffer is emptied by polling. Hitting the
+    /// cap usually means the awaited events never come while others do.
+    pub fn max_pending(&mut self, events: usize) -> &mut Self {
+        self.opts.max_pending = cmp::max(1, events);
+        self
+    }
+
+    /// Makes `watch()` swallow the events that are ready the moment the
+    /// watches are registered, such as sockets with data already buffered,
+    /// so that the first events polled reflect activity after `watch()`.
+    ///
+    /// The price is that whatever was buffered goes unnoticed until more
+    /// arrives. Only edge-triggered watches (the default) stay quiet; a
+    /// level-triggered one reports again on the next poll for as long as
+    /// its condition holds.
+    ///
+    /// Only the `watch()` that starts the watcher is quiet. Later calls
+    /// (after `add_*`, by `reapply`, ...) leave ready events alone, as do
+    /// events the watcher makes up itself, such as a file appearing.
+    pub fn quiet_start(&mut self) -> &mut Self {
+        self.opts.quiet_start = true;
+        self
+    }
+
+    /// Sets how waiting for events handles being interrupted by a signal
+    /// (`EintrPolicy::Surface` by default).
+    ///
+    /// `kevent(2)` is never restarted after a signal handler ran, whatever
+    /// `SA_RESTART` says, so any caught signal ends the wait. Ignored and
+    /// blocked signals don't interrupt it, which is how signals watched with
+    /// `add_signal` are usually set up. A retried wait keeps the original
+    /// deadline rather than starting the timeout over.
+    pub fn eintr_policy(&mut self, policy: EintrPolicy) -> &mut Self {
+        self.opts.eintr = policy;
+        self
+    }
+
+    /// Sets how `kevent(2)` calls that fail transiently (see `RetryPolicy`)
+    /// are repeated before the error is surfaced; by default they aren't.
+    ///
+    /// This covers waiting for events (polls and `iter`, where the backoff
+    /// counts against the timeout) and registering with `watch()`, which
+    /// also repeats the single changes the kernel rejected transiently.
+    pub fn retry_policy(&mut self, policy: RetryPolicy) -> &mut Self {
+        self.opts.retry = policy;
+        self
+    }
+
+    /// Caps how deep below its root `add_tree` descends (32 levels by
+    /// default) and how many directories it watches in total, each taking a
+    /// descriptor (1024 by default). Directories beyond either cap are
+    /// silently left unwatched.
+    pub fn tree_limits(&mut self, max_depth: usize, max_dirs: usize) -> &mut Self {
+        self.opts.tree_depth = max_depth;
+        self.opts.tree_dirs = max_dirs;
+        self
+    }
+
+    /// Installs `tap`, which sees every event `poll` and `iter` hand out
+    /// just before they do, e.g. to trace all activity while debugging
+    /// without touching the event loop. `None` removes it. Clones don't
+    /// inherit the tap, and the batched polls bypass it.
+    pub fn set_tap(&mut self, tap: Option<EventTap>) {
+        *self.tap.get_mut() = tap.map(Tap);
+    }
+
+    /// Reports events, `kevent(2)` calls and errors of this watcher (and of
+    /// clones made afterwards) to `sink`. Without a sink nothing is counted.
+    pub fn metrics(&mut self, sink: Arc<dyn Metrics>) -> &mut Self {
+        self.opts.metrics = Some(MetricsSink(sink));
+        self
+    }
+
+    /// Ends a vnode watch once its file is deleted.
+    ///
+    /// By default a watch survives `NOTE_DELETE`: the kernel keeps the
+    /// unlinked file alive for as long as the watched fd is open and keeps
+    /// delivering events for it (e.g. writes through other open descriptors).
+    /// With this set, the `Vnode::Delete` event is still delivered, but the
+    /// watch is dropped from the watch set afterwards and an owned fd is
+    /// closed. Persistent path watches are moved to the replacement file
+    /// instead when there is one.
+    pub fn remove_on_delete(&mut self) -> &mut Self {
+        self.opts.remove_on_delete = true;
+        self
+    }
+
+    /// Leaves out the `fstat(2)` that fills in `new_size` of `Vnode::Write`
+    /// and `Vnode::Extend` events, for hot loops that don't look at it.
+    /// `new_size` is 0 then.
+    pub fn skip_vnode_sizes(&mut self) -> &mut Self {
+        self.opts.vnode_sizes = false;
+        self
+    }
+
+    /// Watches process `pid` for `flags` on `filter`.
+    ///
+    /// With `NOTE_EXIT` the exit event carries the process' `wait(2)`
+    /// status, see `Proc::exit_info`. The BSDs always report it, while macOS
+    /// only does so for children of this process when asked with
+    /// `NOTE_EXITSTATUS`; that and `NOTE_EXIT_DETAIL` are added on macOS as
+    /// far as the kernel allows them for `pid`.
+    pub fn add_pid(&mut self,
+                   pid: libc::pid_t,
+                   filter: EventFilter,
+                   flags: FilterFlag)
+                   -> Result<WatchKey> {
+        let mut flags = checked_flags(filter, flags)?;
+        if filter == EventFilter::EVFILT_PROC && flags.contains(NOTE_EXIT) {
+            flags |= exit_notes(pid);
+        }
+        Ok(self.push_watch(Watched::new(Ident::Pid(pid), filter, flags)))
+    }
+
+    /// Watches a child process for `NOTE_EXIT` and reaps it when it exits.
+    ///
+    /// The exit event carries the status collected by `waitpid(2)`, so
+    /// supervisors can spawn and await children without a `SIGCHLD` handler
+    /// racing them. `pid` must be a child of this process.
+    pub fn add_child(&mut self, pid: libc::pid_t) -> Result<WatchKey> {
+        let mut watch = Watched::new(Ident::Pid(pid),
+                                     EventFilter::EVFILT_PROC,
+                                     NOTE_EXIT | exit_notes(pid));
+        watch.reap = true;
+
+        Ok(self.push_watch(watch))
+    }
+
+    /// Watches `child`, spawned with `std::process::Command`, for
+    /// `NOTE_EXIT`. Its exit event has `Ident::Pid(child.id())` as ident and
+    /// carries the exit status like `add_pid`.
+    ///
+    /// The watch is registered right away, even before `watch()`, so an exit
+    /// in between isn't missed. A child that has exited already can't be
+    /// registered anymore; its status is then collected with `try_wait` and
+    /// delivered as the next event, and the returned key refers to no watch.
+    /// The watcher never reaps `child` behind its back otherwise: it stays a
+    /// zombie until it is waited for.
+    pub fn add_process(&mut self, child: &mut Child) -> Result<WatchKey> {
+        let pid = child.id() as pid_t;
+        let mut watch = Watched::new(Ident::Pid(pid),
+                                     EventFilter::EVFILT_PROC,
+                                     NOTE_EXIT | exit_notes(pid));
+
+        match self.submit(&[self.to_kevent(&watch)]) {
+            Ok(()) => Ok(self.push_watch(watch)),
+            Err(ref err) if err.raw_os_error() == Some(libc::ESRCH) => {
+                let status = match child.try_wait()? {
+                    Some(status) => status,
+                    None => return Err(Error::from_raw_os_error(libc::ESRCH)),
+                };
+                self.pending.get_mut().push_back(Event {
+                    ident: Ident::Pid(pid),
+                    data: EventData::Proc(Proc::Exit(status.into_raw() as usize)),
+                    filter: EventFilter::EVFILT_PROC,
+                    udata: 0,
+                    notes: NOTE_EXIT,
+                    raw_data: i64::from(status.into_raw()),
+                    last: true,
+                });
+                watch.key = self.alloc_key();
+
+                Ok(WatchKey {
+                    watcher: self.id,
+                    id: watch.key,
+                })
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Watches every member of process group `pgid` for `NOTE_EXIT`,
+    /// reported per member with the member's pid as ident. FreeBSD and macOS
+    /// only.
+    ///
+    /// Members are enumerated once (via `sysctl(3)` on FreeBSD and libproc on
+    /// macOS); processes they fork later are picked up as they appear, with
+    /// `NOTE_TRACK` on FreeBSD (reported as `Proc::Child`) and by listing the
+    /// group again on each `Proc::Fork` on macOS. This is inherently racy: a
+    /// process forked between the enumeration and `watch()`, or on macOS one
+    /// that exits before the group is listed again, is missed, and members
+    /// that move to another group stay watched. Returns the keys of the
+    /// members found.
+    #[cfg(any(target_os = "freebsd", target_os = "macos"))]
+    pub fn add_pgid(&mut self, pgid: pid_t) -> Result<Vec<WatchKey>> {
+        Ok(group_members(pgid)?
+            .into_iter()
+            .map(|pid| self.push_watch(group_watch(pid, pgid)))
+            .collect())
+    }
+
+    /// Watches for deliveries of `signal` to this process, reported as
+    /// `EventData::Signal` with `Ident::Signal`.
+    ///
+    /// kqueue only observes signals: they are still handled as set up with
+    /// `sigaction(2)`, so ignore or block `signal` to learn about it through
+    /// the watcher alone. Attempts to deliver an ignored signal are still
+    /// reported, except for `SIGCHLD`.
+    pub fn add_signal(&mut self, signal: i32) -> Result<WatchKey> {
+        Ok(self.push_watch(Watched::new(Ident::Signal(signal),
+                                        EventFilter::EVFILT_SIGNAL,
+                                        FilterFlag::empty())))
+    }
+
+    /// Arms a periodic timer that fires every `interval` until it is
+    /// removed.
+    ///
+    /// The timer keeps running while its events aren't drained; the next
+    /// event reports every expiry since the previous one (see
+    /// `Event::timer_overrun`). Timers always re-arm this way, whether or
+    /// not clears are enabled.
+    pub fn add_timer(&mut self, ident: i32, interval: Duration) -> Result<WatchKey> {
+        let (unit, data) = timer_data(interval);
+        let mut watch = Watched::new(Ident::Timer(ident), EventFilter::EVFILT_TIMER, unit);
+        watch.data = data;
+
+        Ok(self.push_watch(watch))
+    }
+
+    /// Arms a periodic timer like `add_timer` that may fire up to `leeway`
+    /// late, letting the kernel coalesce its wakeups with others
+    /// (`NOTE_LEEWAY`, macOS only).
+    ///
+    /// This trades timer precision for battery life, which suits background
+    /// work that needn't run on the dot. Only `kevent64(2)` takes a leeway,
+    /// so on macOS the watcher makes all of its calls through it.
+    #[cfg(target_os = "macos")]
+    pub fn add_timer_leeway(&mut self,
+                            ident: i32,
+                            interval: Duration,
+                            leeway: Duration)
+                            -> Result<WatchKey> {
+        let (unit, data) = timer_data(interval);
+        let mut watch = Watched::new(Ident::Timer(ident),
+                                     EventFilter::EVFILT_TIMER,
+                                     unit | NOTE_LEEWAY);
+        watch.data = data;
+        watch.leeway = Some(leeway);
+
+        Ok(self.push_watch(watch))
+    }
+
+    /// Arms a one-shot timer that fires as close as possible to `at`.
+    ///
+    /// The interval is computed when the timer is actually registered by
+    /// `watch()`, in the finest unit the platform supports, so setup time
+    /// doesn't push the deadline back. An instant that has already passed
+    /// fires right away.
+    pub fn add_timer_deadline(&mut self, ident: i32, at: Instant) -> Result<WatchKey> {
+        let (unit, _) = timer_data(Duration::new(0, 0));
+        let mut watch = Watched::new(Ident::Timer(ident), EventFilter::EVFILT_TIMER, unit);
+        watch.event_flags = EV_ONESHOT;
+        watch.deadline = Some(at);
+
+        Ok(self.push_watch(watch))
+    }
+
+    pub fn add_filename<P: AsRef<Path>>(&mut self,
+                                        filename: P,
+                                        filter: EventFilter,
+                                        flags: FilterFlag)
+                                        -> Result<WatchKey> {
+        let flags = checked_flags(filter, flags)?;
+        let file = File::open(filename.as_ref())?;
+        let mut watch = Watched::new(Ident::Filename(file.into_raw_fd(),
+                                                     filename.as_ref()
+                                                         .to_string_lossy()
+                                                         .into_owned()),
+                                     filter,
+                                     flags);
+        watch.owned = true;
+
+        Ok(self.push_watch(watch))
+    }
+
+    /// Like `add_filename` with `EVFILT_VNODE`, but level-triggered (without
+    /// `EV_CLEAR`) whatever the watcher's default.
+    ///
+    /// An edge-triggered vnode watch reports a change once and then forgets
+    /// it, folding every change made before the next poll into that event. A
+    /// level-triggered one keeps the notes it has seen and reports them on
+    /// every poll until the watch is registered anew (e.g. with `reapply`,
+    /// or by removing and re-adding it), so a change can't slip by between
+    /// polls. It still tells neither how many changes happened nor when
+    /// they stopped: handle each event by re-checking the file.
+    pub fn add_filename_level<P: AsRef<Path>>(&mut self,
+                                              filename: P,
+                                              flags: FilterFlag)
+                                              -> Result<WatchKey> {
+        let flags = checked_flags(EventFilter::EVFILT_VNODE, flags)?;
+        let file = File::open(filename.as_ref())?;
+        let mut watch = Watched::new(Ident::Filename(file.into_raw_fd(),
+                                                     filename.as_ref()
+                                                         .to_string_lossy()
+                                                         .into_owned()),
+                                     EventFilter::EVFILT_VNODE,
+                                     flags);
+        watch.owned = true;
+        watch.clear = Some(false);
+
+        Ok(self.push_watch(watch))
+    }
+
+    /// Watches `path` for changes to its contents: writes, appends and
+    /// truncation. Only OpenBSD reports truncation as such; elsewhere it
+    /// shows up as an attribute change, so permission and ownership changes
+    /// are reported too.
+    pub fn watch_modifications<P: AsRef<Path>>(&mut self, path: P) -> Result<WatchKey> {
+        self.add_filename(path,
+                          EventFilter::EVFILT_VNODE,
+                          NOTE_WRITE | NOTE_EXTEND | truncate_notes())
+    }
+
+    /// Watches `path` for going away: being deleted or renamed.
+    pub fn watch_deletions<P: AsRef<Path>>(&mut self, path: P) -> Result<WatchKey> {
+        self.add_filename(path, EventFilter::EVFILT_VNODE, NOTE_DELETE | NOTE_RENAME)
+    }
+
+    /// Watches `path` for metadata changes such as permissions, ownership
+    /// and timestamps.
+    pub fn watch_metadata<P: AsRef<Path>>(&mut self, path: P) -> Result<WatchKey> {
+        self.add_filename(path, EventFilter::EVFILT_VNODE, NOTE_ATTRIB)
+    }
+
+    /// Watches `path` for being closed after it was opened for writing,
+    /// reported once per such close as `Vnode::CloseWrite`. FreeBSD only.
+    ///
+    /// Unlike `NOTE_WRITE`, which fires on every partial write, this tells
+    /// when a writer is done with the file, e.g. to reload a config file
+    /// once it is completely saved in place. Saves that replace the file by
+    /// renaming are better caught with `watch_path_persistent`.
+    #[cfg(target_os = "freebsd")]
+    pub fn add_close_write<P: AsRef<Path>>(&mut self, path: P) -> Result<WatchKey> {
+        self.add_filename(path, EventFilter::EVFILT_VNODE, NOTE_CLOSE_WRITE)
+    }
+
+    /// Watches `name`, opened relative to the directory `dirfd` with
+    /// `openat(2)`, so that programs holding only directory descriptors
+    /// never need an absolute path. The ident keeps `name` as given.
+    ///
+    /// On macOS the file is opened with `O_EVTONLY`, which doesn't keep its
+    /// volume from being unmounted. An invalid `dirfd` is reported as
+    /// `InvalidInput`.
+    pub fn add_filename_at<P: AsRef<Path>>(&mut self,
+                                           dirfd: RawFd,
+                                           name: P,
+                                           filter: EventFilter,
+                                           flags: FilterFlag)
+                                           -> Result<WatchKey> {
+        let flags = checked_flags(filter, flags)?;
+        let name = name.as_ref();
+        let cname = std::ffi::CString::new(name.as_os_str().as_bytes())
+            .map_err(|err| Error::new(io::ErrorKind::InvalidInput, err))?;
+        let fd = unsafe { libc::openat(dirfd, cname.as_ptr(), event_open_flags()) };
+        if fd == -1 {
+            let err = Error::last_os_error();
+            return Err(match err.raw_os_error() {
+                Some(libc::EBADF) => {
+                    Error::new(io::ErrorKind::InvalidInput,
+                               format!("{} is not an open directory fd", dirfd))
+                }
+                _ => err,
+            });
+        }
+
+        let mut watch = Watched::new(Ident::Filename(fd, name.to_string_lossy().into_owned()),
+                                     filter,
+                                     flags);
+        watch.owned = true;
+
+        Ok(self.push_watch(watch))
+    }
+
+    /// Watches every file currently in directory `dir`, but not `dir`
+    /// itself nor its subdirectories, for `flags` on `filter`, like
+    /// `add_filename` would. Files that show up later aren't picked up;
+    /// watch `dir` for `NOTE_WRITE` to learn about them.
+    ///
+    /// A file that can't be opened doesn't stop the others: the keys of the
+    /// watches added come back in name order, followed by the path and
+    /// error of each file that failed. Only failing to list `dir` is an
+    /// error.
+    pub fn add_dir_contents<P: AsRef<Path>>(&mut self,
+                                            dir: P,
+                                            filter: EventFilter,
+                                            flags: FilterFlag)
+                                            -> Result<(Vec<WatchKey>, OpenFailures)> {
+        let flags = checked_flags(filter, flags)?;
+        let mut paths = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                paths.push(entry.path());
+            }
+        }
+        paths.sort();
+
+        let mut keys = Vec::with_capacity(paths.len());
+        let mut failed = Vec::new();
+        for path in paths {
+            match self.add_filename(&path, filter, flags) {
+                Ok(key) => keys.push(key),
+                Err(err) => failed.push((path, err)),
+            }
+        }
+
+        Ok((keys, failed))
+    }
+
+    /// Watches directory `root` and every directory below it for vnode
+    /// events (`flags`, plus the `NOTE_WRITE` needed to follow changes),
+    /// each reported with the directory's path as ident.
+    ///
+    /// kqueue has no recursive watches, so each directory gets a watch of
+    /// its own. When a watched directory's entries change, it is scanned
+    /// again: new subdirectories (with whatever they already contain) are
+    /// watched right away and the watches of vanished ones are dropped.
+    /// Files aren't watched, only the directories containing them; see
+    /// `tree_limits` for the caps on depth and descriptors.
+    pub fn add_tree<P: AsRef<Path>>(&mut self, root: P, flags: FilterFlag) -> Result<()> {
+        let flags = checked_flags(EventFilter::EVFILT_VNODE, flags)? | NOTE_WRITE;
+        let root = root.as_ref();
+        if !std::fs::metadata(root)?.is_dir() {
+            return Err(Error::new(io::ErrorKind::InvalidInput, "tree root is not a directory"));
+        }
+        // fail early on a root we can't open rather than watching nothing
+        File::open(root)?;
+
+        for watch in walk_tree(self, root, 0, flags) {
+            self.push_watch(watch);
+        }
+        Ok(())
+    }
+
+    /// Opens `filename` once and watches it both for vnode events (`flags`,
+    /// e.g. `NOTE_WRITE | NOTE_EXTEND`) and for `EVFILT_READ`, as a `tail -f`
+    /// would. Returns the keys of the vnode and the read watch.
+    ///
+    /// Both watches share the descriptor, which is closed once the last of
+    /// them is gone.
+    pub fn add_filename_rw<P: AsRef<Path>>(&mut self,
+                                           filename: P,
+                                           flags: FilterFlag)
+                                           -> Result<(WatchKey, WatchKey)> {
+        let flags = checked_flags(EventFilter::EVFILT_VNODE, flags)?;
+        let file = File::open(filename.as_ref())?;
+        let ident = Ident::Filename(file.into_raw_fd(),
+                                    filename.as_ref().to_string_lossy().into_owned());
+
+        let mut vnode = Watched::new(ident.clone(), EventFilter::EVFILT_VNODE, flags);
+        vnode.owned = true;
+        let mut read = Watched::new(ident, EventFilter::EVFILT_READ, FilterFlag::empty());
+        read.owned = true;
+
+        Ok((self.push_watch(vnode), self.push_watch(read)))
+    }
+
+    /// Watches `path` for vnode events (`flags`) with plain "watch this path"
+    /// semantics.
+    ///
+    /// Editors commonly save by writing a temporary file and renaming it over
+    /// the original, which leaves a normal watch stuck on the old, now
+    /// unlinked, file. A persistent watch instead reopens `path` when its file
+    /// is deleted or renamed, re-registers on the new file and reports a
+    /// single `Vnode::Replace` event. `NOTE_DELETE` and `NOTE_RENAME` are
+    /// always added to `flags` for this. If nothing can be opened at `path`
+    /// any more, the original event is delivered and the watch stays on the
+    /// old file.
+    pub fn watch_path_persistent<P: AsRef<Path>>(&mut self,
+                                                 path: P,
+                                                 flags: FilterFlag)
+                                                 -> Result<WatchKey> {
+        let flags = checked_flags(EventFilter::EVFILT_VNODE, flags)?;
+        let file = File::open(path.as_ref())?;
+        let mut watch = Watched::new(Ident::Filename(file.into_raw_fd(),
+                                                     path.as_ref()
+                                                         .to_string_lossy()
+                                                         .into_owned()),
+                                     EventFilter::EVFILT_VNODE,
+                                     flags | NOTE_DELETE | NOTE_RENAME);
+        watch.persistent = true;
+        watch.owned = true;
+
+        Ok(self.push_watch(watch))
+    }
+
+    /// Follows the file at `path` like `tail -f`: the iterator blocks until
+    /// the file grows and yields the bytes appended since the last item,
+    /// starting from its current end. The watch is registered right away,
+    /// calling `watch()` if the watcher isn't started yet.
+    ///
+    /// A truncated file is read again from the start. A file rotated by
+    /// renaming or deleting it and creating a new one at `path` is
+    /// followed by the persistent watch and read from the start; if nothing
+    /// is at `path` any more, the iterator ends once the old file is read.
+    /// Other events that arrive meanwhile are buffered for later polls.
+    pub fn tail<P: AsRef<Path>>(&mut self,
+                                path: P)
+                                -> Result<Tail> {
+        let file = File::open(path.as_ref())?;
+        let offset = file.metadata()?.len();
+        // NOTE_ATTRIB catches truncation, which doesn't count as a write
+        let notes = NOTE_EXTEND | NOTE_WRITE | NOTE_ATTRIB;
+        let key = self.watch_path_persistent(path.as_ref(), notes)?;
+        if self.started {
+            let index = self.key_index(key)?;
+            let kev = self.to_kevent(&self.watched.borrow()[index]);
+            self.submit(&[kev])?;
+        } else {
+            self.watch()?;
+        }
+
+        Ok(Tail {
+            watcher: self,
+            path: path.as_ref().to_string_lossy().into_owned(),
+            file: file,
+            offset: offset,
+            done: false,
+        })
+    }
+
+    /// Watches the device file at `path`, such as a tty, for being revoked
+    /// (see `revoke(2)`), reported as `Vnode::Revoke` with the path as ident.
+    ///
+    /// The device is opened non-blocking and without becoming the
+    /// controlling terminal, so that opening a tty neither hangs nor changes
+    /// the session. FreeBSD and DragonFly hand filters on device files to
+    /// the driver, which for ttys doesn't know vnode events; there the
+    /// device is watched for readability and an end-of-file is reported as
+    /// the revoke.
+    pub fn add_device<P: AsRef<Path>>(&mut self, path: P) -> Result<WatchKey> {
+        let path = path.as_ref();
+        let cpath = std::ffi::CString::new(path.as_os_str().as_bytes())
+            .map_err(|err| Error::new(io::ErrorKind::InvalidInput, err))?;
+        let fd = unsafe {
+            libc::open(cpath.as_ptr(),
+                       libc::O_RDONLY | libc::O_NONBLOCK | libc::O_NOCTTY | libc::O_CLOEXEC)
+        };
+        if fd == -1 {
+            return Err(Error::last_os_error());
+        }
+
+        let (filter, flags) = device_watch();
+        let mut watch = Watched::new(Ident::Filename(fd, path.to_string_lossy().into_owned()),
+                                     filter,
+                                     flags);
+        watch.owned = true;
+        watch.device = true;
+
+        Ok(self.push_watch(watch))
+    }
+
+    /// Creates a pipe and watches its read end for `EVFILT_READ`, returning
+    /// the write end and the watch's key. Handy for waking a poller up or as
+    /// a command channel.
+    ///
+    /// Both ends are close-on-exec and non-blocking. The read end belongs to
+    /// the watcher, which closes it on drop; the write end is the caller's
+    /// to close.
+    pub fn add_pipe(&mut self) -> Result<(RawFd, WatchKey)> {
+        let [read_fd, write_fd] = cloexec_pipe()?;
+        let mut watch = Watched::new(Ident::Fd(read_fd),
+                                     EventFilter::EVFILT_READ,
+                                     FilterFlag::empty());
+        watch.owned = true;
+
+        Ok((write_fd, self.push_watch(watch)))
+    }
+
+    /// Opens the FIFO (named pipe) at `path` without blocking and watches
+    /// it for `filter`: `EVFILT_READ` reports the bytes waiting and the end
+    /// of file (`ReadReady(0)`) once the last writer closes its end,
+    /// `EVFILT_WRITE` the space left in the pipe buffer and `EVFILT_VNODE`
+    /// changes to the FIFO itself.
+    ///
+    /// The FIFO is opened for writing only for `EVFILT_WRITE`; as nothing
+    /// could be read from it, that fails with `NotConnected` while no
+    /// reader has it open. Opening it for reading never waits for a writer,
+    /// but a reader that arrives first may only see the end of file after a
+    /// writer has come and gone.
+    pub fn add_fifo<P: AsRef<Path>>(&mut self,
+                                    path: P,
+                                    filter: EventFilter,
+                                    flags: FilterFlag)
+                                    -> Result<WatchKey> {
+        let flags = checked_flags(filter, flags)?;
+        let path = path.as_ref();
+        if !std::fs::metadata(path)?.file_type().is_fifo() {
+            return Err(Error::new(io::ErrorKind::InvalidInput, "not a FIFO"));
+        }
+
+        let cpath = std::ffi::CString::new(path.as_os_str().as_bytes())
+            .map_err(|err| Error::new(io::ErrorKind::InvalidInput, err))?;
+        let mode = if filter == EventFilter::EVFILT_WRITE {
+            libc::O_WRONLY
+        } else {
+            libc::O_RDONLY
+        };
+        let fd = unsafe { libc::open(cpath.as_ptr(), mode | libc::O_NONBLOCK | libc::O_CLOEXEC) };
+        if fd == -1 {
+            let err = Error::last_os_error();
+            return Err(match err.raw_os_error() {
+                Some(libc::ENXIO) => {
+                    Error::new(io::ErrorKind::NotConnected, "no reader has the FIFO open")
+                }
+                _ => err,
+            });
+        }
+
+        let mut watch = Watched::new(Ident::Filename(fd, path.to_string_lossy().into_owned()),
+                                     filter,
+                                     flags);
+        watch.owned = true;
+
+        Ok(self.push_watch(watch))
+    }
+
+    /// Watches `path` for vnode events (`flags`), waiting for it to be
+    /// created first if it doesn't exist yet.
+    ///
+    /// A missing file is waited for by watching its parent directory; once
+    /// the file shows up the watch moves onto it and a `Vnode::Create` event
+    /// is delivered, after which events flow as for `add_filename`. The
+    /// returned key refers to the watch throughout. Fails if the parent
+    /// directory doesn't exist either.
+    pub fn watch_path_create<P: AsRef<Path>>(&mut self,
+                                             path: P,
+                                             flags: FilterFlag)
+                                             -> Result<WatchKey> {
+        let path = path.as_ref();
+        if path.exists() {
+            return self.add_filename(path, EventFilter::EVFILT_VNODE, flags);
+        }
+
+        let flags = checked_flags(EventFilter::EVFILT_VNODE, flags)?;
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+
+        let dir = File::open(parent).map_err(|err| {
+                Error::new(err.kind(),
+                           format!("cannot wait for {} to be created: parent directory {}: {}",
+                                   path.display(),
+                                   parent.display(),
+                                   err))
+            })?;
+
+        let mut watch = Watched::new(Ident::Filename(dir.into_raw_fd(),
+                                                     parent.to_string_lossy().into_owned()),
+                                     EventFilter::EVFILT_VNODE,
+                                     NOTE_WRITE);
+        watch.owned = true;
+        watch.creating = Some((path.to_string_lossy().into_owned(), flags));
+
+        Ok(self.push_watch(watch))
+    }
+
+    /// Watches `fd` without taking ownership: the watcher never closes it,
+    /// so the caller has to keep it open while it is watched and close it
+    /// afterwards. Only descriptors the watcher opened itself (by filename or
+    /// `add_pipe`) are closed on drop.
+    pub fn add_fd(&mut self,
+                  fd: RawFd,
+                  filter: EventFilter,
+                  flags: FilterFlag)
+                  -> Result<WatchKey> {
+        let flags = checked_flags(filter, flags)?;
+        Ok(self.push_watch(Watched::new(Ident::Fd(fd), filter, flags)))
+    }
+
+    /// Like `add_fd`, but hands `fd` over to the watcher, which closes it
+    /// once its watches are gone and on drop, exactly once.
+    pub fn add_owned_fd(&mut self,
+                        fd: OwnedFd,
+                        filter: EventFilter,
+                        flags: FilterFlag)
+                        -> Result<WatchKey> {
+        let flags = checked_flags(filter, flags)?;
+        let mut watch = Watched::new(Ident::Fd(fd.into_raw_fd()), filter, flags);
+        watch.owned = true;
+
+        Ok(self.push_watch(watch))
+    }
+
+    /// Like `add_fd`, but attaches `udata` to the watch, to be handed back
+    /// by `Event::udata` on each of its events. Meant for FFI bridges
+    /// passing context structs through the kernel.
+    ///
+    /// The watcher never dereferences `udata`, but the pointers it hands
+    /// back are only as good as the caller makes them.
+    ///
+    /// # Safety
+    ///
+    /// `udata` must stay valid until the watch is removed or the watcher is
+    /// dropped, and for as long as any event carrying it is kept around.
+    pub unsafe fn add_fd_with_udata(&mut self,
+                                    fd: RawFd,
+                                    filter: EventFilter,
+                                    flags: FilterFlag,
+                                    udata: *mut c_void)
+                                    -> Result<WatchKey> {
+        let flags = checked_flags(filter, flags)?;
+        let mut watch = Watched::new(Ident::Fd(fd), filter, flags);
+        watch.udata = udata as usize;
+
+        Ok(self.push_watch(watch))
+    }
+
+    /// Starts building a watch on `fd`, borrowed as with `add_fd`:
+    ///
+    /// ```ignore
+    /// let key = watcher.watch_fd(fd).read().low_water(4096).edge().oneshot().submit()?;
+    /// ```
+    pub fn watch_fd(&mut self, fd: RawFd) -> WatchBuilder {
+        let watch = Watched::new(Ident::Fd(fd), EventFilter::EVFILT_READ, FilterFlag::empty());
+
+        WatchBuilder {
+            watcher: self,
+            watch: watch,
+        }
+    }
+
+    /// Watches `file` without taking ownership; `file` must outlive the
+    /// watch and is left open when the watcher is dropped.
+    pub fn add_file(&mut self,
+                    file: &File,
+                    filter: EventFilter,
+                    flags: FilterFlag)
+                    -> Result<WatchKey> {
+        let flags = checked_flags(filter, flags)?;
+        Ok(self.push_watch(Watched::new(Ident::Fd(file.as_raw_fd()), filter, flags)))
+    }
+
+    /// Watches every one of `sources` for `filter` in one go, borrowing the
+    /// descriptors like `add_file`. Sources that are already watched this
+    /// way aren't added twice. Returns the keys in the order of `sources`;
+    /// nothing is added if `flags` are invalid.
+    pub fn add_all<S: AsRawFd>(&mut self,
+                               sources: &[S],
+                               filter: EventFilter,
+                               flags: FilterFlag)
+                               -> Result<Vec<WatchKey>> {
+        let flags = checked_flags(filter, flags)?;
+        Ok(sources.iter()
+            .map(|source| {
+                self.push_watch(Watched::new(Ident::Fd(source.as_raw_fd()), filter, flags))
+            })
+            .collect())
+    }
+
+    /// Watches `fd` for readability with the default flags; see `add_fd`.
+    /// Mind that partial reads don't re-arm an edge-triggered watch, see
+    /// `EventData::ReadReady`.
+    pub fn add_read(&mut self, fd: RawFd) -> Result<WatchKey> {
+        self.add_fd(fd, EventFilter::EVFILT_READ, default_flags(EventFilter::EVFILT_READ))
+    }
+
+    /// Watches the borrowed `fd` for lines of text. Whenever it is
+    /// readable the watcher reads what is available and hands out each
+    /// complete line as an `EventData::Line`, holding back a partial one
+    /// until its newline arrives. At end of file a trailing partial line is
+    /// flushed as a last `Line`, followed by the usual `ReadReady(0)`.
+    ///
+    /// The watcher does the reading, so nothing else should read from
+    /// `fd`. It reads only as much as the kernel reports available, so a
+    /// blocking `fd` never blocks a poll, and nothing while `quiet_start`
+    /// swallows what is ready. Line watches can't `require_ack`. Input
+    /// held back is dropped with the watch.
+    pub fn add_lines(&mut self, fd: RawFd) -> Result<WatchKey> {
+        let mut watch = Watched::new(Ident::Fd(fd),
+                                     EventFilter::EVFILT_READ,
+                                     default_flags(EventFilter::EVFILT_READ));
+        watch.lines = true;
+        self.lines.get_mut().insert(fd, Vec::new());
+
+        Ok(self.push_watch(watch))
+    }
+
+    // Drops the line buffers of fds no line watch reads any more
+    fn forget_lines(&self) {
+        let mut lines = self.lines.borrow_mut();
+        if lines.is_empty() {
+            return;
+        }
+
+        let watched = self.watched.borrow();
+        lines.retain(|&fd, _| watched.iter().any(|w| w.lines && w.ident.as_fd() == Some(fd)));
+    }
+
+    /// Watches standard input for lines of text, see `add_lines`.
+    pub fn add_stdin_lines(&mut self) -> Result<WatchKey> {
+        self.add_lines(libc::STDIN_FILENO)
+    }
+
+    /// Watches `fd` for readability level-triggered, whatever the watcher's
+    /// default: the event is reported on every poll for as long as unread
+    /// data is left, so a handler may read only part of it.
+    pub fn add_read_level(&mut self, fd: RawFd) -> Result<WatchKey> {
+        self.watch_fd(fd).read().level().submit()
+    }
+
+    /// Watches `fd` for writability with the default flags; see `add_fd`.
+    pub fn add_write(&mut self, fd: RawFd) -> Result<WatchKey> {
+        self.add_fd(fd, EventFilter::EVFILT_WRITE, default_flags(EventFilter::EVFILT_WRITE))
+    }
+
+    /// Watches the borrowed `fd` for writability, but only once at least
+    /// `bytes` of send buffer space are free (`NOTE_LOWAT`), so a writer
+    /// under flow control isn't woken for every few bytes drained. The
+    /// `WriteReady` events carry the space available.
+    pub fn add_write_lowat(&mut self, fd: RawFd, bytes: usize) -> Result<WatchKey> {
+        let mut watch = Watched::new(Ident::Fd(fd), EventFilter::EVFILT_WRITE, NOTE_LOWAT);
+        watch.data = bytes as i64;
+
+        Ok(self.push_watch(watch))
+    }
+
+    /// Starts watching the borrowed `fd` for writability, for a writer that
+    /// only wants `WriteReady` while it has data queued; see `disarm_write`.
+    /// Other watches on `fd` stay as they are. On a started watcher this is
+    /// a single `kevent(2)` call, and re-arming an armed fd is harmless.
+    pub fn arm_write(&mut self, fd: RawFd) -> Result<WatchKey> {
+        let watch = Watched::new(Ident::Fd(fd),
+                                 EventFilter::EVFILT_WRITE,
+                                 default_flags(EventFilter::EVFILT_WRITE));
+        if self.started {
+            self.submit(&[self.to_kevent(&watch)])?;
+        }
+
+        Ok(self.push_watch(watch))
+    }
+
+    /// Stops watching `fd` for writability once the writer has flushed, so
+    /// it isn't woken for buffer space it has no use for. Read watches on
+    /// `fd` stay in place; `WriteReady` events already buffered for it are
+    /// dropped. On a started watcher this is a single `kevent(2)` call, and
+    /// disarming an fd that isn't armed does nothing.
+    pub fn disarm_write(&mut self, fd: RawFd) -> Result<()> {
+        let is_write = |filter: EventFilter, ident: &Ident| {
+            filter == EventFilter::EVFILT_WRITE && ident.as_fd() == Some(fd)
+        };
+        let mut owned = false;
+        let mut armed = false;
+        self.watched.get_mut().retain(|w| {
+            let matches = is_write(w.filter, &w.ident);
+            armed |= matches;
+            owned |= matches && w.owned;
+            !matches
+        });
+        if !armed {
+            return Ok(());
+        }
+        self.pending.get_mut().retain(|ev| !is_write(ev.filter, &ev.ident));
+
+        let result = if self.started {
+            self.submit(&[kevent {
+                              ident: fd as usize,
+                              filter: EventFilter::EVFILT_WRITE,
+                              flags: EV_DELETE,
+                              fflags: FilterFlag::empty(),
+                              data: 0,
+                              udata: to_udata(0),
+                          }])
+        } else {
+            Ok(())
+        };
+
+        // deleting a registration needs its descriptor, so close afterwards
+        if owned && !self.watched.get_mut().iter().any(|w| w.ident.as_fd() == Some(fd)) {
+            unsafe { libc::close(fd) };
+        }
+        result
+    }
+
+    /// Watches the borrowed socket `fd` for out-of-band data (`EVFILT_EXCEPT`
+    /// with `NOTE_OOB`), reported as `EventData::Except`, like the
+    /// exceptional condition of `select(2)`.
+    ///
+    /// Only DragonFly's kqueue exposes `EVFILT_EXCEPT` through this crate.
+    #[cfg(target_os = "dragonfly")]
+    pub fn add_except(&mut self, fd: RawFd) -> Result<WatchKey> {
+        self.add_fd(fd, EventFilter::EVFILT_EXCEPT, NOTE_OOB)
+    }
+
+    /// Watches for system memory pressure, reported as
+    /// `EventData::VmPressure` events with `Ident::Vm`.
+    ///
+    /// Only available on macOS; other systems have no `EVFILT_VM`. There is
+    /// a single such watch per watcher, adding it again returns the same key.
+    #[cfg(target_os = "macos")]
+    pub fn add_vm_pressure(&mut self) -> Result<WatchKey> {
+        let flags = default_flags(EventFilter::EVFILT_VM);
+        Ok(self.push_watch(Watched::new(Ident::Vm, EventFilter::EVFILT_VM, flags)))
+    }
+
+    /// Watches for system-wide filesystem events such as mounts, unmounts
+    /// and running low on disk space, reported as `EventData::Fs` events
+    /// with `Ident::Fs`.
+    ///
+    /// Available on FreeBSD, DragonFly and macOS. `flags` are handed to the
+    /// kernel as-is; none of these systems currently interpret them, so
+    /// `FilterFlag::empty()` is the usual choice. Which conditions are
+    /// reported differs: FreeBSD and DragonFly post mounts, unmounts and
+    /// network filesystem trouble, while macOS also reports remounts and
+    /// disk space thresholds.
+    #[cfg(any(target_os = "freebsd", target_os = "macos", target_os = "dragonfly"))]
+    pub fn add_fs(&mut self, flags: FilterFlag) -> Result<WatchKey> {
+        Ok(self.push_watch(Watched::new(Ident::Fs, EventFilter::EVFILT_FS, flags)))
+    }
+
+    /// Adds user event `id`, which fires only when `trigger`ed; useful for
+    /// waking up a thread blocked in `poll`. FreeBSD and DragonFly only.
+    ///
+    /// The kernel coalesces triggers: however often `trigger` is called
+    /// before the event is drained, it is delivered once, after which it is
+    /// reset (with the default `EV_CLEAR`). The delivered
+    /// `EventData::User` counts the triggers it stands for, so the event
+    /// works both as a plain wakeup and as a counting semaphore.
+    #[cfg(any(target_os = "freebsd", target_os = "dragonfly"))]
+    pub fn add_user(&mut self, id: usize) -> Result<WatchKey> {
+        Ok(self.push_watch(Watched::new(Ident::User(id), EventFilter::EVFILT_USER, NOTE_FFNOP)))
+    }
+
+    /// Fires user event `id`. Fails with `NotFound` if `id` isn't watched
+    /// and with the kernel's error if the watcher isn't started yet.
+    #[cfg(any(target_os = "freebsd", target_os = "dragonfly"))]
+    pub fn trigger(&self, id: usize) -> Result<()> {
+        let index = self.user_event(id)?;
+        self.submit(&[kevent {
+                          ident: id,
+                          filter: EventFilter::EVFILT_USER,
+                          flags: EventFlag::empty(),
+                          fflags: NOTE_TRIGGER,
+                          data: 0,
+                          udata: to_udata(0),
+                      }])?;
+
+        self.watched.borrow_mut()[index].triggers += 1;
+        Ok(())
+    }
+
+    /// The number of triggers of user event `id` that haven't been
+    /// delivered yet, or `None` if `id` isn't watched.
+    #[cfg(any(target_os = "freebsd", target_os = "dragonfly"))]
+    pub fn user_pending(&self, id: usize) -> Option<usize> {
+        let index = self.user_event(id).ok()?;
+        Some(self.watched.borrow()[index].triggers)
+    }
+
+    /// Resets user event `id` if it was triggered but not drained yet,
+    /// including a delivery already buffered by the watcher.
+    #[cfg(any(target_os = "freebsd", target_os = "dragonfly"))]
+    pub fn clear_user(&mut self, id: usize) -> Result<()> {
+        let index = self.user_event(id)?;
+        self.watched.get_mut()[index].triggers = 0;
+        if !self.started {
+            return Ok(());
+        }
+
+        // the kernel has no way to untrigger, so register the event afresh
+        self.delete_kevents(Ident::User(id), EventFilter::EVFILT_USER)?;
+        let kev = self.to_kevent(&self.watched.borrow()[index]);
+        self.submit(&[kev])
+    }
+
+    #[cfg(any(target_os = "freebsd", target_os = "dragonfly"))]
+    fn user_event(&self, id: usize) -> Result<usize> {
+        self.watched
+            .borrow()
+            .iter()
+            .position(|w| w.filter == EventFilter::EVFILT_USER && w.ident == Ident::User(id))
+            .ok_or_else(|| Error::new(io::ErrorKind::NotFound, "no such user event"))
+    }
+
+    /// How many `kevent(2)` calls this watcher has made, to check how well
+    /// calls are batched. Enabled by the `debug_counters` feature; clones
+    /// count from zero.
+    #[cfg(any(test, feature = "debug_counters"))]
+    pub fn syscall_count(&self) -> u64 {
+        self.syscalls.get()
+    }
+
+    /// Snapshots the watch set, in registration order, for inspection.
+    pub fn watches(&self) -> impl Iterator<Item = Watched> {
+        self.watched.borrow().clone().into_iter()
+    }
+
+    /// The token attached to the `filter` watch on `ident` (see
+    /// `WatchBuilder::token`), 0 for a watch without one, or `None` if there
+    /// is no such watch. Handy for looking up per-watch metadata outside of
+    /// event handling.
+    pub fn watch_data(&self, ident: &Ident, filter: EventFilter) -> Option<u64> {
+        self.watched
+            .borrow()
+            .iter()
+            .find(|watched| watched.ident == *ident && watched.filter == filter)
+            .map(|watched| watched.udata as u64)
+    }
+
+    /// Reports whether the watcher owns (and will close) the descriptor
+    /// behind `ident`, or `None` if `ident` isn't watched. Non-descriptor
+    /// idents such as pids are never owned.
+    pub fn is_owned(&self, ident: &Ident) -> Option<bool> {
+        self.watched
+            .borrow()
+            .iter()
+            .find(|watched| watched.ident == *ident)
+            .map(|watched| watched.owned)
+    }
+
+    /// Stops the watcher from closing `fd`, which it opened itself (e.g. for
+    /// `add_filename`), so that another owner can take it over. The watches
+    /// on `fd` stay registered.
+    pub fn disown_fd(&mut self, fd: RawFd) -> Result<()> {
+        self.set_owned(fd, false)
+    }
+
+    /// Hands `fd` to the watcher, which closes it once its watches are gone
+    /// and on drop, as if it had opened the descriptor itself.
+    pub fn take_ownership(&mut self, fd: RawFd) -> Result<()> {
+        self.set_owned(fd, true)
+    }
+
+    fn set_owned(&mut self, fd: RawFd, owned: bool) -> Result<()> {
+        let mut found = false;
+        for watched in self.watched.get_mut().iter_mut().filter(|w| w.ident.as_fd() == Some(fd)) {
+            watched.owned = owned;
+            found = true;
+        }
+
+        if found {
+            Ok(())
+        } else {
+            Err(Error::new(io::ErrorKind::NotFound, "no watch on fd"))
+        }
+    }
+
+    /// Makes the watch behind `key` deliver its event over and over, on
+    /// every poll, until it is handed to `ack`, so a handler that dies
+    /// midway can't lose it. Not available on OpenBSD.
+    ///
+    /// The watch is registered with `EV_DISPATCH`: the kernel disables it on
+    /// delivery and `ack` enables it again, so new activity only shows up
+    /// after the ack, merged into one event. Meanwhile polling doesn't wait
+    /// for other events, and `poll_ref` returns owned idents.
+    #[cfg(not(target_os = "openbsd"))]
+    pub fn require_ack(&mut self, key: WatchKey) -> Result<()> {
+        let index = self.key_index(key)?;
+        if self.watched.get_mut()[index].lines {
+            return Err(Error::new(io::ErrorKind::InvalidInput,
+                                  "a line watch can't deliver lines it read again"));
+        }
+        let kev = {
+            let mut watched = self.watched.borrow_mut();
+            let watch = &mut watched[index];
+            watch.ack = true;
+            watch.event_flags.insert(EV_DISPATCH);
+            self.to_kevent(watch)
+        };
+
+        if self.started {
+            self.submit(&[kev])
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Marks the last event of a `require_ack` watch on `ident` and
+    /// `filter` as handled: it stops being re-delivered and the watch is
+    /// enabled for new activity.
+    #[cfg(not(target_os = "openbsd"))]
+    pub fn ack(&mut self, ident: &Ident, filter: EventFilter) -> Result<()> {
+        let found = self.watched
+            .get_mut()
+            .iter()
+            .find(|w| w.ack && w.filter == filter && w.ident.same_source(ident))
+            .map(|w| w.ident.as_usize());
+        let raw = match found {
+            Some(raw) => raw,
+            None => {
+                return Err(Error::new(io::ErrorKind::NotFound,
+                                      "no watch requiring acks for ident and filter"))
+            }
+        };
+
+        self.unacked.get_mut().retain(|kev| !(kev.ident == raw && kev.filter == filter));
+        self.discard_buffered(ident, filter);
+        if !self.started {
+            return Ok(());
+        }
+
+        self.submit(&[kevent {
+                          ident: raw,
+                          filter: filter,
+                          flags: EV_ENABLE,
+                          fflags: FilterFlag::empty(),
+                          data: 0,
+                          udata: to_udata(0),
+                      }])
+    }
+
+    /// Stops delivering events for the watch behind `key` without removing
+    /// it. Before `watch()` this only marks the watch to be registered
+    /// disabled.
+    ///
+    /// Events of the watch that were already fetched from the kernel and
+    /// buffered in the watcher are dropped, so nothing is delivered for it
+    /// once this returns. The kernel keeps track of activity meanwhile.
+    pub fn disable(&mut self, key: WatchKey) -> Result<()> {
+        self.toggle(key, false)
+    }
+
+    /// Resumes delivering events for a watch stopped with `disable`.
+    ///
+    /// Activity that happened while the watch was disabled is reported by
+    /// the next poll, merged into a single event.
+    pub fn enable(&mut self, key: WatchKey) -> Result<()> {
+        self.toggle(key, true)
+    }
+
+    fn toggle(&mut self, key: WatchKey, enable: bool) -> Result<()> {
+        let index = self.key_index(key)?;
+        let (ident, filter) = {
+            let watch = &mut self.watched.get_mut()[index];
+            if enable {
+                watch.event_flags.remove(EV_DISABLE);
+            } else {
+                watch.event_flags.insert(EV_DISABLE);
+            }
+
+            (watch.ident.clone(), watch.filter)
+        };
+        if !enable {
+            self.discard_buffered(&ident, filter);
+        }
+
+        if !self.started {
+            return Ok(());
+        }
+
+        self.submit(&[kevent {
+                          ident: ident.as_usize(),
+                          filter: filter,
+                          flags: if enable { EV_ENABLE } else { EV_DISABLE },
+                          fflags: FilterFlag::empty(),
+                          data: 0,
+                          udata: to_udata(0),
+                      }])
+    }
+
+    // Drops the buffered events of the watch on `ident` and `filter`
+    fn discard_buffered(&self, ident: &Ident, filter: EventFilter) {
+        self.pending
+            .borrow_mut()
+            .retain(|ev| !(ev.filter == filter && ev.ident.same_source(ident)));
+    }
+
+    fn key_index(&self, key: WatchKey) -> Result<usize> {
+        if key.watcher != self.id {
+            return Err(Error::new(io::ErrorKind::InvalidInput,
+                                  "watch key belongs to a different watcher"));
+        }
+
+        self.watched
+            .borrow()
+            .iter()
+            .position(|w| w.key == key.id)
+            .ok_or_else(|| Error::new(io::ErrorKind::NotFound, "no watch for key"))
+    }
+
+    fn push_watch(&mut self, mut watch: Watched) -> WatchKey {
+        if watch.owned {
+            forget_stale(self.watched.get_mut(), &watch);
+        }
+        let existing = self.watched.get_mut().iter().find(|w| w.same_watch(&watch)).map(|w| w.key);
+        let id = match existing {
+            Some(key) => key,
+            None => {
+                watch.key = self.alloc_key();
+                let key = watch.key;
+                self.watched.get_mut().push(watch);
+                key
+            }
+        };
+
+        WatchKey {
+            watcher: self.id,
+            id: id,
+        }
+    }
+
+    fn alloc_key(&self) -> usize {
+        let key = self.next_key.get();
+        self.next_key.set(key + 1);
+        key
+    }
+
+    fn sink(&self) -> Option<&dyn Metrics> {
+        self.opts.metrics.as_ref().map(|sink| &*sink.0)
+    }
+
+    // Counts one kevent(2) call and reports its error, if it failed
+    fn record_syscall(&self, ret: i32) -> Result<()> {
+        let err = if ret == -1 { Some(Error::last_os_error()) } else { None };
+        #[cfg(any(test, feature = "debug_counters"))]
+        self.syscalls.set(self.syscalls.get() + 1);
+
+        if let Some(sink) = self.sink() {
+            sink.on_syscall();
+            if let Some(ref err) = err {
+                sink.on_error(err);
+            }
+        }
+
+        match err {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    fn record_event(&self, filter: EventFilter) {
+        if let Some(sink) = self.sink() {
+            sink.on_event(filter);
+        }
+    }
+
+    fn submit(&self, kevs: &[kevent]) -> Result<()> {
+        // without event slots kevent(2) only applies changes and never blocks
+        self.retrying(|| unsafe { self.sys_kevent(kevs, ptr::null_mut(), 0, ptr::null()) })
+            .map(|_| ())
+    }
+
+    // The kevent(2) call behind every other: applies `changes`, then writes
+    // up to `max` events to `out`, which must have room for them
+    #[cfg(not(target_os = "macos"))]
+    unsafe fn sys_kevent(&self,
+                         changes: &[kevent],
+                         out: *mut kevent,
+                         max: usize,
+                         timeout: *const timespec)
+                         -> i32 {
+        kevent(self.queue,
+               changes.as_ptr(),
+               changes.len() as EventListSize,
+               out,
+               max as EventListSize,
+               timeout)
+    }
+
+    // The same through kevent64(2), the only call taking a timer's leeway.
+    // XNU binds a kqueue to the first of the two calls used on it and fails
+    // the other with EINVAL, so the watcher never calls kevent(2) itself.
+    #[cfg(target_os = "macos")]
+    unsafe fn sys_kevent(&self,
+                         changes: &[kevent],
+                         out: *mut kevent,
+                         max: usize,
+                         timeout: *const timespec)
+                         -> i32 {
+        let leeways = self.leeways.borrow();
+        let changes: Vec<libc::kevent64_s> = changes.iter()
+            .map(|kev| {
+                let leeway = if kev.filter == EventFilter::EVFILT_TIMER &&
+                                kev.fflags.contains(NOTE_LEEWAY) {
+                    leeways.get(&kev.ident).cloned().unwrap_or(0)
+                } else {
+                    0
+                };
+                libc::kevent64_s {
+                    ident: kev.ident as u64,
+                    filter: kev.filter as i16,
+                    flags: kev.flags.bits(),
+                    fflags: kev.fflags.bits(),
+                    data: kev.data,
+                    udata: kev.udata as u64,
+                    ext: [0, leeway],
+                }
+            })
+            .collect();
+
+        // reads up to a `poll_into` chunk don't allocate
+        let mut chunk: [MaybeUninit<libc::kevent64_s>; POLL_CHUNK] =
+            MaybeUninit::uninit().assume_init();
+        let mut spill: Vec<libc::kevent64_s> = Vec::new();
+        let buf = if max <= POLL_CHUNK {
+            chunk.as_mut_ptr() as *mut libc::kevent64_s
+        } else {
+            spill.reserve(max);
+            spill.as_mut_ptr()
+        };
+
+        let ret = libc::kevent64(self.queue,
+                                 changes.as_ptr(),
+                                 changes.len() as i32,
+                                 buf,
+                                 max as i32,
+                                 0,
+                                 timeout);
+        for i in 0..cmp::max(ret, 0) as usize {
+            // a kevent64_s starts with the fields of a kevent, laid out alike
+            *out.add(i) = ptr::read(buf.add(i) as *const kevent);
+        }
+        ret
+    }
+
+    // Makes the kevent(2) call `call`, repeating it after transient failures
+    // as `retry_policy` allows
+    fn retrying<F: FnMut() -> i32>(&self, mut call: F) -> Result<i32> {
+        let mut retries = 0;
+        loop {
+            let ret = call();
+            match self.record_syscall(ret) {
+                Ok(()) => return Ok(ret),
+                Err(ref err) if self.opts.retry.retries_after(err, retries) => {
+                    retries += 1;
+                    std::thread::sleep(self.opts.retry.backoff);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    // Submits `kevs` and returns the index and error of each change the
+    // kernel rejected, instead of stopping at the first
+    fn submit_receipts(&self, kevs: &[kevent]) -> Result<Vec<(usize, Error)>> {
+        let changes: Vec<kevent> = kevs.iter()
+            .map(|kev| {
+                let mut kev = *kev;
+                kev.flags.insert(receipt_flag());
+                kev
+            })
+            .collect();
+        let mut receipts: Vec<kevent> = Vec::with_capacity(changes.len());
+        let zero = to_timespec(Duration::new(0, 0));
+
+        let ret = self.retrying(|| unsafe {
+                self.sys_kevent(&changes, receipts.as_mut_ptr(), changes.len(), &zero)
+            })?;
+        unsafe { receipts.set_len(ret as usize) };
+
+        let mut failed = Vec::new();
+        for receipt in receipts {
+            if !receipt.flags.contains(EV_ERROR) {
+                // without EV_RECEIPT spare room may be filled with events
+                if let Some(ev) = deliver_kevent(self, receipt) {
+                    self.pending.borrow_mut().push_back(ev);
+                }
+                continue;
+            }
+
+            failed.extend(self.change_error(kevs, &receipt));
+        }
+
+        Ok(failed)
+    }
+
+    // The index in `kevs` and the error of the change an `EV_ERROR` kevent
+    // reports, None if the change succeeded
+    fn change_error(&self, kevs: &[kevent], receipt: &kevent) -> Option<(usize, Error)> {
+        if receipt.data == 0 {
+            return None;
+        }
+
+        let index = kevs.iter()
+            .position(|kev| kev.ident == receipt.ident && kev.filter == receipt.filter)?;
+        let err = Error::from_raw_os_error(receipt.data as i32);
+        if let Some(sink) = self.sink() {
+            sink.on_error(&err);
+        }
+
+        Some((index, err))
+    }
+
+    fn to_kevent(&self, watched: &Watched) -> kevent {
+        #[cfg(target_os = "macos")]
+        {
+            if let Some(leeway) = watched.leeway {
+                self.leeways
+                    .borrow_mut()
+                    .insert(watched.ident.as_usize(), timer_data(leeway).1 as u64);
+            }
+        }
+
+        kevent {
+            ident: watched.ident.as_usize(),
+            filter: watched.filter,
+            flags: if watched.clear.unwrap_or(self.opts.clear) {
+                EV_ADD | EV_CLEAR
+            } else {
+                EV_ADD
+            } | watched.event_flags,
+            fflags: watched.flags,
+            data: match watched.deadline {
+                Some(at) => timer_data(at.saturating_duration_since(Instant::now())).1,
+                None => watched.data,
+            },
+            udata: to_udata(watched.udata),
+        }
+    }
+
+    fn delete_kevents(&self, ident: Ident, filter: EventFilter) -> Result<()> {
+        // buffered events would otherwise outlive the registration
+        self.discard_buffered(&ident, filter);
+        self.submit(&[kevent {
+                          ident: ident.as_usize(),
+                          filter: filter,
+                          flags: EV_DELETE,
+                          fflags: FilterFlag::empty(),
+                          data: 0,
+                          udata: to_udata(0),
+                      }])
+    }
+
+    /// Deletes exactly the watch behind `key`, leaving other filters on the
+    /// same descriptor or path in place. An owned descriptor is closed once
+    /// no watch uses it any more.
+    pub fn remove(&mut self, key: WatchKey) -> Result<()> {
+        self.apply_changes(&[(key, EV_DELETE)])
+    }
+
+    pub fn remove_pid(&mut self, pid: libc::pid_t, filter: EventFilter) -> Result<()> {
+        let new_watched = self.watched
+            .get_mut()
+            .drain(..)
+            .filter(|x| {
+                if let Ident::Pid(iterpid) = x.ident {
+                    iterpid != pid
+                } else {
+                    true
+                }
+            })
+            .collect();
+
+        *self.watched.get_mut() = new_watched;
+        self.delete_kevents(Ident::Pid(pid), filter)
+    }
+
+    pub fn remove_filename<P: AsRef<Path>>(&mut self,
+                                           filename: P,
+                                           filter: EventFilter)
+                                           -> Result<()> {
+        let mut fd: RawFd = 0;
+        let new_watched = self.watched
+            .get_mut()
+            .drain(..)
+            .filter(|x| {
+                if let Ident::Filename(iterfd, ref iterfile) = x.ident {
+                    if iterfile == filename.as_ref().to_str().unwrap() {
+                        fd = iterfd;
+                        false
+                    } else {
+                        true
+                    }
+                } else {
+                    true
+                }
+            })
+            .collect();
+
+        *self.watched.get_mut() = new_watched;
+        self.forget_lines();
+        self.delete_kevents(Ident::Fd(fd), filter)
+    }
+
+    pub fn remove_fd(&mut self, fd: RawFd, filter: EventFilter) -> Result<()> {
+        let new_watched = self.watched
+            .get_mut()
+            .drain(..)
+            .filter(|x| {
+                if let Ident::Fd(iterfd) = x.ident {
+                    iterfd != fd
+                } else {
+                    true
+                }
+            })
+            .collect();
+
+        *self.watched.get_mut() = new_watched;
+        self.forget_lines();
+        self.delete_kevents(Ident::Fd(fd), filter)
+    }
+
+    pub fn remove_file(&mut self, file: &File, filter: EventFilter) -> Result<()> {
+        self.remove_fd(file.as_raw_fd(), filter)
+    }
+
+    /// Snapshots the watch set as `(ident, filter, flags)` triples, in
+    /// registration order, for `import_config`.
+    ///
+    /// Timer intervals and per-watch options (one-shot, persistence,
+    /// reaping, ...) are not part of the snapshot. A file still waiting to be
+    /// created is exported by its own path, with an fd of -1.
+    pub fn export_config(&self) -> Vec<(Ident, EventFilter, FilterFlag)> {
+        self.watched
+            .borrow()
+            .iter()
+            .map(Watched::config_entry)
+            .collect()
+    }
+
+    /// Replaces the whole watch set with `cfg`, typically taken from
+    /// `export_config` on this or another watcher.
+    ///
+    /// Existing registrations are deleted and the files the watcher opened
+    /// closed. Filenames are reopened by path, vnode ones waiting for
+    /// creation if missing, and `Ident::Fd` descriptors are borrowed. If the
+    /// watcher was already started the new set is registered right away. On
+    /// error the watch set holds the entries imported so far.
+    pub fn import_config(&mut self, cfg: Vec<(Ident, EventFilter, FilterFlag)>) -> Result<()> {
+        let old: Vec<Watched> = self.watched.get_mut().drain(..).collect();
+        let mut closed = Vec::new();
+
+        for watched in &old {
+            if self.started {
+                let _ = self.delete_kevents(watched.ident.clone(), watched.filter);
+            }
+
+            match watched.ident.as_fd() {
+                Some(fd) if watched.owned && !closed.contains(&fd) => {
+                    unsafe { libc::close(fd) };
+                    closed.push(fd);
+                }
+                _ => (),
+            }
+        }
+
+        for (ident, filter, flags) in cfg {
+            match ident {
+                Ident::Filename(_, path) => {
+                    if filter == EventFilter::EVFILT_VNODE {
+                        self.watch_path_create(path, flags)?;
+                    } else {
+                        self.add_filename(path, filter, flags)?;
+                    }
+                }
+                ident => {
+                    self.push_watch(Watched::new(ident, filter, checked_flags(filter, flags)?));
+                }
+            }
+        }
+
+        if self.started {
+            self.watch()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Like `export_config`, but keeps only the watched paths and pids, in
+    /// a form that can be stored (see `WatchConfig`). Descriptors, signals,
+    /// timers and the like don't outlive the process and are left out.
+    pub fn export_watch_config(&self) -> WatchConfig {
+        let mut cfg = WatchConfig::default();
+        for (ident, filter, flags) in self.export_config() {
+            match ident {
+                Ident::Filename(_, path) => {
+                    cfg.files.push(FileConfig {
+                        path: path,
+                        filter: filter as i16,
+                        flags: flags.bits(),
+                    })
+                }
+                Ident::Pid(pid) if filter == EventFilter::EVFILT_PROC => {
+                    cfg.pids.push(PidConfig {
+                        pid: pid,
+                        flags: flags.bits(),
+                    })
+                }
+                _ => (),
+            }
+        }
+
+        cfg
+    }
+
+    /// Replaces the whole watch set with `cfg`, like `import_config`. Pids
+    /// that no longer exist fail `watch()` as usual, see
+    /// `last_watch_errors`.
+    pub fn import_watch_config(&mut self, cfg: &WatchConfig) -> Result<()> {
+        let mut entries = Vec::with_capacity(cfg.files.len() + cfg.pids.len());
+        for file in &cfg.files {
+            let filter = match file_filter(file.filter) {
+                Some(filter) => filter,
+                None => {
+                    return Err(Error::new(io::ErrorKind::InvalidInput,
+                                          format!("{} is not a file filter", file.filter)))
+                }
+            };
+            entries.push((Ident::Filename(-1, file.path.clone()),
+                          filter,
+                          FilterFlag::from_bits_truncate(file.flags)));
+        }
+        for proc_cfg in &cfg.pids {
+            entries.push((Ident::Pid(proc_cfg.pid),
+                          EventFilter::EVFILT_PROC,
+                          FilterFlag::from_bits_truncate(proc_cfg.flags)));
+        }
+
+        self.import_config(entries)
+    }
+
+    /// Brings the watch set in line with `desired`, changing only what
+    /// differs.
+    ///
+    /// Watches missing from `desired` are deleted (closing their owned
+    /// descriptors), new entries are added and entries whose flags changed
+    /// are updated in place. If the watcher was already started all of it
+    /// goes to the kernel as a single `kevent(2)` batch, so unchanged watches
+    /// never miss an event and keep their keys. Entries are matched by ident
+    /// and filter, filenames by path; new filenames are opened, new `Fd`
+    /// idents are borrowed.
+    ///
+    /// Invalid flags or a file that can't be opened leave the watch set
+    /// untouched. If the kernel rejects a change the watch set already
+    /// matches `desired`, the first error is returned and `watch` can be
+    /// used to register it again. Deleting a registration the kernel
+    /// already dropped on its own (`ENOENT`) counts as done, so reconciling
+    /// stays idempotent when the watch set drifts.
+    pub fn reconcile(&mut self, desired: Vec<(Ident, EventFilter, FilterFlag)>) -> Result<()> {
+        let mut wanted = Vec::with_capacity(desired.len());
+        for (ident, filter, flags) in desired {
+            let flags = checked_flags(filter, flags)?;
+            wanted.push((ident, filter, flags));
+        }
+
+        let (deletes, adds, updates) = self.plan_reconcile(&wanted);
+
+        // open new files before touching anything
+        let mut added: Vec<Watched> = Vec::with_capacity(adds.len());
+        for &i in &adds {
+            let (ref ident, filter, flags) = wanted[i];
+            let watch = match *ident {
+                Ident::Filename(_, ref path) => {
+                    match File::open(path) {
+                        Ok(file) => {
+                            let ident = Ident::Filename(file.into_raw_fd(), path.clone());
+                            let mut watch = Watched::new(ident, filter, flags);
+                            watch.owned = true;
+                            watch
+                        }
+                        Err(err) => {
+                            for watch in &added {
+                                if let Ident::Filename(fd, _) = watch.ident {
+                                    unsafe { libc::close(fd) };
+                                }
+                            }
+                            return Err(err);
+                        }
+                    }
+                }
+                ref ident => Watched::new(ident.clone(), filter, flags),
+            };
+            added.push(watch);
+        }
+
+        let mut changes = Vec::new();
+        for (i, flags) in updates {
+            let mut watched = self.watched.borrow_mut();
+            match watched[i].creating {
+                Some((_, ref mut creating_flags)) => *creating_flags = flags,
+                None => {
+                    watched[i].flags = flags;
+                    changes.push(self.to_kevent(&watched[i]));
+                }
+            }
+        }
+
+        let mut removed = Vec::with_capacity(deletes.len());
+        for &i in deletes.iter().rev() {
+            removed.push(self.watched.get_mut().remove(i));
+        }
+        self.forget_lines();
+        for watched in &removed {
+            let ident = watched.ident.clone();
+            self.discard_buffered(&ident, watched.filter);
+            changes.push(kevent {
+                ident: ident.as_usize(),
+                filter: watched.filter,
+                flags: EV_DELETE,
+                fflags: FilterFlag::empty(),
+                data: 0,
+                udata: to_udata(0),
+            });
+        }
+
+        for watch in added {
+            changes.push(self.to_kevent(&watch));
+            self.push_watch(watch);
+        }
+
+        let result = if self.started {
+            self.submit_receipts(&changes).and_then(|failed| {
+                let gone = |&(i, ref err): &(usize, Error)| {
+                    changes[i].flags.contains(EV_DELETE) &&
+                    err.raw_os_error() == Some(libc::ENOENT)
+                };
+                match failed.into_iter().find(|failure| !gone(failure)) {
+                    Some((_, err)) => Err(err),
+                    None => Ok(()),
+                }
+            })
+        } else {
+            Ok(())
+        };
+
+        // deleting a registration needs its descriptor, so close afterwards
+        for watched in removed.iter().filter(|w| w.owned) {
+            if let Some(fd) = watched.ident.as_fd() {
+                if !self.watched.get_mut().iter().any(|w| w.ident.as_fd() == Some(fd)) {
+                    unsafe { libc::close(fd) };
+                }
+            }
+        }
+
+        result
+    }
+
+    // Splits the way to `wanted` into watch indices to delete, `wanted`
+    // indices to add and watch indices whose flags change
+    fn plan_reconcile(&self,
+                      wanted: &[ConfigEntry])
+                      -> (Vec<usize>, Vec<usize>, Vec<(usize, FilterFlag)>) {
+        let current: Vec<ConfigEntry> =
+            self.watched.borrow().iter().map(Watched::config_entry).collect();
+
+        let deletes = (0..current.len())
+            .filter(|&i| !wanted.iter().any(|want| same_entry(&current[i], want)))
+            .collect();
+
+        let mut adds: Vec<usize> = Vec::new();
+        let mut updates: Vec<(usize, FilterFlag)> = Vec::new();
+        for (i, want) in wanted.iter().enumerate() {
+            if adds.iter().any(|&j| same_entry(&wanted[j], want)) {
+                continue;
+            }
+
+            match current.iter().position(|entry| same_entry(entry, want)) {
+                Some(j) => {
+                    if current[j].2 != want.2 && !updates.iter().any(|&(k, _)| k == j) {
+                        updates.push((j, want.2));
+                    }
+                }
+                None => adds.push(i),
+            }
+        }
+
+        (deletes, adds, updates)
+    }
+
+    /// Submits one change per watch as a single `kevent(2)` batch, without
+    /// going through `watch()`'s blanket `EV_ADD`.
+    ///
+    /// Each entry is one of `EV_ADD` (register the watch, e.g. one added
+    /// since `watch()`), `EV_DELETE` (unregister it and drop it from the watch
+    /// set, closing an owned descriptor), `EV_ENABLE` or `EV_DISABLE`. The
+    /// watch set is updated for every entry; if the watcher isn't started yet
+    /// nothing is submitted. Every change is tried even if some fail, and the
+    /// first failure is returned.
+    ///
+    /// No events are fetched in between the changes, so a poll sees either
+    /// none or all of them applied. Buffered events are dropped as by
+    /// `disable` for `EV_DISABLE`, and those of the deleted filter for
+    /// `EV_DELETE`.
+    pub fn apply_changes(&mut self, changes: &[(WatchKey, EventFlag)]) -> Result<()> {
+        let mut indices = Vec::with_capacity(changes.len());
+        for &(key, flags) in changes {
+            if ![EV_ADD, EV_DELETE, EV_ENABLE, EV_DISABLE].contains(&flags) {
+                return Err(Error::new(io::ErrorKind::InvalidInput,
+                                      "change must be one of EV_ADD, EV_DELETE, EV_ENABLE and \
+                                       EV_DISABLE"));
+            }
+            indices.push(self.key_index(key)?);
+        }
+
+        let mut kevs = Vec::with_capacity(changes.len());
+        let mut deletes = Vec::new();
+        {
+            let mut watched = self.watched.borrow_mut();
+            for (&(_, flags), &i) in changes.iter().zip(&indices) {
+                let watch = &mut watched[i];
+                if flags == EV_ENABLE {
+                    watch.event_flags.remove(EV_DISABLE);
+                } else if flags == EV_DISABLE {
+                    watch.event_flags.insert(EV_DISABLE);
+                    self.discard_buffered(&watch.ident, watch.filter);
+                } else if flags == EV_DELETE {
+                    deletes.push(i);
+                }
+
+                kevs.push(if flags == EV_ADD {
+                    self.to_kevent(watch)
+                } else {
+                    kevent {
+                        ident: watch.ident.as_usize(),
+                        filter: watch.filter,
+                        flags: flags,
+                        fflags: FilterFlag::empty(),
+                        data: 0,
+                        udata: to_udata(0),
+                    }
+                });
+            }
+        }
+
+        deletes.sort();
+        deletes.dedup();
+        let mut removed = Vec::with_capacity(deletes.len());
+        for &i in deletes.iter().rev() {
+            let watched = self.watched.get_mut().remove(i);
+            self.discard_buffered(&watched.ident, watched.filter);
+            removed.push(watched);
+        }
+        self.forget_lines();
+
+        let result = if self.started {
+            match self.submit_receipts(&kevs) {
+                Ok(failed) => {
+                    match failed.into_iter().next() {
+                        Some((_, err)) => Err(err),
+                        None => Ok(()),
+                    }
+                }
+                Err(err) => Err(err),
+            }
+        } else {
+            Ok(())
+        };
+
+        // deleting a registration needs its descriptor, so close afterwards
+        for watched in removed.iter().filter(|w| w.owned) {
+            if let Some(fd) = watched.ident.as_fd() {
+                if !self.watched.get_mut().iter().any(|w| w.ident.as_fd() == Some(fd)) {
+                    unsafe { libc::close(fd) };
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Re-registers every watch with the current options.
+    ///
+    /// The kernel ignores `EV_CLEAR` when re-adding an existing registration,
+    /// so each watch is deleted and added again. Events that were already
+    /// pending for a watch, in the kernel or buffered in the watcher, are
+    /// discarded by this; a condition that still holds, such as unread data,
+    /// is reported once afresh by the new registration instead, so
+    /// switching between edge and level neither loses nor duplicates it.
+    pub fn reapply(&mut self) -> Result<()> {
+        if !self.started {
+            return Ok(());
+        }
+
+        for watched in self.watched.borrow().iter() {
+            // a watch the kernel already dropped (e.g. an exited pid) is fine
+            let _ = self.delete_kevents(watched.ident.clone(), watched.filter);
+            self.discard_buffered(&watched.ident, watched.filter);
+        }
+
+        self.watch()
+    }
+
+    /// Registers the whole watch set with the kernel.
+    ///
+    /// Every watch is submitted even if some fail; the failures are kept
+    /// for `last_watch_errors` and the first is returned. Registering never
+    /// blocks and never consumes events, see `watch_and_collect` to also
+    /// fetch the ones that are ready.
+    pub fn watch(&mut self) -> Result<()> {
+        self.watch_errors.clear();
+        let buffered = self.pending.get_mut().len();
+
+        let kevs = self.watch_kevents();
+        let starting = !self.started;
+        self.started = true;
+        let failed = self.submit_receipts(&kevs)?;
+        let failed = self.retry_changes(&kevs, failed)?;
+        self.set_watch_errors(failed);
+
+        let quiet = starting && self.opts.quiet_start;
+        if quiet {
+            // without EV_RECEIPT, registering may already have fetched some
+            let submitted = self.pending.get_mut().len();
+            self.pending.get_mut().drain(buffered..submitted);
+        }
+        let invalid = self.invalidate_rejected();
+        self.pending.get_mut().extend(invalid);
+        let created = self.resolve_created();
+        self.pending.get_mut().extend(created);
+        if quiet {
+            self.swallow_ready(&kevs)?;
+        }
+
+        match self.watch_errors.first() {
+            Some(&(_, ref err)) => Err(copy_error(err)),
+            None => Ok(()),
+        }
+    }
+
+    // Submits the changes of `kevs` the kernel rejected transiently once
+    // more, as `retry_policy` allows, returning what failed for good
+    fn retry_changes(&self,
+                     kevs: &[kevent],
+                     mut failed: Vec<(usize, Error)>)
+                     -> Result<Vec<(usize, Error)>> {
+        let mut retries = 0;
+        loop {
+            let retry: Vec<usize> = failed.iter()
+                .filter(|&&(_, ref err)| self.opts.retry.retries_after(err, retries))
+                .map(|&(index, _)| index)
+                .collect();
+            if retry.is_empty() {
+                return Ok(failed);
+            }
+
+            retries += 1;
+            std::thread::sleep(self.opts.retry.backoff);
+            failed.retain(|&(index, _)| !retry.contains(&index));
+            let again: Vec<kevent> = retry.iter().map(|&index| kevs[index]).collect();
+            for (index, err) in self.submit_receipts(&again)? {
+                failed.push((retry[index], err));
+            }
+        }
+    }
+
+    // Turns the registrations the kernel rejected because their borrowed
+    // descriptor was closed into `Invalidated` events instead of errors
+    fn invalidate_rejected(&mut self) -> Vec<Event> {
+        let closed = |err: &Error| err.raw_os_error() == Some(libc::EBADF);
+        if !self.watch_errors.iter().any(|&(_, ref err)| closed(err)) {
+            return Vec::new();
+        }
+
+        let invalid = prune_invalid(self);
+        self.watch_errors
+            .retain(|&(ref ident, _)| !invalid.iter().any(|ev| ev.ident.same_source(ident)));
+        invalid
+    }
+
+    // Drops what the registrations in `kevs` reported ready right away.
+    // Those events still go through the bookkeeping (reaping children and
+    // so on) but aren't counted or owed an ack; a line watch is left unread.
+    // Anything else is buffered as usual.
+    fn swallow_ready(&mut self, kevs: &[kevent]) -> Result<()> {
+        // a single pass, level-triggered watches would refill it forever
+        let max = cmp::max(self.opts.batch_size, kevs.len());
+        for kev in read_kevents(self, max, Some(Duration::new(0, 0)))? {
+            let submitted = kevs.iter().any(|k| k.ident == kev.ident && k.filter == kev.filter);
+            if !submitted {
+                if let Some(ev) = deliver_kevent(self, kev) {
+                    self.pending.get_mut().push_back(ev);
+                }
+            } else if !is_line_watch(self, &kev) {
+                settle_kevent(self, kev);
+            }
+        }
+        Ok(())
+    }
+
+    /// Registers the watch set like `watch` and collects the events that
+    /// are ready in the same `kevent(2)` call, waiting up to `timeout` for
+    /// some (`None` doesn't wait). Events buffered by earlier calls come
+    /// first.
+    ///
+    /// If the kernel rejects a watch it doesn't look for events at all: the
+    /// failures are kept for `last_watch_errors`, the first is returned and
+    /// the events gathered so far are left for the next poll.
+    pub fn watch_and_collect(&mut self, timeout: Option<Duration>) -> Result<Vec<Event>> {
+        self.watch_errors.clear();
+        let mut events: Vec<Event> = self.pending.get_mut().drain(..).collect();
+
+        let kevs = self.watch_kevents();
+        self.started = true;
+
+        // room for an error per change, so none of them fails the whole call
+        let slots = cmp::max(self.opts.batch_size, kevs.len());
+        let mut ready: Vec<kevent> = Vec::with_capacity(slots);
+        let tspec = to_timespec(timeout.unwrap_or_default());
+        let ret = unsafe { self.sys_kevent(&kevs, ready.as_mut_ptr(), slots, &tspec) };
+        if let Err(err) = self.record_syscall(ret) {
+            self.pending.get_mut().extend(events);
+            return Err(err);
+        }
+        unsafe { ready.set_len(ret as usize) };
+
+        let mut failed = Vec::new();
+        for kev in ready {
+            if kev.flags.contains(EV_ERROR) {
+                failed.extend(self.change_error(&kevs, &kev));
+            } else {
+                events.extend(deliver_kevent(self, kev));
+            }
+        }
+        self.set_watch_errors(failed);
+        events.extend(self.invalidate_rejected());
+        events.extend(self.resolve_created());
+
+        match self.watch_errors.first() {
+            Some(&(_, ref err)) => {
+                let err = copy_error(err);
+                self.pending.get_mut().extend(events);
+                Err(err)
+            }
+            None => {
+                self.sort_batch(&mut events);
+                Ok(events)
+            }
+        }
+    }
+
+    fn watch_kevents(&self) -> Vec<kevent> {
+        self.watched
+            .borrow()
+            .iter()
+            .map(|watched| self.to_kevent(watched))
+            .collect()
+    }
+
+    fn set_watch_errors(&mut self, failed: Vec<(usize, Error)>) {
+        let watched = self.watched.get_mut();
+        self.watch_errors =
+            failed.into_iter().map(|(index, err)| (watched[index].ident.clone(), err)).collect();
+    }
+
+    // Files may have been created before their directory was registered
+    fn resolve_created(&mut self) -> Vec<Event> {
+        let waiting: Vec<RawFd> = self.watched
+            .borrow()
+            .iter()
+            .filter(|w| w.creating.is_some())
+            .filter_map(|w| w.ident.as_fd())
+            .collect();
+
+        waiting.into_iter().filter_map(|fd| finish_create(self, fd)).collect()
+    }
+
+    /// Registers the watch set like `watch` and waits up to `timeout` for
+    /// the first event (`None` returns immediately, as with `poll`). Handy
+    /// for one-off "wait until this changes" scripts.
+    pub fn watch_once(&mut self, timeout: Option<Duration>) -> Result<Option<Event>> {
+        self.watch()?;
+        Ok(self.drain(1, Some(timeout.unwrap_or_default()))?.pop())
+    }
+
+    /// The registrations the last `watch()` call failed to make, with the
+    /// kernel's error for each. Empty if all of them succeeded.
+    pub fn last_watch_errors(&self) -> &[(Ident, Error)] {
+        &self.watch_errors
+    }
+
+    /// Waits up to `timeout` for an event; `None` returns immediately.
+    ///
+    /// Timeouts beyond about 68 years (`i32::MAX` seconds), up to
+    /// `Duration::MAX`, are clamped to that and so effectively wait forever.
+    /// This holds for every method taking a timeout.
+    pub fn poll(&self, timeout: Option<Duration>) -> Option<Event> {
         // poll will not block indefinitely
         // None -> return immediately
         match timeout {
             Some(timeout) => get_event(self, Some(timeout)),
             None => get_event(self, Some(Duration::new(0, 0))),
         }
-    }
+    }
+
+    /// Like `poll`, but also returns how long the call took, for telling a
+    /// ready queue from an idle one. Errors are returned rather than turned
+    /// into error events.
+    pub fn poll_timed(&self, timeout: Option<Duration>) -> Result<(Option<Event>, Duration)> {
+        let started = Instant::now();
+        let ev = self.drain(1, Some(timeout.unwrap_or_default()))?.pop();
+        Ok((ev, started.elapsed()))
+    }
+
+    /// Drains at most `max` events with a single `kevent(2)` call.
+    ///
+    /// The returned flag is `true` when the buffer came back full, meaning
+    /// more events are likely still pending and the caller should come back
+    /// for them after yielding to other work. Like `poll`, a `timeout` of
+    /// `None` returns immediately.
+    pub fn poll_up_to(&self,
+                      max: usize,
+                      timeout: Option<Duration>)
+                      -> Result<(Vec<Event>, bool)> {
+        if max == 0 {
+            return Err(Error::new(io::ErrorKind::InvalidInput, "max must be non-zero"));
+        }
+
+        let events = self.drain(max, Some(timeout.unwrap_or_default()))?;
+        let full = events.len() == max;
+        Ok((events, full))
+    }
+
+    /// Drains every event that is pending, waiting up to `timeout` for the
+    /// first one (`None` returns immediately, as with `poll`).
+    ///
+    /// Events are fetched `batch_size` at a time; whenever a batch comes
+    /// back full the queue is polled again without blocking, so an event is
+    /// never left behind because the buffer was too small. Events are
+    /// returned in the order the kernel delivered them.
+    pub fn poll_all(&self, timeout: Option<Duration>) -> Result<Vec<Event>> {
+        let size = self.opts.batch_size;
+        let mut events = Vec::new();
+        let mut batch = self.drain(size, Some(timeout.unwrap_or_default()))?;
+
+        loop {
+            let full = batch.len() == size;
+            events.append(&mut batch);
+            if !full {
+                self.sort_batch(&mut events);
+                return Ok(events);
+            }
+
+            batch = get_events(self, size, Some(Duration::new(0, 0)))?;
+        }
+    }
+
+    /// Like `poll_all`, but fills the caller's `buf` instead of allocating
+    /// a `Vec` on every call, and returns the events in it. The event loop
+    /// reuses one buffer for good:
+    ///
+    /// ```ignore
+    /// let mut buf = Vec::with_capacity(256);
+    /// loop {
+    ///     for ev in watcher.poll_into(&mut buf, Some(Duration::from_secs(1)))? {
+    ///         // ...
+    ///     }
+    /// }
+    /// ```
+    ///
+    /// `buf` is cleared and takes at most as many events as fit into its
+    /// capacity, which is never grown; a buffer without any is given room
+    /// for `batch_size` events once. Like `poll`, a `timeout` of `None`
+    /// returns immediately.
+    ///
+    /// Nothing is allocated while plain events are delivered, though
+    /// filename idents are copies of the watched names and watch set
+    /// changes (such as moving a persistent watch) allocate as usual.
+    pub fn poll_into<'a>(&self,
+                         buf: &'a mut Vec<Event>,
+                         timeout: Option<Duration>)
+                         -> Result<&'a [Event]> {
+        self.fill_batch(buf, timeout)?;
+        self.sort_batch(buf);
+        Ok(buf)
+    }
+
+    // Fills `buf` for `poll_into`, buffered events first
+    fn fill_batch(&self, buf: &mut Vec<Event>, timeout: Option<Duration>) -> Result<()> {
+        buf.clear();
+        if buf.capacity() == 0 {
+            buf.reserve_exact(self.opts.batch_size);
+        }
+        let max = buf.capacity();
+
+        {
+            let mut pending = self.pending.borrow_mut();
+            let count = cmp::min(max, pending.len());
+            buf.extend(pending.drain(..count));
+        }
+        if buf.len() == max {
+            return Ok(());
+        }
+        if let Some(ev) = take_emptied(self) {
+            buf.push(ev);
+            return Ok(());
+        }
+
+        let timeout = if buf.is_empty() {
+            timeout.unwrap_or_default()
+        } else {
+            Duration::new(0, 0)
+        };
+        if !self.unacked.borrow().is_empty() {
+            // events owed again are rebuilt anyway
+            buf.extend(get_events(self, max - buf.len(), Some(timeout))?);
+            return Ok(());
+        }
+
+        let deadline = Instant::now().checked_add(timeout);
+        let mut wait = timeout;
+        loop {
+            let before = buf.len();
+            let read = read_events_into(self, buf, max - before, Some(wait))?;
+            if read == 0 || buf.len() > before || self.emptied.get() {
+                if buf.len() < max {
+                    buf.extend(take_emptied(self));
+                }
+                return Ok(());
+            }
+
+            // only internal bookkeeping arrived, keep waiting out the timeout
+            if let Some(deadline) = deadline {
+                wait = deadline.saturating_duration_since(Instant::now());
+            }
+        }
+    }
+
+    /// Takes one batch of up to `batch_size` events with a single
+    /// `kevent(2)` call and iterates over it, waiting up to `timeout` for the
+    /// first one (`None` returns immediately, as with `poll`).
+    ///
+    /// Unlike `iter`, the iterator ends with the batch, and its `len()` is
+    /// known up front.
+    pub fn poll_iter(&self,
+                     timeout: Option<Duration>)
+                     -> Result<impl ExactSizeIterator<Item = Event>> {
+        Ok(self.drain(self.opts.batch_size, Some(timeout.unwrap_or_default()))?.into_iter())
+    }
+
+    /// Takes a batch of events like `poll_iter` and groups them by ident,
+    /// keeping the order of each ident's events, e.g. to hand every
+    /// connection handler all of its events at once. Idents of different
+    /// kinds never share a group, see `GroupKey`.
+    pub fn poll_grouped(&self,
+                        timeout: Option<Duration>)
+                        -> Result<HashMap<GroupKey, Vec<Event>>> {
+        let mut groups: HashMap<GroupKey, Vec<Event>> = HashMap::new();
+        for ev in self.drain(self.opts.batch_size, Some(timeout.unwrap_or_default()))? {
+            groups.entry(GroupKey(ev.ident.clone())).or_default().push(ev);
+        }
+
+        Ok(groups)
+    }
+
+    /// Registers the watch set with `watch()`, then waits for events for as
+    /// long as it takes and hands each to `f`, until `f` returns
+    /// `ControlFlow::Break`. Events fetched along with the last one stay
+    /// buffered for later polls.
+    ///
+    /// To stop the loop from another thread, signal a `shutdown_token`, or
+    /// watch a wakeup source such as `add_pipe` and break on its event.
+    pub fn run<F>(&mut self, mut f: F) -> Result<()>
+        where F: FnMut(Event) -> ControlFlow<()>
+    {
+        self.watch()?;
+        loop {
+            let mut events = self.drain(self.opts.batch_size, None)?.into_iter();
+            while let Some(ev) = events.next() {
+                let stop = match ev.data {
+                    EventData::Shutdown | EventData::WatchSetEmpty => true,
+                    _ => f(ev) == ControlFlow::Break(()),
+                };
+                if stop {
+                    let pending = self.pending.get_mut();
+                    for ev in events.rev() {
+                        pending.push_front(ev);
+                    }
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Drops the watches on borrowed descriptors (see `add_file`) that were
+    /// closed behind the watcher's back, and returns how many descriptors
+    /// that were. The next polls report each as `EventData::Invalidated`.
+    ///
+    /// The kernel forgets a closed descriptor's registrations without a
+    /// word, so this checks every borrowed descriptor with a `fcntl(2)`
+    /// call, which polling doesn't do; `watch()` only reports the ones the
+    /// kernel refuses to register.
+    pub fn prune(&self) -> usize {
+        let invalid = prune_invalid(self);
+        let count = invalid.len();
+        self.pending.borrow_mut().extend(invalid);
+        count
+    }
+
+    /// A token that, signaled from any thread, wakes this watcher up with
+    /// `EventData::Shutdown`: `iter` ends, `run` returns, and polls deliver
+    /// the event. Every call hands out a clone of the same token.
+    ///
+    /// The token is backed by a socket pair watched for reading, registered
+    /// right away if the watcher is started and by `watch()` otherwise.
+    pub fn shutdown_token(&self) -> Result<ShutdownToken> {
+        if let Some(ref token) = *self.shutdown.borrow() {
+            return Ok(token.clone());
+        }
+
+        let [read_fd, write_fd] = wake_socketpair()?;
+        let token = ShutdownToken { wake: Arc::new(WakeSocket(write_fd)) };
+        let mut watch = Watched::new(Ident::Fd(read_fd),
+                                     EventFilter::EVFILT_READ,
+                                     FilterFlag::empty());
+        watch.owned = true;
+        watch.shutdown = true;
+        watch.key = self.alloc_key();
+        if self.started {
+            if let Err(err) = self.submit(&[self.to_kevent(&watch)]) {
+                unsafe { libc::close(read_fd) };
+                return Err(err);
+            }
+        }
+
+        self.watched.borrow_mut().push(watch);
+        *self.shutdown.borrow_mut() = Some(token.clone());
+        Ok(token)
+    }
+
+    // Takes up to `max` events, buffered ones first, only waiting for the
+    // kernel when nothing is buffered
+    fn drain(&self, max: usize, timeout: Option<Duration>) -> Result<Vec<Event>> {
+        let mut events: Vec<Event> = {
+            let mut pending = self.pending.borrow_mut();
+            let count = cmp::min(max, pending.len());
+            pending.drain(..count).collect()
+        };
+
+        if events.len() < max {
+            let timeout = if events.is_empty() {
+                timeout
+            } else {
+                Some(Duration::new(0, 0))
+            };
+            events.extend(get_events(self, max - events.len(), timeout)?);
+        }
+
+        self.sort_batch(&mut events);
+        Ok(events)
+    }
+
+    fn sort_batch(&self, events: &mut [Event]) {
+        if let Some(EventOrder(ref cmp)) = self.opts.order {
+            events.sort_by(|a, b| cmp(a, b));
+        }
+    }
+
+    /// Waits up to `timeout` for an event on `ident` (`None` only checks
+    /// what is ready), ignoring other watches. An `Ident::Fd` also matches a
+    /// filename opened as that fd.
+    ///
+    /// Events for other idents that arrive meanwhile are buffered, in order,
+    /// and delivered by later `poll`s and the like.
+    pub fn wait_for(&self, ident: &Ident, timeout: Option<Duration>) -> Result<Option<Event>> {
+        self.next_matching(|ev| ev.ident.same_source(ident), timeout)
+    }
+
+    /// Waits up to `timeout` for the first event `pred` accepts (`None` only
+    /// checks what is ready).
+    ///
+    /// kqueue can't be drained selectively, so rejected events are buffered,
+    /// in order, for later `poll`s and `poll_matching`s rather than dropped.
+    pub fn poll_matching<F>(&mut self, pred: F, timeout: Option<Duration>) -> Result<Option<Event>>
+        where F: Fn(&Event) -> bool
+    {
+        self.next_matching(pred, timeout)
+    }
+
+    /// Returns the next event without consuming it, fetching what is ready
+    /// from the kernel (without waiting) if nothing is buffered. The next
+    /// `poll` or the like returns that same event.
+    pub fn peek(&mut self) -> Result<Option<&Event>> {
+        if self.pending.get_mut().is_empty() {
+            let events = get_events(self, self.opts.batch_size, Some(Duration::new(0, 0)))?;
+            self.pending.get_mut().extend(events);
+        }
+
+        Ok(self.pending.get_mut().front())
+    }
+
+    fn next_matching<F>(&self, pred: F, timeout: Option<Duration>) -> Result<Option<Event>>
+        where F: Fn(&Event) -> bool
+    {
+        {
+            let mut pending = self.pending.borrow_mut();
+            if let Some(index) = pending.iter().position(&pred) {
+                return Ok(pending.remove(index));
+            }
+        }
+
+        let timeout = timeout.unwrap_or_default();
+        let deadline = Instant::now().checked_add(timeout);
+        loop {
+            // never fetch more than the buffer can still take
+            let room = self.opts.max_pending.saturating_sub(self.pending.borrow().len());
+            if room == 0 {
+                return Err(Error::new(io::ErrorKind::WouldBlock,
+                                      "too many events buffered while waiting for a match"));
+            }
+
+            let wait = deadline.map_or(timeout, |at| at.saturating_duration_since(Instant::now()));
+            let events = get_events(self, cmp::min(self.opts.batch_size, room), Some(wait))?;
+            if events.is_empty() {
+                return Ok(None);
+            }
+
+            let mut found = None;
+            let mut pending = self.pending.borrow_mut();
+            for ev in events {
+                if found.is_none() && pred(&ev) {
+                    found = Some(ev);
+                } else {
+                    pending.push_back(ev);
+                }
+            }
+
+            if found.is_some() {
+                return Ok(found);
+            }
+        }
+    }
+
+    /// Waits for a single event until the wall-clock `deadline`.
+    ///
+    /// Returns `Ok(None)` straight away if the deadline has already passed,
+    /// which includes the system clock having been set back past it.
+    pub fn wait_until_systime(&self, deadline: SystemTime) -> Result<Option<Event>> {
+        match deadline.duration_since(SystemTime::now()) {
+            Ok(remaining) => Ok(self.drain(1, Some(remaining))?.pop()),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Like `poll`, but returns an event whose filename ident borrows from
+    /// the watch set instead of allocating a copy, for hot paths watching
+    /// many files. The event must be dropped before the watcher is used
+    /// again.
+    ///
+    /// Unlike the other polls this takes `&mut self`: the borrowed filename
+    /// points into the watch set, which polling through `&self` changes
+    /// (reopening files, dropping finished watches). The exclusive borrow
+    /// has the compiler check that the event is gone before the set can
+    /// change, where a `RefCell` guard held by the event would only fail at
+    /// run time, in whatever poll came next.
+    ///
+    /// Events that change the watch set while being delivered (vnode deletes
+    /// and renames, file creation) and events already buffered by an earlier
+    /// batch have owned idents.
+    pub fn poll_ref(&mut self, timeout: Option<Duration>) -> Result<Option<EventRef>> {
+        if let Some(ev) = self.pending.get_mut().pop_front() {
+            return Ok(Some(ev.into()));
+        }
+        if let Some(ev) = take_emptied(self) {
+            return Ok(Some(ev.into()));
+        }
+
+        // events owed again are rebuilt, so they can't borrow
+        if !self.unacked.get_mut().is_empty() {
+            let mut events = get_events(self, self.opts.batch_size, timeout)?.into_iter();
+            let first = events.next();
+            self.pending.get_mut().extend(events);
+            return Ok(first.map(EventRef::from));
+        }
+
+        let timeout = timeout.unwrap_or_default();
+        let deadline = Instant::now().checked_add(timeout);
+        let kev = loop {
+            let wait = deadline.map_or(timeout, |at| at.saturating_duration_since(Instant::now()));
+            let kev = match read_kevents(self, 1, Some(wait))?.pop() {
+                Some(kev) => kev,
+                None => return Ok(None),
+            };
+
+            // anything that touches the watch set takes the owned path
+            if is_plain_file_event(self, &kev) {
+                self.record_event(kev.filter);
+                break kev;
+            }
+
+            if let Some(ev) = deliver_kevent(self, kev) {
+                return Ok(Some(ev.into()));
+            }
+            if let Some(ev) = take_emptied(self) {
+                return Ok(Some(ev.into()));
+            }
+        };
+
+        let data = EventData::from_kevent(&kev, self.opts.vnode_sizes);
+        let ident = match kev.filter {
+            EventFilter::EVFILT_READ |
+            EventFilter::EVFILT_WRITE |
+            EventFilter::EVFILT_VNODE => {
+                let fd = kev.ident as RawFd;
+                let watched = self.watched.get_mut();
+                let found = watched.iter().find_map(|w| match w.ident {
+                    Ident::Filename(ident_fd, ref name) if ident_fd == fd => {
+                        Some(IdentRef::Filename(fd, Cow::Borrowed(name.as_str())))
+                    }
+                    Ident::Fd(ident_fd) if ident_fd == fd => Some(IdentRef::Fd(fd)),
+                    _ => None,
+                });
+
+                found.unwrap_or(IdentRef::Fd(fd))
+            }
+            EventFilter::EVFILT_SIGNAL => IdentRef::Signal(kev.ident as i32),
+            EventFilter::EVFILT_TIMER => IdentRef::Timer(kev.ident as i32),
+            EventFilter::EVFILT_PROC => IdentRef::Pid(kev.ident as pid_t),
+            #[cfg(target_os = "macos")]
+            EventFilter::EVFILT_VM => IdentRef::Vm,
+            #[cfg(any(target_os = "freebsd", target_os = "dragonfly"))]
+            EventFilter::EVFILT_USER => IdentRef::User(kev.ident),
+            #[cfg(any(target_os = "freebsd", target_os = "macos", target_os = "dragonfly"))]
+            EventFilter::EVFILT_FS => IdentRef::Fs,
+            _ => IdentRef::Fd(kev.ident as RawFd),
+        };
+
+        Ok(Some(EventRef {
+            ident: ident,
+            data: data,
+            filter: kev.filter,
+            udata: kev.udata as usize,
+        }))
+    }
+
+    /// Iterates over events, blocking for each.
+    ///
+    /// Iterating a watcher `watch()` hasn't been called on yet yields a
+    /// single `InvalidInput` error event (with the queue's own descriptor as
+    /// `Ident::Fd`) and then ends, rather than ending silently as if there
+    /// were no events.
+    pub fn iter(&self) -> EventIter {
+        EventIter {
+            watcher: self,
+            reported: false,
+        }
+    }
+
+    /// Iterates over timer events as `(ident, expirations)` pairs, blocking
+    /// for each, for callers that handle timers apart from everything else.
+    /// Other events that arrive meanwhile are buffered for later polls.
+    ///
+    /// Like `iter`, it ends on `Shutdown` or `WatchSetEmpty`, and it also
+    /// ends on a watcher that isn't started. Errors are yielded as they
+    /// come, both failed polls (e.g. too many events piling up in the
+    /// buffer, see `max_pending`) and error events of the timers.
+    pub fn timers(&self) -> Counts {
+        Counts {
+            watcher: self,
+            filter: EventFilter::EVFILT_TIMER,
+            done: false,
+        }
+    }
+
+    /// Iterates over signal events as `(signal, deliveries)` pairs, like
+    /// `timers` does for timers.
+    pub fn signals(&self) -> Counts {
+        Counts {
+            watcher: self,
+            filter: EventFilter::EVFILT_SIGNAL,
+            done: false,
+        }
+    }
+
+    /// Whether `watch()` has registered the watch set with the kernel yet.
+    pub fn is_started(&self) -> bool {
+        self.started
+    }
+}
+
+/// Shares the kernel queue with the original; see `Watcher::try_clone` for
+/// the exact semantics.
+///
+/// # Panics
+///
+/// Panics if the queue descriptor cannot be duplicated, e.g. because the
+/// process has run out of file descriptors; `try_clone` reports that as an
+/// error instead.
+impl Clone for Watcher {
+    fn clone(&self) -> Watcher {
+        self.try_clone().expect("failed to dup kqueue descriptor")
+    }
+}
+
+impl ShutdownToken {
+    /// Wakes the watcher up with `EventData::Shutdown`. Signaling more than
+    /// once before the watcher notices is the same as once. Fails with
+    /// `BrokenPipe` once the watcher is gone, without raising `SIGPIPE`.
+    pub fn signal(&self) -> Result<()> {
+        let ret = unsafe {
+            libc::send(self.wake.0, b"x".as_ptr() as *const c_void, 1, SEND_NOSIGNAL)
+        };
+        if ret == -1 {
+            let err = Error::last_os_error();
+            // a full socket has a wakeup pending already
+            if err.kind() != io::ErrorKind::WouldBlock {
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for WakeSocket {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+impl Drop for Watcher {
+    // may run while unwinding, where panicking again would abort: nothing
+    // here may fail loudly
+    fn drop(&mut self) {
+        unsafe { libc::close(self.queue) };
+        // a failed close (the file was closed behind our back) is of no
+        // consequence here
+        for fd in owned_fds(self.watched.get_mut()) {
+            unsafe { libc::close(fd) };
+        }
+    }
+}
+
+// The descriptors the watcher opened itself, each once even if several
+// watches share it: closing a number twice could hit a recycled one
+fn owned_fds(watched: &[Watched]) -> Vec<RawFd> {
+    let mut fds: Vec<RawFd> = watched.iter()
+        .filter(|w| w.owned)
+        .filter_map(|w| w.ident.as_fd())
+        .collect();
+    fds.sort_unstable();
+    fds.dedup();
+    fds
+}
+
+// Drops the watches whose descriptor has the number of `fresh`'s under a
+// different ident. `fresh` holds a live descriptor the watcher owns, so they
+// can only be left over from one closed behind its back: the kernel dropped
+// their registrations and they must neither shadow `fresh` in
+// `find_file_ident` nor have the reused number closed on their behalf
+fn forget_stale(watched: &mut Vec<Watched>, fresh: &Watched) {
+    let fd = match fresh.ident.as_fd() {
+        Some(fd) => fd,
+        None => return,
+    };
+
+    watched.retain(|w| match (&w.ident, &fresh.ident) {
+        _ if w.ident.as_fd() != Some(fd) => true,
+        (&Ident::Filename(_, ref old), &Ident::Filename(_, ref new)) => old == new,
+        (&Ident::Fd(_), &Ident::Fd(_)) => true,
+        _ => false,
+    });
+}
+
+fn find_file_ident(watcher: &Watcher, fd: RawFd) -> Option<Ident> {
+    for watched in watcher.watched.borrow().iter() {
+        match watched.ident.clone() {
+            Ident::Fd(ident_fd) => {
+                if fd == ident_fd {
+                    return Some(Ident::Fd(fd));
+                } else {
+                    continue;
+                }
+            }
+            Ident::Filename(ident_fd, ident_str) => {
+                if fd == ident_fd {
+                    return Some(Ident::Filename(ident_fd, ident_str));
+                } else {
+                    continue;
+                }
+            }
+            _ => continue,
+        }
+    }
+
+    None
+}
+
+// Whether a vnode event means the file left its path
+fn is_unlink(kev: &kevent) -> bool {
+    kev.filter == EventFilter::EVFILT_VNODE && kev.fflags.intersects(NOTE_DELETE | NOTE_RENAME)
+}
+
+// Moves a persistent watch whose file was deleted or renamed onto whatever
+// file now lives at its path. Returns the replacement event on success.
+fn reopen_persistent(watcher: &Watcher, kev: &kevent) -> Option<Event> {
+    if !is_unlink(kev) {
+        return None;
+    }
+
+    let mut watched = watcher.watched.borrow_mut();
+    let entry = watched.iter_mut().find(|w| match w.ident {
+        Ident::Filename(fd, _) => w.persistent && fd == kev.ident as RawFd,
+        _ => false,
+    })?;
+
+    let (old_fd, path) = match entry.ident {
+        Ident::Filename(fd, ref path) => (fd, path.clone()),
+        _ => return None,
+    };
+
+    let new_fd = File::open(&path).ok()?.into_raw_fd();
+    let mut replacement = entry.clone();
+    replacement.ident = Ident::Filename(new_fd, path);
+    // this handle opened the new fd, so it closes it too
+    replacement.owned = true;
+
+    if watcher.submit(&[watcher.to_kevent(&replacement)]).is_err() {
+        unsafe { libc::close(new_fd) };
+        return None;
+    }
+
+    // closing the stale fd also drops its registration; one borrowed from
+    // the watcher a `try_clone` was made of is left to its owner
+    if entry.owned {
+        unsafe { libc::close(old_fd) };
+    }
+    *entry = replacement;
+
+    Some(Event {
+        ident: entry.ident.clone(),
+        data: EventData::Vnode(Vnode::Replace),
+        filter: entry.filter,
+        udata: entry.udata,
+        notes: FilterFlag::empty(),
+        raw_data: 0,
+        last: false,
+    })
+}
+
+// Drops every watch on a deleted file's fd, closing it if we own it
+fn remove_deleted(watcher: &Watcher, kev: &kevent) {
+    if kev.filter != EventFilter::EVFILT_VNODE || !kev.fflags.contains(NOTE_DELETE) {
+        return;
+    }
+
+    let fd = kev.ident as RawFd;
+    let mut owned = false;
+    watcher.watched.borrow_mut().retain(|w| {
+        let matches = match w.ident {
+            Ident::Fd(ident_fd) | Ident::Filename(ident_fd, _) => ident_fd == fd,
+            _ => false,
+        };
+
+        owned |= matches && w.owned;
+        !matches
+    });
+
+    if owned {
+        unsafe { libc::close(fd) };
+    } else {
+        let _ = watcher.delete_kevents(Ident::Fd(fd), EventFilter::EVFILT_VNODE);
+    }
+}
+
+// Collects the wait status of an exited child watched with add_child
+fn reap_child(watcher: &Watcher, kev: &kevent) -> Option<usize> {
+    if kev.filter != EventFilter::EVFILT_PROC || !kev.fflags.contains(NOTE_EXIT) {
+        return None;
+    }
+
+    let pid = kev.ident as pid_t;
+    let reap = watcher.watched
+        .borrow()
+        .iter()
+        .any(|w| w.reap && w.ident == Ident::Pid(pid));
+    if !reap {
+        return None;
+    }
+
+    let mut status: libc::c_int = 0;
+    match unsafe { libc::waitpid(pid, &mut status, libc::WNOHANG) } {
+        ret if ret == pid => Some(status as usize),
+        _ => None,
+    }
+}
+
+// Opens `dir` and the directories below it that aren't watched yet, down to
+// the depth cap and up to the directory cap. Unreadable ones are skipped.
+fn walk_tree(watcher: &Watcher, dir: &Path, depth: usize, flags: FilterFlag) -> Vec<Watched> {
+    let mut known: HashSet<String> = watcher.watched
+        .borrow()
+        .iter()
+        .filter_map(|w| match (&w.ident, w.tree) {
+            (&Ident::Filename(_, ref name), Some(_)) => Some(name.clone()),
+            _ => None,
+        })
+        .collect();
+    let mut room = watcher.opts.tree_dirs.saturating_sub(known.len());
+    let mut found = Vec::new();
+    let mut dirs = vec![(dir.to_path_buf(), depth)];
+
+    while let Some((dir, depth)) = dirs.pop() {
+        if room == 0 {
+            break;
+        }
+
+        // a watched directory is never opened twice
+        let name = dir.to_string_lossy().into_owned();
+        if known.contains(&name) {
+            continue;
+        }
+        let file = match File::open(&dir) {
+            Ok(file) => file,
+            Err(_) => continue,
+        };
+        known.insert(name.clone());
+
+        if depth < watcher.opts.tree_depth {
+            if let Ok(entries) = std::fs::read_dir(&dir) {
+                // file_type doesn't follow symlinks, so loops can't happen
+                for entry in entries.filter_map(|entry| entry.ok()) {
+                    if entry.file_type().map(|kind| kind.is_dir()).unwrap_or(false) {
+                        dirs.push((entry.path(), depth + 1));
+                    }
+                }
+            }
+        }
+
+        let mut watch = Watched::new(Ident::Filename(file.into_raw_fd(), name),
+                                     EventFilter::EVFILT_VNODE,
+                                     flags);
+        watch.owned = true;
+        watch.tree = Some(depth);
+        found.push(watch);
+        room -= 1;
+    }
+
+    found
+}
+
+// Keeps an `add_tree` watch set in step with a directory whose entries
+// changed: watches of vanished subdirectories (and everything below them)
+// are dropped and new subdirectories are watched
+fn track_tree(watcher: &Watcher, kev: &kevent) {
+    if kev.filter != EventFilter::EVFILT_VNODE || !kev.fflags.contains(NOTE_WRITE) {
+        return;
+    }
+
+    let found = watcher.watched.borrow().iter().find_map(|w| match (&w.ident, w.tree) {
+        (&Ident::Filename(fd, ref name), Some(depth)) if fd == kev.ident as RawFd => {
+            Some((PathBuf::from(name), depth, w.flags))
+        }
+        _ => None,
+    });
+    let (dir, depth, flags) = match found {
+        Some(found) => found,
+        None => return,
+    };
+
+    let gone: Vec<PathBuf> = watcher.watched
+        .borrow()
+        .iter()
+        .filter_map(|w| match (&w.ident, w.tree) {
+            (&Ident::Filename(_, ref name), Some(_)) => Some(PathBuf::from(name)),
+            _ => None,
+        })
+        .filter(|path| path.parent() == Some(dir.as_path()) && !path.is_dir())
+        .collect();
+    if !gone.is_empty() {
+        let mut watched = watcher.watched.borrow_mut();
+        let mut owned = Vec::new();
+        watched.retain(|w| {
+            let below = match (&w.ident, w.tree) {
+                (&Ident::Filename(_, ref name), Some(_)) => {
+                    gone.iter().any(|path| Path::new(name).starts_with(path))
+                }
+                _ => false,
+            };
+            if below && w.owned {
+                owned.extend(w.ident.as_fd());
+            }
+            !below
+        });
+        // closing the descriptors also drops their registrations; those of
+        // the watcher a `try_clone` was made of are left to their owner
+        owned.sort_unstable();
+        owned.dedup();
+        for fd in owned {
+            if !watched.iter().any(|w| w.ident.as_fd() == Some(fd)) {
+                unsafe { libc::close(fd) };
+            }
+        }
+    }
+
+    if depth >= watcher.opts.tree_depth {
+        return;
+    }
+    // only the changed directory is scanned, and only its new subdirectories
+    // are walked
+    let known: HashSet<PathBuf> = watcher.watched
+        .borrow()
+        .iter()
+        .filter_map(|w| match (&w.ident, w.tree) {
+            (&Ident::Filename(_, ref name), Some(_)) => Some(PathBuf::from(name)),
+            _ => None,
+        })
+        .filter(|path| path.parent() == Some(dir.as_path()))
+        .collect();
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        if known.contains(&entry.path()) ||
+           !entry.file_type().map(|kind| kind.is_dir()).unwrap_or(false) {
+            continue;
+        }
+
+        for mut watch in walk_tree(watcher, &entry.path(), depth + 1, flags) {
+            watch.key = watcher.alloc_key();
+            if watcher.submit(&[watcher.to_kevent(&watch)]).is_ok() {
+                watcher.watched.borrow_mut().push(watch);
+            } else if let Some(fd) = watch.ident.as_fd() {
+                unsafe { libc::close(fd) };
+            }
+        }
+    }
+}
+
+#[cfg(any(target_os = "freebsd", target_os = "macos"))]
+fn group_watch(pid: pid_t, pgid: pid_t) -> Watched {
+    let mut watch = Watched::new(Ident::Pid(pid), EventFilter::EVFILT_PROC, group_notes());
+    watch.pgid = Some(pgid);
+    watch
+}
+
+#[cfg(target_os = "freebsd")]
+fn group_notes() -> FilterFlag {
+    NOTE_EXIT | NOTE_TRACK
+}
+
+#[cfg(target_os = "macos")]
+fn group_notes() -> FilterFlag {
+    NOTE_EXIT | NOTE_FORK
+}
+
+#[cfg(target_os = "freebsd")]
+fn group_members(pgid: pid_t) -> Result<Vec<pid_t>> {
+    let mib = [libc::CTL_KERN, libc::KERN_PROC, libc::KERN_PROC_PGRP, pgid];
+    let mut len: libc::size_t = 0;
+    let sized = unsafe {
+        libc::sysctl(mib.as_ptr(), 4, ptr::null_mut(), &mut len, ptr::null(), 0)
+    };
+    if sized == -1 {
+        return Err(Error::last_os_error());
+    }
+
+    // leave room for processes forked since sizing
+    let size = std::mem::size_of::<libc::kinfo_proc>();
+    let mut procs: Vec<libc::kinfo_proc> = Vec::with_capacity(len / size + 8);
+    len = procs.capacity() * size;
+    let listed = unsafe {
+        libc::sysctl(mib.as_ptr(),
+                     4,
+                     procs.as_mut_ptr() as *mut c_void,
+                     &mut len,
+                     ptr::null(),
+                     0)
+    };
+    if listed == -1 {
+        return Err(Error::last_os_error());
+    }
+
+    unsafe { procs.set_len(len / size) };
+    Ok(procs.iter().map(|proc_info| proc_info.ki_pid).collect())
+}
+
+#[cfg(target_os = "macos")]
+fn group_members(pgid: pid_t) -> Result<Vec<pid_t>> {
+    let count = unsafe { libc::proc_listpgrppids(pgid, ptr::null_mut(), 0) };
+    if count == -1 {
+        return Err(Error::last_os_error());
+    }
+
+    // leave room for processes forked since sizing
+    let mut pids: Vec<pid_t> = vec![0; count as usize + 8];
+    let size = (pids.len() * std::mem::size_of::<pid_t>()) as libc::c_int;
+    let count = unsafe {
+        libc::proc_listpgrppids(pgid, pids.as_mut_ptr() as *mut c_void, size)
+    };
+    if count == -1 {
+        return Err(Error::last_os_error());
+    }
+
+    pids.truncate(count as usize);
+    Ok(pids)
+}
+
+// Keeps the members of watched process groups in the watch set as they fork
+// and exit
+#[cfg(any(target_os = "freebsd", target_os = "macos"))]
+fn track_group(watcher: &Watcher, kev: &kevent) {
+    if kev.filter != EventFilter::EVFILT_PROC {
+        return;
+    }
+
+    let pid = kev.ident as pid_t;
+    let pgid = watcher.watched
+        .borrow()
+        .iter()
+        .filter(|w| w.filter == EventFilter::EVFILT_PROC)
+        .find_map(|w| match w.ident {
+            Ident::Pid(member) if member == pid => w.pgid,
+            // a tracked child is reported under its own pid
+            _ if kev.fflags.contains(NOTE_CHILD) && w.ident == Ident::Pid(kev.data as pid_t) => {
+                w.pgid
+            }
+            _ => None,
+        });
+    let pgid = match pgid {
+        Some(pgid) => pgid,
+        None => return,
+    };
+
+    if kev.fflags.contains(NOTE_EXIT) {
+        // the kernel drops the registration of an exited process
+        watcher.watched
+            .borrow_mut()
+            .retain(|w| w.pgid.is_none() || w.ident != Ident::Pid(pid));
+    } else if kev.fflags.contains(NOTE_CHILD) {
+        let mut watch = group_watch(pid, pgid);
+        watch.key = watcher.alloc_key();
+        watcher.watched.borrow_mut().push(watch);
+    } else if cfg!(target_os = "macos") && kev.fflags.contains(NOTE_FORK) {
+        let members = match group_members(pgid) {
+            Ok(members) => members,
+            Err(_) => return,
+        };
+
+        for member in members {
+            let known = watcher.watched
+                .borrow()
+                .iter()
+                .any(|w| w.pgid.is_some() && w.ident == Ident::Pid(member));
+            if known {
+                continue;
+            }
+
+            let mut watch = group_watch(member, pgid);
+            watch.key = watcher.alloc_key();
+            if watcher.submit(&[watcher.to_kevent(&watch)]).is_ok() {
+                watcher.watched.borrow_mut().push(watch);
+            }
+        }
+    }
+}
+
+// Moves a watch waiting for its file to be created (watching the parent
+// directory `dir_fd`) onto the file, if it exists now
+fn finish_create(watcher: &Watcher, dir_fd: RawFd) -> Option<Event> {
+    let mut watched = watcher.watched.borrow_mut();
+    let entry = watched.iter_mut()
+        .find(|w| w.creating.is_some() && w.ident.as_fd() == Some(dir_fd))?;
+    let (path, flags) = entry.creating.clone()?;
+
+    let new_fd = File::open(&path).ok()?.into_raw_fd();
+    let mut replacement = entry.clone();
+    replacement.ident = Ident::Filename(new_fd, path);
+    replacement.flags = flags;
+    replacement.creating = None;
+    // this handle opened the new fd, so it closes it too
+    replacement.owned = true;
+
+    if watcher.submit(&[watcher.to_kevent(&replacement)]).is_err() {
+        unsafe { libc::close(new_fd) };
+        return None;
+    }
+
+    // as in reopen_persistent, a directory fd borrowed from the original
+    // watcher is left to its owner
+    if entry.owned {
+        unsafe { libc::close(dir_fd) };
+    }
+    *entry = replacement;
+
+    Some(Event {
+        ident: entry.ident.clone(),
+        data: EventData::Vnode(Vnode::Create),
+        filter: entry.filter,
+        udata: entry.udata,
+        notes: FilterFlag::empty(),
+        raw_data: 0,
+        last: false,
+    })
+}
+
+fn is_creating(watcher: &Watcher, kev: &kevent) -> bool {
+    kev.filter == EventFilter::EVFILT_VNODE &&
+    watcher.watched
+        .borrow()
+        .iter()
+        .any(|w| w.creating.is_some() && w.ident.as_fd() == Some(kev.ident as RawFd))
+}
+
+// Makes the kernel acknowledge every change in the event list; OpenBSD
+// reports only failed changes there
+#[cfg(not(target_os = "openbsd"))]
+fn receipt_flag() -> EventFlag {
+    EV_RECEIPT
+}
+
+#[cfg(target_os = "openbsd")]
+fn receipt_flag() -> EventFlag {
+    EventFlag::empty()
+}
+
+fn copy_error(err: &Error) -> Error {
+    match err.raw_os_error() {
+        Some(code) => Error::from_raw_os_error(code),
+        None => Error::new(err.kind(), err.to_string()),
+    }
+}
+
+// The filter and notes that catch a device being revoked
+#[cfg(any(target_os = "freebsd", target_os = "dragonfly"))]
+fn device_watch() -> (EventFilter, FilterFlag) {
+    (EventFilter::EVFILT_READ, FilterFlag::empty())
+}
+
+#[cfg(not(any(target_os = "freebsd", target_os = "dragonfly")))]
+fn device_watch() -> (EventFilter, FilterFlag) {
+    (EventFilter::EVFILT_VNODE, NOTE_REVOKE)
+}
+
+// Flags for opening a file only to watch it
+#[cfg(target_os = "macos")]
+fn event_open_flags() -> libc::c_int {
+    libc::O_EVTONLY | libc::O_CLOEXEC
+}
+
+#[cfg(not(target_os = "macos"))]
+fn event_open_flags() -> libc::c_int {
+    libc::O_RDONLY | libc::O_CLOEXEC
+}
+
+// A close-on-exec, non-blocking pipe as [read end, write end]
+#[cfg(not(target_os = "macos"))]
+fn cloexec_pipe() -> Result<[RawFd; 2]> {
+    let mut fds = [0; 2];
+    match unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) } {
+        -1 => Err(Error::last_os_error()),
+        _ => Ok(fds),
+    }
+}
+
+// macOS has no pipe2(2), so the flags are set right after creating the pipe
+#[cfg(target_os = "macos")]
+fn cloexec_pipe() -> Result<[RawFd; 2]> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+        return Err(Error::last_os_error());
+    }
+
+    for &fd in &fds {
+        let failed = unsafe {
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) == -1 ||
+            libc::fcntl(fd, libc::F_SETFL, libc::O_NONBLOCK) == -1
+        };
+        if failed {
+            let err = Error::last_os_error();
+            unsafe {
+                libc::close(fds[0]);
+                libc::close(fds[1]);
+            }
+            return Err(err);
+        }
+    }
+
+    Ok(fds)
+}
+
+// A close-on-exec, non-blocking stream socket pair as [receiving end, sending
+// end], for a wakeup that mustn't raise SIGPIPE once the receiving end is gone
+#[cfg(not(target_os = "macos"))]
+fn wake_socketpair() -> Result<[RawFd; 2]> {
+    let mut fds = [0; 2];
+    let kind = libc::SOCK_STREAM | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK;
+    match unsafe { libc::socketpair(libc::AF_UNIX, kind, 0, fds.as_mut_ptr()) } {
+        -1 => Err(Error::last_os_error()),
+        _ => Ok(fds),
+    }
+}
+
+// macOS has neither the socket type flags nor MSG_NOSIGNAL, so the sending
+// end opts out of SIGPIPE with SO_NOSIGPIPE instead
+#[cfg(target_os = "macos")]
+fn wake_socketpair() -> Result<[RawFd; 2]> {
+    let mut fds = [0; 2];
+    if unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr()) } == -1 {
+        return Err(Error::last_os_error());
+    }
+
+    let on: libc::c_int = 1;
+    let failed = unsafe {
+        libc::fcntl(fds[0], libc::F_SETFD, libc::FD_CLOEXEC) == -1 ||
+        libc::fcntl(fds[1], libc::F_SETFD, libc::FD_CLOEXEC) == -1 ||
+        libc::fcntl(fds[0], libc::F_SETFL, libc::O_NONBLOCK) == -1 ||
+        libc::fcntl(fds[1], libc::F_SETFL, libc::O_NONBLOCK) == -1 ||
+        libc::setsockopt(fds[1],
+                         libc::SOL_SOCKET,
+                         libc::SO_NOSIGPIPE,
+                         &on as *const libc::c_int as *const c_void,
+                         std::mem::size_of::<libc::c_int>() as libc::socklen_t) == -1
+    };
+    if failed {
+        let err = Error::last_os_error();
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+        return Err(err);
+    }
+
+    Ok(fds)
+}
+
+#[cfg(not(target_os = "macos"))]
+const SEND_NOSIGNAL: libc::c_int = libc::MSG_NOSIGNAL;
+
+#[cfg(target_os = "macos")]
+const SEND_NOSIGNAL: libc::c_int = 0;
+
+// Whether a read event belongs to a watch from `add_lines`
+fn is_line_watch(watcher: &Watcher, kev: &kevent) -> bool {
+    kev.filter == EventFilter::EVFILT_READ &&
+    watcher.watched
+        .borrow()
+        .iter()
+        .any(|w| w.lines && w.ident.as_fd() == Some(kev.ident as RawFd))
+}
+
+// Reads what the fd of a line watch has available and splits off the
+// complete lines. The first becomes the event and the others are buffered
+// behind it; None while no line is complete.
+fn read_lines(watcher: &Watcher, kev: kevent) -> Option<Event> {
+    let fd = kev.ident as RawFd;
+    let eof = kev.flags.contains(EV_EOF);
+    let mut texts = Vec::new();
+    {
+        let mut lines = watcher.lines.borrow_mut();
+        let buf = lines.entry(fd).or_default();
+        if kev.data > 0 {
+            let start = buf.len();
+            buf.resize(start + kev.data as usize, 0);
+            let ret = unsafe {
+                libc::read(fd, buf[start..].as_mut_ptr() as *mut c_void, kev.data as usize)
+            };
+            buf.truncate(start + cmp::max(ret, 0) as usize);
+        }
+
+        while let Some(end) = buf.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = buf.drain(..=end).collect();
+            texts.push(String::from_utf8_lossy(&line[..end]).into_owned());
+        }
+        if eof && !buf.is_empty() {
+            texts.push(String::from_utf8_lossy(buf).into_owned());
+            buf.clear();
+        }
+    }
+
+    let mut events: Vec<Event> = texts.into_iter()
+        .map(|text| {
+            let mut ev = Event::new(kev, watcher);
+            ev.data = EventData::Line(text);
+            ev
+        })
+        .collect();
+    if eof {
+        let mut ev = Event::new(kev, watcher);
+        ev.data = EventData::ReadReady(0);
+        events.push(ev);
+    }
+
+    let mut events = events.into_iter();
+    let first = events.next();
+    watcher.pending.borrow_mut().extend(events);
+    first
+}
+
+// Whether a read event is the end-of-file standing in for a device revoke
+fn is_revoked_device(watcher: &Watcher, kev: &kevent) -> bool {
+    kev.filter == EventFilter::EVFILT_READ && kev.flags.contains(EV_EOF) &&
+    watcher.watched.borrow().iter().any(|w| {
+        w.device && w.filter == kev.filter && w.ident.as_fd() == Some(kev.ident as RawFd)
+    })
+}
+
+// Whether the kevent is for the receiving end of a shutdown token's socket
+fn is_shutdown(watcher: &Watcher, kev: &kevent) -> bool {
+    kev.filter == EventFilter::EVFILT_READ &&
+    watcher.watched
+        .borrow()
+        .iter()
+        .any(|w| w.shutdown && w.ident.as_fd() == Some(kev.ident as RawFd))
+}
+
+// Whether an fd event is delivered exactly as the kernel reported it
+fn is_plain_file_event(watcher: &Watcher, kev: &kevent) -> bool {
+    let plain = match kev.filter {
+        EventFilter::EVFILT_READ => {
+            !is_revoked_device(watcher, kev) && !is_line_watch(watcher, kev)
+        }
+        EventFilter::EVFILT_WRITE => !is_revoked_device(watcher, kev),
+        EventFilter::EVFILT_VNODE => !is_unlink(kev) && !is_creating(watcher, kev),
+        _ => false,
+    };
+
+    plain && !needs_ack(watcher, kev) && !ends_watch(watcher, kev)
+}
+
+// Whether `kev` belongs to a watch set up with `require_ack`
+fn needs_ack(watcher: &Watcher, kev: &kevent) -> bool {
+    watcher.watched
+        .borrow()
+        .iter()
+        .any(|w| w.ack && w.filter == kev.filter && w.ident.as_usize() == kev.ident)
+}
+
+// Like `process_kevent`, counting the events that reach the caller and
+// remembering the ones to deliver again until acked
+fn deliver_kevent(watcher: &Watcher, kev: kevent) -> Option<Event> {
+    let ev = settle_kevent(watcher, kev);
+    if ev.is_some() {
+        watcher.record_event(kev.filter);
+        if needs_ack(watcher, &kev) {
+            let mut unacked = watcher.unacked.borrow_mut();
+            unacked.retain(|owed| !(owed.ident == kev.ident && owed.filter == kev.filter));
+            unacked.push(Owed::new(&kev));
+        }
+    }
+    ev
+}
+
+// Like `process_kevent`, also forgetting the watches the kevent ended
+fn settle_kevent(watcher: &Watcher, kev: kevent) -> Option<Event> {
+    let watching = !watcher.watched.borrow().is_empty();
+    let ev = process_kevent(watcher, kev);
+    retire_finished(watcher, &kev);
+    if watching && watcher.watched.borrow().is_empty() {
+        watcher.emptied.set(true);
+    }
+    ev
+}
+
+// Whether the kernel dropped the registration `kev` came from: one-shot
+// watches fire once, and an exited process is gone
+fn ends_watch(watcher: &Watcher, kev: &kevent) -> bool {
+    may_finish(kev) && watcher.watched.borrow().iter().any(|w| finished(w, kev))
+}
+
+// Whether `kev` can end a watch at all, which spares looking through the
+// watch set for every other kevent: one-shot kevents carry EV_ONESHOT
+fn may_finish(kev: &kevent) -> bool {
+    kev.flags.contains(EV_ONESHOT) ||
+    kev.filter == EventFilter::EVFILT_PROC && kev.fflags.contains(NOTE_EXIT)
+}
+
+fn finished(w: &Watched, kev: &kevent) -> bool {
+    let exited = kev.filter == EventFilter::EVFILT_PROC && kev.fflags.contains(NOTE_EXIT);
+    w.filter == kev.filter && w.ident.as_usize() == kev.ident &&
+    (exited || w.event_flags.contains(EV_ONESHOT))
+}
+
+// Forgets the watches `kev` ended, closing descriptors nothing else watches
+fn retire_finished(watcher: &Watcher, kev: &kevent) {
+    if !may_finish(kev) {
+        return;
+    }
+
+    let mut watched = watcher.watched.borrow_mut();
+    let mut owned = None;
+    watched.retain(|w| {
+        // watches waiting for an ack are only forgotten by removing them
+        let done = finished(w, kev) && !w.ack;
+        if done && w.owned {
+            owned = w.ident.as_fd();
+        }
+        !done
+    });
+
+    if let Some(fd) = owned {
+        if !watched.iter().any(|w| w.ident.as_fd() == Some(fd)) {
+            unsafe { libc::close(fd) };
+        }
+    }
+}
+
+// The event for a watch set that ran empty since it was last reported
+fn take_emptied(watcher: &Watcher) -> Option<Event> {
+    if !watcher.emptied.replace(false) {
+        return None;
+    }
+
+    Some(Event {
+        ident: Ident::Fd(watcher.queue),
+        data: EventData::WatchSetEmpty,
+        filter: EventFilter::EVFILT_SYSCOUNT,
+        udata: 0,
+        notes: FilterFlag::empty(),
+        raw_data: 0,
+        last: false,
+    })
+}
+
+// Rebuilds the events still waiting for an ack, forgetting those whose watch
+// is gone
+fn unacked_events(watcher: &Watcher) -> Vec<Event> {
+    let owed: Vec<kevent> = {
+        let mut unacked = watcher.unacked.borrow_mut();
+        unacked.retain(|owed| needs_ack(watcher, &owed.to_kevent()));
+        unacked.iter().map(|owed| owed.to_kevent()).collect()
+    };
+
+    owed.into_iter().map(|kev| Event::new(kev, watcher)).collect()
+}
+
+// Applies the watch set bookkeeping a kevent calls for and turns it into an
+// event, or None if the kevent was purely internal
+fn process_kevent(watcher: &Watcher, kev: kevent) -> Option<Event> {
+    if is_shutdown(watcher, &kev) {
+        return Some(Event {
+            ident: Ident::Fd(kev.ident as RawFd),
+            data: EventData::Shutdown,
+            filter: kev.filter,
+            udata: 0,
+            notes: FilterFlag::empty(),
+            raw_data: 0,
+            last: false,
+        });
+    }
+
+    if is_line_watch(watcher, &kev) {
+        return read_lines(watcher, kev);
+    }
+
+    if is_creating(watcher, &kev) {
+        // the directory changed; only the file appearing is of interest
+        return finish_create(watcher, kev.ident as RawFd);
+    }
+
+    if let Some(ev) = reopen_persistent(watcher, &kev) {
+        return Some(ev);
+    }
+
+    let mut ev = Event::new(kev, watcher);
+    if is_revoked_device(watcher, &kev) {
+        ev.data = EventData::Vnode(Vnode::Revoke);
+    }
+
+    #[cfg(any(target_os = "freebsd", target_os = "macos"))]
+    track_group(watcher, &kev);
+
+    track_tree(watcher, &kev);
+
+    if let Some(status) = reap_child(watcher, &kev) {
+        ev.data = EventData::Proc(Proc::Exit(status));
+    }
+
+    #[cfg(any(target_os = "freebsd", target_os = "dragonfly"))]
+    {
+        if kev.filter == EventFilter::EVFILT_USER {
+            ev.data = EventData::User(cmp::max(take_triggers(watcher, kev.ident), 1));
+        }
+    }
+
+    if watcher.opts.remove_on_delete {
+        remove_deleted(watcher, &kev);
+    }
+
+    Some(ev)
+}
+
+// Resets the trigger count of user event `id`, returning the old count
+#[cfg(any(target_os = "freebsd", target_os = "dragonfly"))]
+fn take_triggers(watcher: &Watcher, id: usize) -> usize {
+    watcher.watched
+        .borrow_mut()
+        .iter_mut()
+        .find(|w| w.filter == EventFilter::EVFILT_USER && w.ident == Ident::User(id))
+        .map_or(0, |w| std::mem::replace(&mut w.triggers, 0))
+}
+
+fn get_event(watcher: &Watcher, timeout: Option<Duration>) -> Option<Event> {
+    let ev = next_event(watcher, timeout);
+    if let (Some(ref ev), Some(&mut Tap(ref mut tap))) = (&ev, watcher.tap.borrow_mut().as_mut()) {
+        tap(ev);
+    }
+
+    ev
+}
+
+fn next_event(watcher: &Watcher, timeout: Option<Duration>) -> Option<Event> {
+    if let Some(ev) = watcher.pending.borrow_mut().pop_front() {
+        return Some(ev);
+    }
+
+    match get_events(watcher, watcher.opts.batch_size, timeout) {
+        Ok(events) => {
+            let mut events = events.into_iter();
+            let first = events.next();  // None: timeout expired
+            watcher.pending.borrow_mut().extend(events);
+            first
+        }
+        Err(err) => {
+            Some(Event::from_parts(Ident::Fd(watcher.queue),
+                                   EventData::Error(err),
+                                   EventFilter::EVFILT_SYSCOUNT))
+        }
+    }
+}
+
+/// The notes a watch on `filter` gets when it is added with empty flags.
+///
+/// Vnode watches default to every portable vnode note and process watches
+/// to `NOTE_EXIT`, since neither fires at all without notes. Other filters
+/// need none.
+pub fn default_flags(filter: EventFilter) -> FilterFlag {
+    match filter {
+        EventFilter::EVFILT_VNODE => {
+            NOTE_DELETE | NOTE_WRITE | NOTE_EXTEND | NOTE_ATTRIB | NOTE_LINK | NOTE_RENAME |
+            NOTE_REVOKE
+        }
+        EventFilter::EVFILT_PROC => NOTE_EXIT,
+        #[cfg(target_os = "macos")]
+        EventFilter::EVFILT_VM => NOTE_VM_PRESSURE,
+        #[cfg(target_os = "dragonfly")]
+        EventFilter::EVFILT_EXCEPT => NOTE_OOB,
+        _ => FilterFlag::empty(),
+    }
+}
+
+/// Reads everything `source` has available until it would block or ends,
+/// as an edge-triggered `EventData::ReadReady` calls for. `source` must be
+/// non-blocking, otherwise this blocks once the data is read.
+pub fn drain_fully<R: Read>(source: &mut R) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        match source.read(&mut buf) {
+            Ok(0) => return Ok(data),
+            Ok(n) => data.extend_from_slice(&buf[..n]),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(data),
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Waits up to `timeout` for any of `fds` to become readable (or reach
+/// end-of-file) and returns those that are, in the order given. Like
+/// `Watcher::poll`, a `timeout` of `None` only checks what is ready.
+///
+/// A replacement for `select(2)` in simple programs: a temporary kqueue
+/// watches the fds level-triggered and is closed again before returning,
+/// leaving the fds open. An fd that isn't open fails the call.
+pub fn wait_readable(fds: &[RawFd], timeout: Option<Duration>) -> Result<Vec<RawFd>> {
+    let mut watcher = Watcher::new()?;
+    watcher.disable_clears();
+    for &fd in fds {
+        watcher.add_fd(fd, EventFilter::EVFILT_READ, FilterFlag::empty())?;
+    }
+
+    let mut ready = Vec::new();
+    for ev in watcher.watch_and_collect(timeout)? {
+        match (ev.ident, ev.data) {
+            (Ident::Fd(fd), EventData::ReadReady(_)) => ready.push(fd),
+            (Ident::Fd(fd), EventData::Invalidated) => {
+                return Err(Error::new(io::ErrorKind::InvalidInput,
+                                      format!("{} is not an open fd", fd)))
+            }
+            (_, EventData::Error(err)) => return Err(err),
+            _ => (),
+        }
+    }
+
+    Ok(fds.iter().cloned().filter(|fd| ready.contains(fd)).collect())
+}
+
+// The filter a stored `FileConfig` names, if it is one for files
+fn file_filter(raw: i16) -> Option<EventFilter> {
+    [EventFilter::EVFILT_READ, EventFilter::EVFILT_WRITE, EventFilter::EVFILT_VNODE]
+        .iter()
+        .cloned()
+        .find(|&filter| filter as i16 == raw)
+}
+
+// Input notes each filter understands on this platform, listed for each
+// filter rather than taken from its defaults, or None when the crate doesn't
+// know the filter well enough to check
+fn valid_flags(filter: EventFilter) -> Option<FilterFlag> {
+    match filter {
+        EventFilter::EVFILT_READ | EventFilter::EVFILT_WRITE => {
+            Some(NOTE_LOWAT | platform_rw_notes())
+        }
+        EventFilter::EVFILT_VNODE => {
+            Some(NOTE_DELETE | NOTE_WRITE | NOTE_EXTEND | NOTE_ATTRIB | NOTE_LINK | NOTE_RENAME |
+                 NOTE_REVOKE | platform_vnode_notes())
+        }
+        EventFilter::EVFILT_PROC => {
+            Some(NOTE_EXIT | NOTE_FORK | NOTE_EXEC | platform_proc_notes())
+        }
+        EventFilter::EVFILT_SIGNAL => Some(FilterFlag::empty()),
+        EventFilter::EVFILT_TIMER => Some(platform_timer_notes()),
+        #[cfg(target_os = "macos")]
+        EventFilter::EVFILT_VM => Some(NOTE_VM_PRESSURE),
+        #[cfg(target_os = "dragonfly")]
+        EventFilter::EVFILT_EXCEPT => Some(NOTE_OOB),
+        _ => None,
+    }
+}
+
+// Applies the filter's defaults to empty flags and rejects notes the filter
+// doesn't understand, which would otherwise make a watch silently never fire
+fn checked_flags(filter: EventFilter, flags: FilterFlag) -> Result<FilterFlag> {
+    if flags.is_empty() {
+        return Ok(default_flags(filter));
+    }
+
+    match valid_flags(filter) {
+        Some(valid) if !valid.contains(flags) => {
+            Err(Error::new(io::ErrorKind::InvalidInput,
+                           format!("flags {:?} are not valid for {:?}", flags - valid, filter)))
+        }
+        _ if filter == EventFilter::EVFILT_TIMER &&
+             (flags & timer_units()).bits().count_ones() > 1 => {
+            Err(Error::new(io::ErrorKind::InvalidInput,
+                           format!("timer units {:?} exclude each other", flags & timer_units())))
+        }
+        _ => Ok(flags),
+    }
+}
+
+#[cfg(target_os = "freebsd")]
+fn platform_rw_notes() -> FilterFlag {
+    NOTE_FILE_POLL
+}
+
+#[cfg(target_os = "openbsd")]
+fn platform_rw_notes() -> FilterFlag {
+    NOTE_EOF
+}
+
+#[cfg(target_os = "dragonfly")]
+fn platform_rw_notes() -> FilterFlag {
+    NOTE_OOB
+}
+
+#[cfg(not(any(target_os = "freebsd", target_os = "openbsd", target_os = "dragonfly")))]
+fn platform_rw_notes() -> FilterFlag {
+    FilterFlag::empty()
+}
+
+#[cfg(target_os = "freebsd")]
+fn platform_vnode_notes() -> FilterFlag {
+    NOTE_OPEN | NOTE_CLOSE | NOTE_CLOSE_WRITE | NOTE_READ
+}
+
+#[cfg(target_os = "openbsd")]
+fn platform_vnode_notes() -> FilterFlag {
+    NOTE_TRUNCATE
+}
+
+#[cfg(not(any(target_os = "freebsd", target_os = "openbsd")))]
+fn platform_vnode_notes() -> FilterFlag {
+    FilterFlag::empty()
+}
+
+// The vnode notes a truncation fires
+#[cfg(target_os = "openbsd")]
+fn truncate_notes() -> FilterFlag {
+    NOTE_TRUNCATE
+}
+
+#[cfg(not(target_os = "openbsd"))]
+fn truncate_notes() -> FilterFlag {
+    NOTE_ATTRIB
+}
+
+// macOS still defines NOTE_TRACK but has refused it for years
+#[cfg(target_os = "macos")]
+fn platform_proc_notes() -> FilterFlag {
+    NOTE_SIGNAL | NOTE_EXITSTATUS | NOTE_EXIT_DETAIL
+}
+
+#[cfg(any(target_os = "openbsd", target_os = "netbsd", target_os = "dragonfly"))]
+fn platform_proc_notes() -> FilterFlag {
+    NOTE_TRACK | NOTE_SIGNAL
+}
+
+#[cfg(target_os = "freebsd")]
+fn platform_proc_notes() -> FilterFlag {
+    NOTE_TRACK
+}
+
+// Extra notes for a NOTE_EXIT watch on `pid` that make the kernel report
+// how the process ended. macOS refuses NOTE_EXITSTATUS for anything but our
+// own children.
+#[cfg(target_os = "macos")]
+fn exit_notes(pid: pid_t) -> FilterFlag {
+    let mut info: libc::proc_bsdinfo = unsafe { std::mem::zeroed() };
+    let size = std::mem::size_of::<libc::proc_bsdinfo>() as libc::c_int;
+    let ret = unsafe {
+        libc::proc_pidinfo(pid,
+                           libc::PROC_PIDTBSDINFO,
+                           0,
+                           &mut info as *mut _ as *mut c_void,
+                           size)
+    };
+
+    if ret == size && info.pbi_ppid as pid_t == unsafe { libc::getpid() } {
+        NOTE_EXIT_DETAIL | NOTE_EXITSTATUS
+    } else {
+        NOTE_EXIT_DETAIL
+    }
+}
+
+#[cfg(not(target_os = "macos"))]
+fn exit_notes(_: pid_t) -> FilterFlag {
+    FilterFlag::empty()
+}
+
+// Decodes each of the process notes beyond exit, fork and exec that the
+// platform delivers, most specific first
+#[cfg(not(target_os = "macos"))]
+fn platform_proc_events(fflags: FilterFlag, data: i64) -> Vec<Proc> {
+    let mut notes = Vec::new();
+    if fflags.contains(NOTE_CHILD) {
+        notes.push(Proc::Child(data as libc::pid_t));
+    }
+    if fflags.contains(NOTE_TRACKERR) {
+        notes.push(Proc::Trackerr);
+    }
+    if fflags.contains(NOTE_TRACK) {
+        notes.push(Proc::Track(data as libc::pid_t));
+    }
+
+    notes
+}
+
+#[cfg(target_os = "macos")]
+fn platform_proc_events(_: FilterFlag, _: i64) -> Vec<Proc> {
+    Vec::new()
+}
+
+// Decodes the vnode notes only some platforms deliver
+#[cfg(target_os = "freebsd")]
+fn platform_vnode_event(ev: &kevent) -> Option<Vnode> {
+    if ev.fflags.contains(NOTE_CLOSE_WRITE) {
+        Some(Vnode::CloseWrite)
+    } else if ev.fflags.contains(NOTE_CLOSE) {
+        Some(Vnode::Close)
+    } else if ev.fflags.contains(NOTE_OPEN) {
+        Some(Vnode::Open)
+    } else if ev.fflags.contains(NOTE_READ) {
+        Some(Vnode::Read)
+    } else {
+        None
+    }
+}
+
+#[cfg(target_os = "openbsd")]
+fn platform_vnode_event(ev: &kevent) -> Option<Vnode> {
+    if ev.fflags.contains(NOTE_TRUNCATE) {
+        Some(Vnode::Truncate)
+    } else {
+        None
+    }
+}
+
+#[cfg(not(any(target_os = "freebsd", target_os = "openbsd")))]
+fn platform_vnode_event(_: &kevent) -> Option<Vnode> {
+    None
+}
+
+#[cfg(target_os = "freebsd")]
+fn platform_timer_notes() -> FilterFlag {
+    NOTE_SECONDS | NOTE_MSECONDS | NOTE_USECONDS | NOTE_NSECONDS
+}
+
+#[cfg(target_os = "macos")]
+fn platform_timer_notes() -> FilterFlag {
+    NOTE_SECONDS | NOTE_USECONDS | NOTE_NSECONDS | NOTE_ABSOLUTE | NOTE_LEEWAY | NOTE_CRITICAL |
+    NOTE_BACKGROUND
+}
+
+#[cfg(not(any(target_os = "freebsd", target_os = "macos")))]
+fn platform_timer_notes() -> FilterFlag {
+    FilterFlag::empty()
+}
+
+// The timer notes that pick the unit of the interval, at most one at a time
+#[cfg(target_os = "freebsd")]
+fn timer_units() -> FilterFlag {
+    NOTE_SECONDS | NOTE_MSECONDS | NOTE_USECONDS | NOTE_NSECONDS
+}
+
+#[cfg(target_os = "macos")]
+fn timer_units() -> FilterFlag {
+    NOTE_SECONDS | NOTE_USECONDS | NOTE_NSECONDS
+}
+
+#[cfg(not(any(target_os = "freebsd", target_os = "macos")))]
+fn timer_units() -> FilterFlag {
+    FilterFlag::empty()
+}
+
+// The timer unit flag and interval for `interval`, in the finest unit
+// available. Intervals are at least one unit, as not every platform accepts 0.
+#[cfg(any(target_os = "freebsd", target_os = "macos"))]
+fn timer_data(interval: Duration) -> (FilterFlag, i64) {
+    let nanos = interval.as_secs()
+        .saturating_mul(1_000_000_000)
+        .saturating_add(u64::from(interval.subsec_nanos()));
+    (NOTE_NSECONDS, cmp::max(1, cmp::min(nanos, i64::MAX as u64)) as i64)
+}
+
+#[cfg(not(any(target_os = "freebsd", target_os = "macos")))]
+fn timer_data(interval: Duration) -> (FilterFlag, i64) {
+    let millis = interval.as_secs()
+        .saturating_mul(1_000)
+        .saturating_add(u64::from(interval.subsec_millis()));
+    (FilterFlag::empty(), cmp::max(1, cmp::min(millis, i64::MAX as u64)) as i64)
+}
+
+// kqueue2-sys declares udata as a pointer, except on NetBSD where it is an
+// intptr_t
+#[cfg(not(target_os = "netbsd"))]
+fn to_udata(udata: usize) -> *mut c_void {
+    udata as *mut c_void
+}
+
+#[cfg(target_os = "netbsd")]
+fn to_udata(udata: usize) -> libc::intptr_t {
+    udata as libc::intptr_t
+}
+
+// Longer timeouts are clamped to about 68 years, the most every kernel (and
+// a 32-bit time_t) accepts, rather than wrapping into the past
+fn to_timespec(duration: Duration) -> timespec {
+    let secs = cmp::min(duration.as_secs(), i32::MAX as u64);
+    timespec {
+        tv_sec: secs as libc::time_t,
+        tv_nsec: duration.subsec_nanos() as libc::c_long,
+    }
+}
+
+// Fetches up to `max` raw kevents in one kevent(2) call
+fn read_kevents(watcher: &Watcher, max: usize, timeout: Option<Duration>) -> Result<Vec<kevent>> {
+    let mut kevs: Vec<kevent> = Vec::with_capacity(max);
+    let count = wait_kevents(watcher, kevs.as_mut_ptr(), max, timeout)?;
+    unsafe { kevs.set_len(count) };
+    Ok(kevs)
+}
+
+// Waits up to `timeout` for at most `max` kevents, written to `out`, which
+// must have room for them. Interrupted waits and transient failures are
+// handled as configured with `eintr_policy` and `retry_policy`.
+fn wait_kevents(watcher: &Watcher,
+                out: *mut kevent,
+                max: usize,
+                timeout: Option<Duration>)
+                -> Result<usize> {
+    let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+    let mut timeout = timeout;
+    let mut retries = 0;
+    let mut transient = 0;
+
+    loop {
+        let tspec = timeout.map(to_timespec);
+        let tspec_ptr = match tspec {
+            Some(ref ts) => ts as *const timespec,
+            None => ptr::null(),
+        };
+
+        let ret = unsafe { watcher.sys_kevent(&[], out, max, tspec_ptr) };
+
+        match watcher.record_syscall(ret) {
+            Ok(()) => return Ok(ret as usize),
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted &&
+                            watcher.opts.eintr.retries(retries) => {
+                retries += 1;
+            }
+            Err(ref err) if watcher.opts.retry.retries_after(err, transient) => {
+                transient += 1;
+                let backoff = watcher.opts.retry.backoff;
+                std::thread::sleep(deadline.map_or(backoff, |at| {
+                    cmp::min(backoff, at.saturating_duration_since(Instant::now()))
+                }));
+            }
+            Err(err) => return Err(err),
+        }
+
+        if let Some(deadline) = deadline {
+            timeout = Some(deadline.saturating_duration_since(Instant::now()));
+        }
+    }
+}
+
+// How many kevents `poll_into` reads per `kevent(2)` call, into a buffer on
+// the stack
+const POLL_CHUNK: usize = 64;
+
+// Reads up to `max` kevents without allocating, waiting up to `timeout` for
+// the first chunk, and appends the events they make to `events`. Returns the
+// number of kevents read.
+fn read_events_into(watcher: &Watcher,
+                    events: &mut Vec<Event>,
+                    max: usize,
+                    timeout: Option<Duration>)
+                    -> Result<usize> {
+    let mut chunk: [MaybeUninit<kevent>; POLL_CHUNK] =
+        unsafe { MaybeUninit::uninit().assume_init() };
+    let mut read = 0;
+    let mut wait = timeout;
+
+    while read < max {
+        let want = cmp::min(max - read, POLL_CHUNK);
+        let count = wait_kevents(watcher, chunk.as_mut_ptr() as *mut kevent, want, wait)?;
+        for kev in &chunk[..count] {
+            if let Some(ev) = deliver_kevent(watcher, unsafe { kev.assume_init_read() }) {
+                events.push(ev);
+            }
+        }
+
+        read += count;
+        if count < want {
+            break;
+        }
+        wait = Some(Duration::new(0, 0));
+    }
+
+    Ok(read)
+}
+
+// Removes the watches whose borrowed descriptors were closed by their owner,
+// returning an `Invalidated` event for each. Owned descriptors are only
+// closed by the watcher itself and aren't checked.
+fn prune_invalid(watcher: &Watcher) -> Vec<Event> {
+    let mut watched = watcher.watched.borrow_mut();
+    let mut events = Vec::new();
+
+    watched.retain(|w| {
+        let fd = match w.ident.as_fd() {
+            Some(fd) if !w.owned => fd,
+            _ => return true,
+        };
+
+        let closed = unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 &&
+                     Error::last_os_error().raw_os_error() == Some(libc::EBADF);
+        if closed && !events.iter().any(|ev: &Event| ev.ident.same_source(&w.ident)) {
+            events.push(Event {
+                ident: w.ident.clone(),
+                data: EventData::Invalidated,
+                filter: w.filter,
+                udata: w.udata,
+                notes: FilterFlag::empty(),
+                raw_data: 0,
+                last: true,
+            });
+        }
+
+        !closed
+    });
+
+    if !events.is_empty() && watched.is_empty() {
+        watcher.emptied.set(true);
+    }
+    drop(watched);
+    if !events.is_empty() {
+        watcher.forget_lines();
+    }
+    events
+}
+
+fn get_events(watcher: &Watcher, max: usize, timeout: Option<Duration>) -> Result<Vec<Event>> {
+    if let Some(ev) = take_emptied(watcher) {
+        return Ok(vec![ev]);
+    }
+
+    let mut events = collect_events(watcher, max, timeout)?;
+    if events.len() < max {
+        events.extend(take_emptied(watcher));
+    }
+    Ok(events)
+}
+
+fn collect_events(watcher: &Watcher,
+                  max: usize,
+                  timeout: Option<Duration>)
+                  -> Result<Vec<Event>> {
+    let owed = unacked_events(watcher);
+    if !owed.is_empty() {
+        // what arrived meanwhile comes first, without waiting for more
+        let mut events: Vec<Event> = read_kevents(watcher, max, Some(Duration::new(0, 0)))?
+            .into_iter()
+            .filter_map(|kev| deliver_kevent(watcher, kev))
+            .collect();
+        events.extend(owed);
+        return Ok(events);
+    }
+
+    let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+    let mut wait = timeout;
+
+    loop {
+        let kevs = read_kevents(watcher, max, wait)?;
+        if kevs.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let events: Vec<Event> = kevs.into_iter()
+            .filter_map(|kev| deliver_kevent(watcher, kev))
+            .collect();
+        if !events.is_empty() || watcher.emptied.get() {
+            return Ok(events);
+        }
+
+        // only internal bookkeeping arrived, keep waiting out the timeout
+        if let Some(deadline) = deadline {
+            wait = Some(deadline.saturating_duration_since(Instant::now()));
+        }
+    }
+}
+
+// The size of the file a vnode event is about, 0 when sizes are skipped or
+// it can't be stat()ed
+fn vnode_size(ev: &kevent, sizes: bool) -> u64 {
+    if !sizes {
+        return 0;
+    }
+
+    let mut st = MaybeUninit::<libc::stat>::uninit();
+    if unsafe { libc::fstat(ev.ident as RawFd, st.as_mut_ptr()) } == -1 {
+        return 0;
+    }
+    unsafe { st.assume_init() }.st_size as u64
+}
+
+// OS specific
+// TODO: Events can have more than one filter flag
+impl EventData {
+    fn from_kevent(ev: &kevent, sizes: bool) -> EventData {
+        match ev.filter {
+            EventFilter::EVFILT_READ => EventData::ReadReady(ev.data as usize),
+            // a socket's pending error (e.g. of a connect) comes with EOF
+            EventFilter::EVFILT_WRITE if ev.flags.contains(EV_EOF) && ev.fflags.bits() != 0 => {
+                EventData::Error(Error::from_raw_os_error(ev.fflags.bits() as i32))
+            }
+            EventFilter::EVFILT_WRITE => EventData::WriteReady(ev.data as usize),
+            EventFilter::EVFILT_SIGNAL => EventData::Signal(ev.data as usize),
+            EventFilter::EVFILT_TIMER => EventData::Timer(ev.data as usize),
+            EventFilter::EVFILT_PROC => {
+                let inner = if ev.fflags.contains(NOTE_EXIT) {
+                    Proc::Exit(ev.data as usize)
+                } else if ev.fflags.contains(NOTE_FORK) {
+                    Proc::Fork
+                } else if ev.fflags.contains(NOTE_EXEC) {
+                    Proc::Exec
+                } else {
+                    platform_proc_events(ev.fflags, ev.data)
+                        .into_iter()
+                        .next()
+                        .unwrap_or(Proc::Other(ev.fflags.bits()))
+                };
+
+                EventData::Proc(inner)
+            }
+            EventFilter::EVFILT_VNODE => {
+                let inner = if ev.fflags.contains(NOTE_DELETE) {
+                    Vnode::Delete
+                } else if ev.fflags.contains(NOTE_WRITE) {
+                    Vnode::Write { new_size: vnode_size(ev, sizes) }
+                } else if ev.fflags.contains(NOTE_EXTEND) {
+                    Vnode::Extend { new_size: vnode_size(ev, sizes) }
+                } else if ev.fflags.contains(NOTE_ATTRIB) {
+                    Vnode::Attrib
+                } else if ev.fflags.contains(NOTE_LINK) {
+                    Vnode::Link
+                } else if ev.fflags.contains(NOTE_RENAME) {
+                    Vnode::Rename
+                } else if ev.fflags.contains(NOTE_REVOKE) {
+                    Vnode::Revoke
+                } else {
+                    platform_vnode_event(ev).unwrap_or(Vnode::Other(ev.fflags.bits()))
+                };
+
+                EventData::Vnode(inner)
+            }
+            #[cfg(target_os = "macos")]
+            EventFilter::EVFILT_VM => {
+                let inner = if ev.fflags.contains(NOTE_VM_PRESSURE_SUDDEN_TERMINATE) {
+                    Vm::SuddenTerminate
+                } else if ev.fflags.contains(NOTE_VM_PRESSURE_TERMINATE) {
+                    Vm::Terminate
+                } else if ev.fflags.contains(NOTE_VM_ERROR) {
+                    Vm::Error
+                } else {
+                    Vm::Pressure
+                };
+
+                EventData::VmPressure(inner)
+            }
+            #[cfg(any(target_os = "freebsd", target_os = "macos", target_os = "dragonfly"))]
+            EventFilter::EVFILT_FS => EventData::Fs(Fs::from_bits(ev.fflags.bits())),
+            #[cfg(any(target_os = "freebsd", target_os = "dragonfly"))]
+            EventFilter::EVFILT_USER => EventData::User(1),
+            #[cfg(target_os = "dragonfly")]
+            EventFilter::EVFILT_EXCEPT => EventData::Except,
+            _ => EventData::Other { notes: ev.fflags.bits(), data: ev.data },
+        }
+    }
+}
+
+impl Proc {
+    /// Decodes the status of an `Exit`, `None` for other events.
+    ///
+    /// On macOS the status is only known for children of this process (see
+    /// `Watcher::add_pid`); for other processes only `detail` is meaningful
+    /// and the process shows up as having exited with code 0.
+    pub fn exit_info(&self) -> Option<ExitInfo> {
+        let data = match *self {
+            Proc::Exit(data) => data,
+            _ => return None,
+        };
+
+        // the wait(2) status takes the low 16 bits, macOS puts details above
+        let status = (data & 0xffff) as libc::c_int;
+        Some(ExitInfo {
+            code: if libc::WIFEXITED(status) { Some(libc::WEXITSTATUS(status)) } else { None },
+            signal: if libc::WIFSIGNALED(status) { Some(libc::WTERMSIG(status)) } else { None },
+            #[cfg(target_os = "macos")]
+            detail: exit_detail(data as u32),
+        })
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn exit_detail(data: u32) -> Option<ExitDetail> {
+    if data & NOTE_EXIT_DECRYPTFAIL != 0 {
+        Some(ExitDetail::DecryptFail)
+    } else if data & NOTE_EXIT_MEMORY != 0 {
+        Some(ExitDetail::Memory)
+    } else if data & NOTE_EXIT_CSERROR != 0 {
+        Some(ExitDetail::CodeSigning)
+    } else {
+        None
+    }
+}
+
+#[cfg(any(target_os = "freebsd", target_os = "macos", target_os = "dragonfly"))]
+impl Fs {
+    // fflags carry VQ_* bits, which kqueue2-sys has no constants for
+    fn from_bits(bits: u32) -> Fs {
+        if bits & VQ_MOUNT != 0 {
+            return Fs::Mount;
+        } else if bits & VQ_UNMOUNT != 0 {
+            return Fs::Unmount;
+        }
+
+        #[cfg(target_os = "macos")]
+        {
+            if bits & VQ_UPDATE != 0 {
+                return Fs::Update;
+            }
+        }
+
+        if bits & VQ_LOWDISK != 0 {
+            return Fs::LowDisk;
+        }
+
+        #[cfg(target_os = "macos")]
+        {
+            if bits & VQ_VERYLOWDISK != 0 {
+                return Fs::VeryLowDisk;
+            }
+        }
+
+        if bits & VQ_NOTRESP != 0 {
+            Fs::NotResponding
+        } else if bits & VQ_NEEDAUTH != 0 {
+            Fs::NeedAuth
+        } else if bits & VQ_DEAD != 0 {
+            Fs::Dead
+        } else if bits & VQ_ASSIST != 0 {
+            Fs::Assist
+        } else {
+            Fs::Other(bits)
+        }
+    }
+}
+
+impl Event {
+    pub fn new(ev: kevent, watcher: &Watcher) -> Event {
+        let data = EventData::from_kevent(&ev, watcher.opts.vnode_sizes);
+
+        let fallback = Ident::Fd(ev.ident as RawFd);
+        let ident = match ev.filter {
+            // a watch added through another handle onto the queue (see
+            // `Watcher::try_clone`) isn't in this one's set
+            EventFilter::EVFILT_READ |
+            EventFilter::EVFILT_WRITE |
+            EventFilter::EVFILT_VNODE => {
+                find_file_ident(watcher, ev.ident as RawFd).unwrap_or(fallback)
+            }
+            #[cfg(target_os = "dragonfly")]
+            EventFilter::EVFILT_EXCEPT => {
+                find_file_ident(watcher, ev.ident as RawFd).unwrap_or(fallback)
+            }
+            EventFilter::EVFILT_SIGNAL => Ident::Signal(ev.ident as i32),
+            EventFilter::EVFILT_TIMER => Ident::Timer(ev.ident as i32),
+            EventFilter::EVFILT_PROC => Ident::Pid(ev.ident as pid_t),
+            #[cfg(target_os = "macos")]
+            EventFilter::EVFILT_VM => Ident::Vm,
+            #[cfg(any(target_os = "freebsd", target_os = "dragonfly"))]
+            EventFilter::EVFILT_USER => Ident::User(ev.ident),
+            #[cfg(any(target_os = "freebsd", target_os = "macos", target_os = "dragonfly"))]
+            EventFilter::EVFILT_FS => Ident::Fs,
+            _ => fallback,
+        };
+
+        Event {
+            ident: ident,
+            data: data,
+            filter: ev.filter,
+            udata: ev.udata as usize,
+            notes: ev.fflags,
+            raw_data: ev.data,
+            last: ends_watch(watcher, &ev),
+        }
+    }
+
+    /// Builds an error event for `ev`; never panics.
+    ///
+    /// The error is the errno the kernel stored in an `EV_ERROR` kevent, or
+    /// the last OS error otherwise. Fds that are no longer watched and
+    /// filters this crate doesn't decode get an `Ident::Fd` carrying the raw
+    /// ident.
+    pub fn from_error(ev: kevent, watcher: &Watcher) -> Event {
+        let fallback = Ident::Fd(ev.ident as RawFd);
+        let ident = match ev.filter {
+            EventFilter::EVFILT_READ |
+            EventFilter::EVFILT_WRITE |
+            EventFilter::EVFILT_VNODE => {
+                find_file_ident(watcher, ev.ident as RawFd).unwrap_or(fallback)
+            }
+            #[cfg(target_os = "dragonfly")]
+            EventFilter::EVFILT_EXCEPT => {
+                find_file_ident(watcher, ev.ident as RawFd).unwrap_or(fallback)
+            }
+            EventFilter::EVFILT_SIGNAL => Ident::Signal(ev.ident as i32),
+            EventFilter::EVFILT_TIMER => Ident::Timer(ev.ident as i32),
+            EventFilter::EVFILT_PROC => Ident::Pid(ev.ident as pid_t),
+            #[cfg(target_os = "macos")]
+            EventFilter::EVFILT_VM => Ident::Vm,
+            #[cfg(any(target_os = "freebsd", target_os = "dragonfly"))]
+            EventFilter::EVFILT_USER => Ident::User(ev.ident),
+            #[cfg(any(target_os = "freebsd", target_os = "macos", target_os = "dragonfly"))]
+            EventFilter::EVFILT_FS => Ident::Fs,
+            _ => fallback,
+        };
+
+        let err = if ev.flags.contains(EV_ERROR) && ev.data != 0 {
+            Error::from_raw_os_error(ev.data as i32)
+        } else {
+            Error::last_os_error()
+        };
+
+        Event {
+            data: EventData::Error(err),
+            ident: ident,
+            filter: ev.filter,
+            udata: ev.udata as usize,
+            notes: FilterFlag::empty(),
+            raw_data: 0,
+            last: false,
+        }
+    }
+
+    /// Builds an event by hand, e.g. to feed event handlers in tests. It
+    /// carries no notes, token or kernel data and isn't final.
+    pub fn from_parts(ident: Ident, data: EventData, filter: EventFilter) -> Event {
+        Event {
+            ident: ident,
+            data: data,
+            filter: filter,
+            udata: 0,
+            notes: FilterFlag::empty(),
+            raw_data: 0,
+            last: false,
+        }
+    }
+
+    pub fn is_err(&self) -> bool {
+        match self.data {
+            EventData::Error(_) => true,
+            _ => false,
+        }
+    }
+
+    /// The filter that produced the event, also for errors and for
+    /// `Invalidated` events (the filter of the dropped watch).
+    pub fn filter(&self) -> EventFilter {
+        self.filter
+    }
+
+    /// How many times a timer expired since its last event, or `None` for
+    /// other events. Schedulers can subtract one to count the missed ticks.
+    pub fn timer_overrun(&self) -> Option<usize> {
+        match self.data {
+            EventData::Timer(count) => Some(count),
+            _ => None,
+        }
+    }
+
+    /// The outcome of a non-blocking `connect(2)` awaited with a write
+    /// watch on the socket: `Ok` once it is connected, the error it failed
+    /// with (e.g. `ConnectionRefused`) otherwise. `None` for events of other
+    /// filters.
+    pub fn connect_result(&self) -> Option<Result<()>> {
+        if self.filter != EventFilter::EVFILT_WRITE {
+            return None;
+        }
+
+        match self.data {
+            EventData::WriteReady(_) => Some(Ok(())),
+            EventData::Error(ref err) => Some(Err(copy_error(err))),
+            _ => None,
+        }
+    }
+
+    /// The pointer the watch was registered with through
+    /// `Watcher::add_fd_with_udata`, null for watches without one.
+    ///
+    /// Pointers and `WatchBuilder::token`s share the kernel's udata slot, so
+    /// for a watch with a token this is the token as a pointer, which must
+    /// not be dereferenced.
+    pub fn udata(&self) -> *mut c_void {
+        self.udata as *mut c_void
+    }
+
+    /// The token the watch was registered with through
+    /// `WatchBuilder::token`, 0 for watches without one. For a watch from
+    /// `Watcher::add_fd_with_udata` it is the address of the pointer, as
+    /// both share one slot (see `udata`).
+    pub fn token(&self) -> u64 {
+        self.udata as u64
+    }
+
+    /// Whether this is the last event of its watch: it came from a one-shot
+    /// watch, reports a process exit or is `Invalidated`, so nothing more
+    /// arrives for the ident. Unless it awaits an ack, the watch is gone
+    /// from `Watcher::watches` by the time the event is handed out.
+    pub fn is_final(&self) -> bool {
+        self.last
+    }
+
+    /// Every process note a `Proc` event reports, empty for other events.
+    ///
+    /// `EventData::Proc` holds only the most telling of them, but notes that
+    /// fire before the event is polled are delivered together, e.g. the
+    /// fork and the exit of a short-lived tracked process. They come in the
+    /// order exit, fork, exec, then the platform's tracking notes. The kernel
+    /// keeps a single data value per event, so with an exit among them
+    /// `Child` and `Track` carry the exit data rather than a pid.
+    pub fn proc_notes(&self) -> Vec<Proc> {
+        let exit = match self.data {
+            EventData::Proc(Proc::Exit(status)) => Some(status),
+            EventData::Proc(_) => None,
+            _ => return Vec::new(),
+        };
+
+        let mut notes = Vec::new();
+        if self.notes.contains(NOTE_EXIT) {
+            // a reaped child's status replaces the kernel's data
+            notes.push(Proc::Exit(exit.unwrap_or(self.raw_data as usize)));
+        }
+        if self.notes.contains(NOTE_FORK) {
+            notes.push(Proc::Fork);
+        }
+        if self.notes.contains(NOTE_EXEC) {
+            notes.push(Proc::Exec);
+        }
+        notes.extend(platform_proc_events(self.notes, self.raw_data));
+        if notes.is_empty() {
+            notes.push(Proc::Other(self.notes.bits()));
+        }
+
+        notes
+    }
+}
+
+impl<'a> EventRef<'a> {
+    /// See `Event::filter`.
+    pub fn filter(&self) -> EventFilter {
+        self.filter
+    }
+
+    /// See `Event::udata`.
+    pub fn udata(&self) -> *mut c_void {
+        self.udata as *mut c_void
+    }
+}
+
+impl<'a> Iterator for EventIter<'a> {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.watcher.started {
+            if self.reported {
+                return None;
+            }
+
+            self.reported = true;
+            return Some(Event {
+                ident: Ident::Fd(self.watcher.queue),
+                data: EventData::Error(Error::new(io::ErrorKind::InvalidInput,
+                                                  "iterating a watcher before watch()")),
+                filter: EventFilter::EVFILT_READ,
+                udata: 0,
+                notes: FilterFlag::empty(),
+                raw_data: 0,
+                last: false,
+            });
+        }
+
+        match get_event(self.watcher, None) {
+            Some(Event { data: EventData::Shutdown, .. }) |
+            Some(Event { data: EventData::WatchSetEmpty, .. }) => None,
+            ev => ev,
+        }
+    }
+}
+
+// How long `Tail` and `Counts` wait per round, since `next_matching` can't
+// block forever
+const MATCH_WAIT: Duration = Duration::from_secs(60);
+
+impl<'a> Tail<'a> {
+    /// The position up to which the file has been read.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Moves the read position, e.g. to 0 to read the file again from the
+    /// start. The next item is whatever lies past `offset` once the file
+    /// changes. A position past the end of the file counts as truncation and
+    /// is reset to 0.
+    pub fn set_offset(&mut self, offset: u64) {
+        self.offset = offset;
+    }
+
+    // Reads whatever was appended since `offset`, from the start again if
+    // the file shrank below it, as after a truncation
+    fn read_new(&mut self) -> Result<Vec<u8>> {
+        if self.file.metadata()?.len() < self.offset {
+            self.offset = 0;
+        }
+
+        self.file.seek(SeekFrom::Start(self.offset))?;
+        let mut buf = Vec::new();
+        self.file.read_to_end(&mut buf)?;
+        self.offset += buf.len() as u64;
+        Ok(buf)
+    }
+
+    fn is_ours(&self, ev: &Event) -> bool {
+        ev.filter == EventFilter::EVFILT_VNODE &&
+        match ev.ident {
+            Ident::Filename(_, ref path) => *path == self.path,
+            _ => false,
+        }
+    }
+}
+
+impl<'a> Iterator for Tail<'a> {
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.done {
+                return None;
+            }
+
+            let ev = match self.watcher.next_matching(|ev| self.is_ours(ev), Some(MATCH_WAIT)) {
+                Ok(Some(ev)) => ev,
+                Ok(None) => continue,
+                Err(err) => return Some(Err(err)),
+            };
+
+            match ev.data {
+                EventData::Vnode(Vnode::Replace) => {
+                    // drain the old file before following the new one
+                    let rest = self.read_new();
+                    match File::open(&self.path) {
+                        Ok(file) => self.file = file,
+                        Err(err) => return Some(Err(err)),
+                    }
+                    self.offset = 0;
+                    match rest {
+                        Ok(ref buf) if buf.is_empty() => (),
+                        rest => return Some(rest),
+                    }
+                }
+                EventData::Vnode(Vnode::Delete) |
+                EventData::Vnode(Vnode::Rename) => self.done = true,
+                EventData::Error(err) => return Some(Err(err)),
+                _ => (),
+            }
+
+            match self.read_new() {
+                Ok(ref buf) if buf.is_empty() => (),
+                read => return Some(read),
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for Counts<'a> {
+    type Item = Result<(i32, usize)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done || !self.watcher.started {
+            return None;
+        }
+
+        let filter = self.filter;
+        let wanted = |ev: &Event| {
+            ev.filter == filter ||
+            match ev.data {
+                EventData::Shutdown | EventData::WatchSetEmpty => true,
+                _ => false,
+            }
+        };
+        loop {
+            let ev = match self.watcher.next_matching(wanted, Some(MATCH_WAIT)) {
+                Ok(Some(ev)) => ev,
+                Ok(None) => continue,
+                Err(err) => return Some(Err(err)),
+            };
+
+            match (ev.ident, ev.data) {
+                (Ident::Timer(id), EventData::Timer(count)) |
+                (Ident::Signal(id), EventData::Signal(count)) => return Some(Ok((id, count))),
+                (_, EventData::Shutdown) | (_, EventData::WatchSetEmpty) => break,
+                (_, EventData::Error(err)) => return Some(Err(err)),
+                // the filter reports nothing else
+                _ => (),
+            }
+        }
+
+        self.done = true;
+        None
+    }
+}
+
+/// Helpers for exercising watchers in tests, enabled by the `testutil`
+/// feature.
+#[cfg(any(test, feature = "testutil"))]
+pub mod testutil {
+    use std::io::Result;
+    use std::os::unix::net::UnixStream;
+
+    /// A connected pair of non-blocking Unix stream sockets for driving
+    /// `EVFILT_READ` and `EVFILT_WRITE` watches: writing to one end makes
+    /// the other readable, dropping it makes the other see end-of-file.
+    pub fn socketpair() -> Result<(UnixStream, UnixStream)> {
+        let (left, right) = UnixStream::pair()?;
+        left.set_nonblocking(true)?;
+        right.set_nonblocking(true)?;
+        Ok((left, right))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libc::c_void;
+    use std::borrow::Cow;
+    use std::cmp;
+    use std::env;
+    use std::ffi::CStr;
+    use std::fs;
+    use std::io::{self, Read, Write};
+    use std::ops::ControlFlow;
+    use std::panic;
+    use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd};
+    use std::os::unix::net::UnixStream;
+    #[cfg(any(target_os = "freebsd", target_os = "macos"))]
+    use std::os::unix::process::CommandExt;
+    use std::process::Command;
+    use std::ptr;
+    use std::time::{Duration, Instant, SystemTime};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use super::{Watcher, SharedWatcher, Metrics, EintrPolicy, RetryPolicy, Event, EventFilter,
+                EventData, EventFlag, FilterFlag, NOTE_DELETE, NOTE_WRITE, EV_ERROR, Vnode, Ident,
+                IdentRef, Proc, NOTE_EXIT, NOTE_EXTEND, NOTE_EXEC, NOTE_TRACK, NOTE_LOWAT, EV_ADD,
+                EV_CLEAR, EV_DELETE, EV_DISABLE, EV_ONESHOT, default_flags, drain_fully, kevent,
+                owned_fds, to_udata, wait_readable, GroupKey, QueueCreationError};
+    use super::testutil::socketpair;
+    #[cfg(target_os = "freebsd")]
+    use super::{NOTE_CLOSE, NOTE_OPEN};
+    #[cfg(not(target_os = "macos"))]
+    use super::NOTE_FORK;
+    #[cfg(any(target_os = "freebsd", target_os = "macos"))]
+    use super::{NOTE_NSECONDS, NOTE_SECONDS};
+    #[cfg(any(target_os = "freebsd", target_os = "macos", target_os = "dragonfly"))]
+    use super::{Fs, VQ_DEAD, VQ_LOWDISK, VQ_MOUNT};
+
+    // Whether this process is the copy of the test binary `run_in_child`
+    // started for `test`
+    fn is_child(test: &str) -> bool {
+        env::var("KQUEUE_CHILD_TEST").is_ok_and(|name| name == test)
+    }
+
+    // A fresh copy of the test binary that runs just `test`, for tests that
+    // change process-wide state or check what a child inherits; fork() is no
+    // option in the threaded harness
+    fn child_test(test: &str) -> Command {
+        let mut child = Command::new(env::current_exe().unwrap());
+        child.args(["--exact", &format!("tests::{}", test), "--test-threads=1"])
+            .env("KQUEUE_CHILD_TEST", test);
+        child
+    }
+
+    // Whether the child ran its test and it passed
+    fn child_passed(child: &mut Command) -> bool {
+        let output = child.output().unwrap();
+        // a filter matching nothing would pass as well
+        output.status.success() && String::from_utf8_lossy(&output.stdout).contains("1 passed")
+    }
+
+    fn run_in_child(test: &str) -> bool {
+        child_passed(&mut child_test(test))
+    }
+
+    #[test]
+    fn test_new_watcher() {
+        let mut watcher = Watcher::new().unwrap();
+        let file = fs::File::create("testing.txt").unwrap();
+
+        assert!(watcher.add_file(&file, EventFilter::EVFILT_VNODE, NOTE_WRITE).is_ok(),
+                "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+    }
+
+    #[test]
+    fn test_filename() {
+        let filename = "/tmp/testing.txt";
+        let mut watcher = match Watcher::new() {
+            Ok(wat) => wat,
+            Err(_) => panic!("new failed"),
+        };
+
+        {
+            assert!(fs::File::create(filename).is_ok(), "file creation failed");
+        };
+
+        assert!(watcher.add_filename(filename, EventFilter::EVFILT_VNODE, NOTE_WRITE).is_ok(),
+                "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+
+        let mut new_file = match fs::OpenOptions::new().write(true).open(filename) {
+            Ok(fil) => fil,
+            Err(_) => panic!("open failed"),
+        };
+
+        assert!(new_file.write_all(b"foo").is_ok(), "write failed");
+        let ev = watcher.iter().next().unwrap();
+        match ev.data {
+            EventData::Vnode(Vnode::Write { .. }) => assert!(true),
+            _ => assert!(false),
+        };
+
+        match ev.ident {
+            Ident::Filename(_, name) => assert!(name == filename),
+            _ => assert!(false),
+        };
+    }
+
+    #[test]
+    fn test_file() {
+        let filename = "/tmp/testing.txt";
+        let mut watcher = match Watcher::new() {
+            Ok(wat) => wat,
+            Err(_) => panic!("new failed"),
+        };
+
+        let file_res = fs::File::create(filename);
+        assert!(file_res.is_ok(), "file creation failed");
+        let mut file = file_res.unwrap();
+
+        assert!(watcher.add_file(&file, EventFilter::EVFILT_VNODE, NOTE_WRITE).is_ok(),
+                "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+        assert!(file.write_all(b"foo").is_ok(), "write failed");
+        let ev = watcher.iter().next().unwrap();
+        match ev.data {
+            EventData::Vnode(Vnode::Write { .. }) => assert!(true),
+            _ => assert!(false),
+        };
+
+        match ev.ident {
+            Ident::Fd(_) => assert!(true),
+            _ => assert!(false),
+        };
+    }
+
+    #[test]
+    fn test_delete_filename() {
+        let filename = "/tmp/testing.txt";
+        let mut watcher = match Watcher::new() {
+            Ok(wat) => wat,
+            Err(_) => panic!("new failed"),
+        };
+
+        {
+            assert!(fs::File::create(filename).is_ok(), "file creation failed");
+        };
+
+        assert!(watcher.add_filename(filename, EventFilter::EVFILT_VNODE, NOTE_WRITE).is_ok(),
+                "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+        assert!(watcher.remove_filename(filename, EventFilter::EVFILT_VNODE).is_ok(),
+                "delete failed");
+    }
+
+    #[test]
+    fn test_dupe() {
+        let filename = "/tmp/testing.txt";
+        let mut watcher = match Watcher::new() {
+            Ok(wat) => wat,
+            Err(_) => panic!("new failed"),
+        };
+
+        {
+            assert!(fs::File::create(filename).is_ok(), "file creation failed");
+        };
+
+        assert!(watcher.add_filename(filename, EventFilter::EVFILT_VNODE, NOTE_WRITE).is_ok(),
+                "add failed");
+        assert!(watcher.add_filename(filename, EventFilter::EVFILT_VNODE, NOTE_WRITE).is_ok(),
+                "second add failed");
+
+        assert!(watcher.watched.borrow().len() == 1);
+    }
+
+    #[test]
+    fn test_poll_up_to() {
+        let mut watcher = Watcher::new().unwrap();
+        let mut writers = Vec::new();
+
+        for _ in 0..4 {
+            let (mut tx, rx) = UnixStream::pair().unwrap();
+            assert!(tx.write_all(b"foo").is_ok(), "write failed");
+            assert!(watcher.add_fd(rx.into_raw_fd(), EventFilter::EVFILT_READ, FilterFlag::empty())
+                        .is_ok(),
+                    "add failed");
+            writers.push(tx);
+        }
+
+        assert!(watcher.watch().is_ok(), "watch failed");
+
+        let (events, more) = watcher.poll_up_to(3, None).unwrap();
+        assert_eq!(events.len(), 3);
+        assert!(more, "buffer should report more pending");
+
+        let (events, more) = watcher.poll_up_to(3, None).unwrap();
+        assert_eq!(events.len(), 1);
+        assert!(!more, "queue should be drained");
+    }
+
+    #[test]
+    fn test_clone_shares_queue() {
+        let filename = "/tmp/testing_clone.txt";
+        let mut watcher = Watcher::new().unwrap();
+
+        {
+            assert!(fs::File::create(filename).is_ok(), "file creation failed");
+        };
+
+        assert!(watcher.add_filename(filename, EventFilter::EVFILT_VNODE, NOTE_WRITE).is_ok(),
+                "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+
+        let cloned = watcher.clone();
+        assert!(cloned.queue != watcher.queue, "clone should own its own queue fd");
+
+        let mut new_file = fs::OpenOptions::new().write(true).open(filename).unwrap();
+        assert!(new_file.write_all(b"foo").is_ok(), "write failed");
+
+        let ev = cloned.iter().next().unwrap();
+        match ev.data {
+            EventData::Vnode(Vnode::Write { .. }) => (),
+            _ => panic!("unexpected event: {:?}", ev),
+        };
+        assert!(watcher.poll(None).is_none(), "event should be consumed from the shared queue");
+
+        drop(cloned);
+        assert!(watcher.watch().is_ok(), "original queue closed by clone");
+    }
+
+    #[test]
+    fn test_persistent_path_survives_replace() {
+        let filename = "/tmp/testing_persistent.txt";
+        let tmpname = "/tmp/testing_persistent.txt.tmp";
+        let mut watcher = Watcher::new().unwrap();
+
+        {
+            assert!(fs::File::create(filename).is_ok(), "file creation failed");
+        };
+
+        assert!(watcher.watch_path_persistent(filename, NOTE_WRITE).is_ok(), "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+
+        {
+            assert!(fs::File::create(tmpname).is_ok(), "temp file creation failed");
+        };
+        assert!(fs::rename(tmpname, filename).is_ok(), "rename failed");
+
+        let ev = watcher.poll(Some(Duration::from_secs(1))).unwrap();
+        match ev.data {
+            EventData::Vnode(Vnode::Replace) => (),
+            _ => panic!("unexpected event: {:?}", ev),
+        };
+
+        // the watch now follows the new file
+        let mut new_file = fs::OpenOptions::new().write(true).open(filename).unwrap();
+        assert!(new_file.write_all(b"foo").is_ok(), "write failed");
+        let ev = watcher.poll(Some(Duration::from_secs(1))).unwrap();
+        match ev.data {
+            EventData::Vnode(Vnode::Write { .. }) => (),
+            _ => panic!("unexpected event: {:?}", ev),
+        };
+
+        match ev.ident {
+            Ident::Filename(_, name) => assert!(name == filename),
+            _ => panic!("unexpected ident: {:?}", ev.ident),
+        };
+    }
+
+    #[test]
+    fn test_is_owned() {
+        let filename = "/tmp/testing_owned.txt";
+        let mut watcher = Watcher::new().unwrap();
+        let file = fs::File::create(filename).unwrap();
+        let (_tx, rx) = UnixStream::pair().unwrap();
+
+        assert!(watcher.add_filename(filename, EventFilter::EVFILT_VNODE, NOTE_WRITE).is_ok(),
+                "add failed");
+        assert!(watcher.add_file(&file, EventFilter::EVFILT_VNODE, NOTE_WRITE).is_ok(),
+                "add failed");
+        assert!(watcher.add_fd(rx.into_raw_fd(), EventFilter::EVFILT_READ, FilterFlag::empty())
+                    .is_ok(),
+                "add failed");
+
+        assert_eq!(watcher.is_owned(&Ident::Filename(0, filename.to_string())),
+                   Some(true));
+        assert_eq!(watcher.is_owned(&Ident::Fd(file.as_raw_fd())), Some(false));
+        assert_eq!(watcher.is_owned(&Ident::Pid(-1)), None);
+        assert_eq!(watcher.clone().is_owned(&Ident::Filename(0, filename.to_string())),
+                   Some(false));
+    }
+
+    #[test]
+    fn test_wait_until_systime() {
+        let mut watcher = Watcher::new().unwrap();
+        let (_tx, rx) = UnixStream::pair().unwrap();
+
+        assert!(watcher.add_fd(rx.into_raw_fd(), EventFilter::EVFILT_READ, FilterFlag::empty())
+                    .is_ok(),
+                "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+
+        let start = Instant::now();
+        let past = SystemTime::now() - Duration::from_secs(60);
+        assert!(watcher.wait_until_systime(past).unwrap().is_none());
+        assert!(start.elapsed() < Duration::from_millis(50), "past deadline should not block");
+
+        let start = Instant::now();
+        let soon = SystemTime::now() + Duration::from_millis(100);
+        assert!(watcher.wait_until_systime(soon).unwrap().is_none());
+        assert!(start.elapsed() >= Duration::from_millis(90), "returned before the deadline");
+    }
+
+    #[test]
+    fn test_remove_on_delete() {
+        let filename = "/tmp/testing_remove_on_delete.txt";
+        let mut watcher = Watcher::new().unwrap();
+        watcher.remove_on_delete();
+
+        {
+            assert!(fs::File::create(filename).is_ok(), "file creation failed");
+        };
+
+        assert!(watcher.add_filename(filename, EventFilter::EVFILT_VNODE, NOTE_DELETE).is_ok(),
+                "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+        assert!(fs::remove_file(filename).is_ok(), "delete failed");
+
+        let ev = watcher.poll(Some(Duration::from_secs(1))).unwrap();
+        match ev.data {
+            EventData::Vnode(Vnode::Delete) => (),
+            _ => panic!("unexpected event: {:?}", ev),
+        };
+        assert!(watcher.watched.borrow().is_empty(), "deleted watch was kept");
+    }
+
+    #[test]
+    fn test_poll_ref() {
+        let filename = "/tmp/testing_poll_ref.txt";
+        let mut watcher = Watcher::new().unwrap();
+
+        {
+            assert!(fs::File::create(filename).is_ok(), "file creation failed");
+        };
+
+        assert!(watcher.add_filename(filename, EventFilter::EVFILT_VNODE, NOTE_WRITE).is_ok(),
+                "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+
+        let mut new_file = fs::OpenOptions::new().write(true).open(filename).unwrap();
+        assert!(new_file.write_all(b"foo").is_ok(), "write failed");
+
+        let ev = watcher.poll_ref(Some(Duration::from_secs(1))).unwrap().unwrap();
+        match ev.data {
+            EventData::Vnode(Vnode::Write { .. }) => (),
+            _ => panic!("unexpected event: {:?}", ev),
+        };
+
+        match ev.ident {
+            IdentRef::Filename(_, Cow::Borrowed(name)) => assert!(name == filename),
+            _ => panic!("unexpected ident: {:?}", ev.ident),
+        };
+    }
+
+    #[test]
+    fn test_add_child_reaps() {
+        let mut watcher = Watcher::new().unwrap();
+        let mut child = Command::new("sh").arg("-c").arg("sleep 1; exit 3").spawn().unwrap();
+
+        assert!(watcher.add_child(child.id() as libc::pid_t).is_ok(), "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+
+        let ev = watcher.poll(Some(Duration::from_secs(5))).unwrap();
+        match ev.data {
+            EventData::Proc(Proc::Exit(status)) => {
+                assert!(libc::WIFEXITED(status as libc::c_int));
+                assert_eq!(libc::WEXITSTATUS(status as libc::c_int), 3);
+            }
+            _ => panic!("unexpected event: {:?}", ev),
+        };
+
+        match ev.ident {
+            Ident::Pid(pid) => assert_eq!(pid, child.id() as libc::pid_t),
+            _ => panic!("unexpected ident: {:?}", ev.ident),
+        };
+        assert!(child.wait().is_err(), "child was not reaped");
+    }
+
+    #[test]
+    fn test_reapply_clears() {
+        let mut watcher = Watcher::new().unwrap();
+        let (mut tx, rx) = UnixStream::pair().unwrap();
+
+        assert!(watcher.add_fd(rx.into_raw_fd(), EventFilter::EVFILT_READ, FilterFlag::empty())
+                    .is_ok(),
+                "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+        assert!(tx.write_all(b"foo").is_ok(), "write failed");
+
+        // edge-triggered: unread data is only reported once
+        assert!(watcher.poll(Some(Duration::from_secs(1))).is_some());
+        assert!(watcher.poll(None).is_none());
+
+        watcher.disable_clears();
+        assert!(watcher.reapply().is_ok(), "reapply failed");
+
+        // level-triggered: unread data keeps being reported
+        assert!(watcher.poll(None).is_some());
+        assert!(watcher.poll(None).is_some());
+    }
+
+    #[test]
+    fn test_timer_deadline() {
+        let mut watcher = Watcher::new().unwrap();
+        let at = Instant::now() + Duration::from_millis(100);
+
+        assert!(watcher.add_timer_deadline(1, at).is_ok(), "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+
+        let ev = watcher.poll(Some(Duration::from_secs(1))).unwrap();
+        let fired = Instant::now();
+        match ev.data {
+            EventData::Timer(_) => (),
+            _ => panic!("unexpected event: {:?}", ev),
+        };
+
+        assert!(fired >= at - Duration::from_millis(5), "timer fired early");
+        assert!(fired <= at + Duration::from_millis(20), "timer fired late");
+    }
+
+    #[test]
+    fn test_watch_keys() {
+        let mut watcher = Watcher::new().unwrap();
+        let mut other = Watcher::new().unwrap();
+        let (mut tx, rx) = UnixStream::pair().unwrap();
+
+        let key = watcher.add_fd(rx.into_raw_fd(), EventFilter::EVFILT_READ, FilterFlag::empty())
+            .unwrap();
+        assert!(watcher.watch().is_ok(), "watch failed");
+        assert!(other.disable(key).is_err(), "foreign key accepted");
+
+        assert!(watcher.disable(key).is_ok(), "disable failed");
+        assert!(tx.write_all(b"foo").is_ok(), "write failed");
+        assert!(watcher.poll(Some(Duration::from_millis(100))).is_none());
+
+        assert!(watcher.enable(key).is_ok(), "enable failed");
+        assert!(watcher.poll(Some(Duration::from_secs(1))).is_some());
+    }
+
+    #[test]
+    fn test_poll_all_refills_small_buffer() {
+        // 200 pipes need more descriptors than some default soft limits, and
+        // raising the limit for every test would hide descriptor leaks
+        if !is_child("test_poll_all_refills_small_buffer") {
+            assert!(run_in_child("test_poll_all_refills_small_buffer"), "events lost");
+            return;
+        }
+
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) };
+        limit.rlim_cur = cmp::max(limit.rlim_cur, cmp::min(limit.rlim_max, 1024));
+        unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) };
+
+        let mut watcher = Watcher::new().unwrap();
+        watcher.batch_size(64);
+        let mut writers = Vec::new();
+        let mut readers = Vec::new();
+
+        for _ in 0..200 {
+            let (tx, rx) = UnixStream::pair().unwrap();
+            let fd = rx.into_raw_fd();
+            assert!(watcher.add_fd(fd, EventFilter::EVFILT_READ, FilterFlag::empty()).is_ok(),
+                    "add failed");
+            writers.push(tx);
+            readers.push(fd);
+        }
+
+        assert!(watcher.watch().is_ok(), "watch failed");
+        for tx in &mut writers {
+            assert!(tx.write_all(b"foo").is_ok(), "write failed");
+        }
+
+        let events = watcher.poll_all(Some(Duration::from_secs(1))).unwrap();
+        let delivered: Vec<_> = events.iter()
+            .map(|ev| match ev.ident {
+                Ident::Fd(fd) => fd,
+                _ => panic!("unexpected ident: {:?}", ev.ident),
+            })
+            .collect();
+        assert_eq!(delivered, readers);
+    }
+
+    #[test]
+    fn test_poll_failure_event() {
+        let watcher = Watcher::new().unwrap();
+        // a descriptor that isn't a kqueue fails every kevent(2) call
+        let file = fs::File::open("/dev/null").unwrap();
+        assert!(unsafe { libc::dup2(file.as_raw_fd(), watcher.queue) } != -1);
+
+        match watcher.poll(None) {
+            Some(Event { ident: Ident::Fd(fd), data: EventData::Error(err), .. }) => {
+                assert_eq!(fd, watcher.queue);
+                assert!(err.raw_os_error().is_some(), "not the kernel's error: {:?}", err);
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_flags_validated() {
+        let filename = "/tmp/testing_flags.txt";
+        let mut watcher = Watcher::new().unwrap();
+
+        {
+            assert!(fs::File::create(filename).is_ok(), "file creation failed");
+        };
+
+        assert!(watcher.add_filename(filename, EventFilter::EVFILT_VNODE, NOTE_EXIT).is_err(),
+                "proc note accepted for a vnode watch");
+        assert!(watcher.add_pid(1, EventFilter::EVFILT_READ, NOTE_EXTEND).is_err(),
+                "vnode note accepted for a read watch");
+        // FreeBSD's NOTE_FILE_POLL and DragonFly's NOTE_OOB have its value
+        #[cfg(not(any(target_os = "freebsd", target_os = "dragonfly")))]
+        assert!(watcher.add_pid(1, EventFilter::EVFILT_READ, NOTE_WRITE).is_err(),
+                "vnode note accepted for a read watch");
+        #[cfg(any(target_os = "freebsd", target_os = "macos"))]
+        assert!(watcher.add_pid(1, EventFilter::EVFILT_TIMER, NOTE_SECONDS | NOTE_NSECONDS)
+                    .is_err(),
+                "two timer units accepted");
+        assert!(watcher.watched.borrow().is_empty());
+
+        assert!(watcher.add_filename(filename, EventFilter::EVFILT_VNODE, FilterFlag::empty())
+                    .is_ok(),
+                "add failed");
+        assert_eq!(watcher.watched.borrow()[0].flags,
+                   default_flags(EventFilter::EVFILT_VNODE));
+    }
+
+    #[test]
+    fn test_config_round_trip() {
+        let filename = "/tmp/testing_config.txt";
+        let mut watcher = Watcher::new().unwrap();
+        let (_tx, rx) = UnixStream::pair().unwrap();
+
+        {
+            assert!(fs::File::create(filename).is_ok(), "file creation failed");
+        };
+
+        assert!(watcher.add_filename(filename, EventFilter::EVFILT_VNODE, NOTE_WRITE).is_ok(),
+                "add failed");
+        assert!(watcher.add_fd(rx.into_raw_fd(), EventFilter::EVFILT_READ, FilterFlag::empty())
+                    .is_ok(),
+                "add failed");
+        assert!(watcher.add_pid(1, EventFilter::EVFILT_PROC, NOTE_EXIT).is_ok(), "add failed");
+
+        let cfg = watcher.export_config();
+        let mut restored = Watcher::new().unwrap();
+        assert!(restored.import_config(cfg.clone()).is_ok(), "import failed");
+        assert!(restored.watch().is_ok(), "watch failed");
+        assert_eq!(restored.export_config(), cfg);
+    }
+
+    #[test]
+    fn test_watch_path_create() {
+        let filename = "/tmp/testing_create.txt";
+        let _ = fs::remove_file(filename);
+        let mut watcher = Watcher::new().unwrap();
+
+        assert!(watcher.watch_path_create("/tmp/no/such/dir/testing.txt", NOTE_WRITE).is_err(),
+                "missing parent accepted");
+        assert!(watcher.watch_path_create(filename, NOTE_WRITE).is_ok(), "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+        assert!(watcher.poll(None).is_none(), "event before the file exists");
+
+        let mut file = fs::File::create(filename).unwrap();
+        let ev = watcher.poll(Some(Duration::from_secs(1))).unwrap();
+        match ev.data {
+            EventData::Vnode(Vnode::Create) => (),
+            _ => panic!("unexpected event: {:?}", ev),
+        };
+
+        match ev.ident {
+            Ident::Filename(_, ref name) => assert!(name == filename),
+            _ => panic!("unexpected ident: {:?}", ev.ident),
+        };
+
+        assert!(file.write_all(b"foo").is_ok(), "write failed");
+        let ev = watcher.poll(Some(Duration::from_secs(1))).unwrap();
+        match ev.data {
+            EventData::Vnode(Vnode::Write { .. }) => (),
+            _ => panic!("unexpected event: {:?}", ev),
+        };
+    }
+
+    #[test]
+    fn test_closed_fd_invalidated() {
+        let filename = "/tmp/testing_invalidated.txt";
+        let file = fs::File::create(filename).unwrap();
+        let mut watcher = Watcher::new().unwrap();
+
+        assert!(watcher.add_file(&file, EventFilter::EVFILT_VNODE, NOTE_WRITE).is_ok(),
+                "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+
+        let fd = file.as_raw_fd();
+        drop(file);
+
+        // polling alone doesn't check descriptors
+        assert!(watcher.poll(None).is_none(), "checked while polling");
+        assert_eq!(watcher.prune(), 1);
+        let ev = watcher.poll(None).unwrap();
+        match ev.data {
+            EventData::Invalidated => (),
+            _ => panic!("unexpected event: {:?}", ev),
+        };
+
+        assert!(ev.ident == Ident::Fd(fd));
+        assert!(watcher.is_owned(&Ident::Fd(fd)).is_none(), "stale watch kept");
+        assert!(watcher.poll(None).is_none(), "invalidated twice");
+        assert_eq!(watcher.prune(), 0);
+
+        // a descriptor closed before registering is reported by watch()
+        let file = fs::File::open(filename).unwrap();
+        assert!(watcher.add_file(&file, EventFilter::EVFILT_VNODE, NOTE_WRITE).is_ok(),
+                "add failed");
+        let fd = file.as_raw_fd();
+        drop(file);
+        assert!(watcher.watch().is_ok(), "closed fd failed watch");
+        match watcher.poll(None) {
+            Some(Event { ident: Ident::Fd(ev_fd), data: EventData::Invalidated, .. })
+                if ev_fd == fd => (),
+            other => panic!("unexpected event: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_from_error_removed_watch() {
+        let filename = "/tmp/testing_from_error.txt";
+        let file = fs::File::create(filename).unwrap();
+        let fd = file.as_raw_fd();
+        let mut watcher = Watcher::new().unwrap();
+
+        assert!(watcher.add_file(&file, EventFilter::EVFILT_VNODE, NOTE_WRITE).is_ok(),
+                "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+        assert!(watcher.remove_file(&file, EventFilter::EVFILT_VNODE).is_ok(),
+                "remove failed");
+
+        let kev = kevent {
+            ident: fd as usize,
+            filter: EventFilter::EVFILT_VNODE,
+            flags: EV_ERROR,
+            fflags: FilterFlag::empty(),
+            data: libc::ENOENT as i64,
+            udata: to_udata(0),
+        };
+        let ev = Event::from_error(kev, &watcher);
+        assert!(ev.ident == Ident::Fd(fd));
+        match ev.data {
+            EventData::Error(ref err) => assert!(err.raw_os_error() == Some(libc::ENOENT)),
+            _ => panic!("unexpected event: {:?}", ev),
+        };
+
+        let kev = kevent {
+            ident: 0,
+            filter: EventFilter::EVFILT_SYSCOUNT,
+            flags: EventFlag::empty(),
+            fflags: FilterFlag::empty(),
+            data: 0,
+            udata: to_udata(0),
+        };
+        assert!(Event::from_error(kev, &watcher).is_err());
+    }
+
+    #[cfg(target_os = "macos")]
+    #[test]
+    fn test_add_vm_pressure() {
+        let mut watcher = Watcher::new().unwrap();
+
+        let key = watcher.add_vm_pressure().unwrap();
+        assert!(watcher.add_vm_pressure().unwrap() == key, "duplicate vm watch");
+        assert!(watcher.watch().is_ok(), "watch failed");
+        assert!(watcher.is_owned(&Ident::Vm) == Some(false));
+    }
+
+    #[cfg(any(target_os = "freebsd", target_os = "macos", target_os = "dragonfly"))]
+    #[test]
+    fn test_add_fs() {
+        let mut watcher = Watcher::new().unwrap();
+
+        assert!(watcher.add_fs(FilterFlag::empty()).is_ok(), "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+        assert!(watcher.export_config()[0].0 == Ident::Fs);
+    }
+
+    #[cfg(any(target_os = "freebsd", target_os = "macos", target_os = "dragonfly"))]
+    #[test]
+    fn test_fs_event_decoded() {
+        let decode = |bits| {
+            let kev = kevent {
+                ident: 0,
+                filter: EventFilter::EVFILT_FS,
+                flags: EventFlag::empty(),
+                fflags: FilterFlag::from_bits_truncate(bits),
+                data: 0,
+                udata: to_udata(0),
+            };
+            match EventData::from_kevent(&kev, false) {
+                EventData::Fs(fs) => fs,
+                data => panic!("unexpected event: {:?}", data),
+            }
+        };
+
+        match decode(VQ_MOUNT) {
+            Fs::Mount => (),
+            fs => panic!("unexpected condition: {:?}", fs),
+        };
+        // the first condition in declaration order wins
+        match decode(VQ_LOWDISK | VQ_DEAD) {
+            Fs::LowDisk => (),
+            fs => panic!("unexpected condition: {:?}", fs),
+        };
+        // a bit no platform defines a VQ_* condition for
+        match decode(0x8000_0000) {
+            Fs::Other(0x8000_0000) => (),
+            fs => panic!("unexpected condition: {:?}", fs),
+        };
+    }
+
+    #[test]
+    fn test_reconcile() {
+        let entry = |sig| (Ident::Signal(sig), EventFilter::EVFILT_SIGNAL, FilterFlag::empty());
+        let mut watcher = Watcher::new().unwrap();
+
+        assert!(watcher.import_config(vec![entry(libc::SIGUSR1), entry(libc::SIGUSR2)]).is_ok(),
+                "import failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+        let kept = watcher.export_config()[1].clone();
+
+        let desired = vec![entry(libc::SIGUSR2), entry(libc::SIGHUP)];
+        let (deletes, adds, updates) = watcher.plan_reconcile(&desired);
+        assert_eq!(deletes, vec![0]);
+        assert_eq!(adds, vec![1]);
+        assert!(updates.is_empty(), "unexpected updates: {:?}", updates);
+
+        assert!(watcher.reconcile(desired).is_ok(), "reconcile failed");
+        let cfg = watcher.export_config();
+        assert_eq!(cfg.len(), 2);
+        assert!(cfg[0] == kept);
+        assert!(cfg[1].0 == Ident::Signal(libc::SIGHUP));
+    }
+
+    #[test]
+    fn test_thread_bounds() {
+        fn is_send<T: Send>() {}
+        fn is_sync<T: Sync>() {}
+
+        is_send::<Watcher>();
+        is_send::<SharedWatcher>();
+        is_sync::<SharedWatcher>();
+        is_send::<Arc<SharedWatcher>>();
+    }
+
+    #[test]
+    fn test_shared_watcher() {
+        let (mut tx, rx) = UnixStream::pair().unwrap();
+        let shared = Arc::new(SharedWatcher::new(Watcher::new().unwrap()));
+        assert!(shared.lock().watch().is_ok(), "watch failed");
+
+        let poller = {
+            let shared = shared.clone();
+            thread::spawn(move || shared.poll(Some(Duration::from_secs(5))))
+        };
+
+        // registering must not wait for the blocked poller
+        thread::sleep(Duration::from_millis(50));
+        {
+            let mut watcher = shared.lock();
+            assert!(watcher.add_read(rx.into_raw_fd()).is_ok(), "add failed");
+            assert!(watcher.watch().is_ok(), "watch failed");
+        }
+        assert!(tx.write_all(b"foo").is_ok(), "write failed");
+
+        let ev = poller.join().unwrap().unwrap().unwrap();
+        match ev.data {
+            EventData::ReadReady(_) => (),
+            _ => panic!("unexpected event: {:?}", ev),
+        };
+    }
+
+    #[test]
+    fn test_poll_timed() {
+        let (mut tx, rx) = UnixStream::pair().unwrap();
+        let mut watcher = Watcher::new().unwrap();
+
+        assert!(watcher.add_read(rx.into_raw_fd()).is_ok(), "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+
+        let (ev, elapsed) = watcher.poll_timed(Some(Duration::from_millis(100))).unwrap();
+        assert!(ev.is_none(), "unexpected event: {:?}", ev);
+        assert!(elapsed >= Duration::from_millis(90), "returned after {:?}", elapsed);
+
+        assert!(tx.write_all(b"foo").is_ok(), "write failed");
+        let (ev, elapsed) = watcher.poll_timed(Some(Duration::from_secs(5))).unwrap();
+        assert!(ev.is_some(), "no event");
+        assert!(elapsed < Duration::from_millis(100), "blocked for {:?}", elapsed);
+    }
+
+    #[test]
+    fn test_add_all() {
+        let (mut senders, sources): (Vec<UnixStream>, Vec<UnixStream>) =
+            (0..50).map(|_| UnixStream::pair().unwrap()).unzip();
+        let mut watcher = Watcher::new().unwrap();
+
+        let keys = watcher.add_all(&sources, EventFilter::EVFILT_READ, FilterFlag::empty())
+            .unwrap();
+        assert_eq!(keys.len(), 50);
+        assert!(watcher.add_all(&sources[..10], EventFilter::EVFILT_READ, FilterFlag::empty())
+                    .unwrap() == keys[..10].to_vec(),
+                "sources added twice");
+        assert_eq!(watcher.export_config().len(), 50);
+        assert!(watcher.is_owned(&Ident::Fd(sources[0].as_raw_fd())) == Some(false));
+        assert!(watcher.watch().is_ok(), "watch failed");
+
+        for tx in &mut senders {
+            assert!(tx.write_all(b"foo").is_ok(), "write failed");
+        }
+
+        let events = watcher.poll_all(Some(Duration::from_secs(1))).unwrap();
+        assert_eq!(events.len(), 50);
+    }
+
+    #[cfg(any(target_os = "freebsd", target_os = "dragonfly"))]
+    #[test]
+    fn test_user_event_coalescing() {
+        let mut watcher = Watcher::new().unwrap();
+
+        assert!(watcher.add_user(42).is_ok(), "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+        assert!(watcher.trigger(7).is_err(), "unknown user event triggered");
+
+        assert!(watcher.trigger(42).is_ok(), "trigger failed");
+        assert!(watcher.trigger(42).is_ok(), "trigger failed");
+        assert_eq!(watcher.user_pending(42), Some(2));
+
+        let ev = watcher.poll(Some(Duration::from_secs(1))).unwrap();
+        assert!(ev.ident == Ident::User(42));
+        match ev.data {
+            EventData::User(count) => assert_eq!(count, 2),
+            _ => panic!("unexpected event: {:?}", ev),
+        };
+        assert!(watcher.poll(None).is_none(), "triggers not coalesced");
+        assert_eq!(watcher.user_pending(42), Some(0));
+
+        assert!(watcher.trigger(42).is_ok(), "trigger failed");
+        assert!(watcher.clear_user(42).is_ok(), "clear failed");
+        assert!(watcher.poll(None).is_none(), "cleared event delivered");
+        assert_eq!(watcher.user_pending(42), Some(0));
+    }
+
+    #[test]
+    fn test_fd_udata() {
+        let (mut tx, rx) = UnixStream::pair().unwrap();
+        let (mut other_tx, other_rx) = UnixStream::pair().unwrap();
+        let mut context = 42u32;
+        let udata = &mut context as *mut u32 as *mut c_void;
+        let mut watcher = Watcher::new().unwrap();
+
+        let added = unsafe {
+            watcher.add_fd_with_udata(rx.into_raw_fd(),
+                                      EventFilter::EVFILT_READ,
+                                      FilterFlag::empty(),
+                                      udata)
+        };
+        assert!(added.is_ok(), "add failed");
+        assert!(watcher.add_read(other_rx.into_raw_fd()).is_ok(), "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+
+        assert!(tx.write_all(b"foo").is_ok(), "write failed");
+        let ev = watcher.poll(Some(Duration::from_secs(1))).unwrap();
+        assert!(ev.udata() == udata);
+        assert_eq!(unsafe { *(ev.udata() as *mut u32) }, 42);
+
+        assert!(other_tx.write_all(b"foo").is_ok(), "write failed");
+        let ev = watcher.poll(Some(Duration::from_secs(1))).unwrap();
+        assert!(ev.udata().is_null());
+
+        let ev = Event::from_parts(Ident::Fd(3), EventData::ReadReady(1), EventFilter::EVFILT_READ);
+        assert!(ev.udata().is_null() && !ev.is_final());
+        assert_eq!(ev.filter(), EventFilter::EVFILT_READ);
+    }
+
+    #[test]
+    fn test_supports() {
+        assert!(Watcher::supports(EventFilter::EVFILT_READ));
+        assert!(Watcher::supports(EventFilter::EVFILT_READ), "cached answer differs");
+        assert!(Watcher::supports(EventFilter::EVFILT_TIMER));
+        assert!(!Watcher::supports(EventFilter::EVFILT_SYSCOUNT));
+    }
+
+    #[test]
+    fn test_watch_builder() {
+        let (mut tx, rx) = UnixStream::pair().unwrap();
+        let mut watcher = Watcher::new().unwrap();
+        assert!(watcher.disable_clears().watch().is_ok(), "watch failed");
+
+        let key = watcher.watch_fd(rx.into_raw_fd())
+            .read()
+            .low_water(4)
+            .edge()
+            .oneshot()
+            .submit();
+        assert!(key.is_ok(), "submit failed");
+
+        let kev = {
+            let watched = watcher.watched.borrow();
+            watcher.to_kevent(watched.last().unwrap())
+        };
+        assert!(kev.filter == EventFilter::EVFILT_READ);
+        assert!(kev.flags == EV_ADD | EV_CLEAR | EV_ONESHOT, "flags: {:?}", kev.flags);
+        assert!(kev.fflags == NOTE_LOWAT);
+        assert_eq!(kev.data, 4);
+
+        assert!(tx.write_all(b"foo").is_ok(), "write failed");
+        assert!(watcher.poll(Some(Duration::from_millis(100))).is_none(), "fired below low water");
+        assert!(tx.write_all(b"bar").is_ok(), "write failed");
+        assert!(watcher.poll(Some(Duration::from_secs(1))).is_some(), "no event");
+    }
+
+    #[test]
+    fn test_device_revoke() {
+        extern "C" {
+            fn revoke(path: *const libc::c_char) -> libc::c_int;
+        }
+
+        let mut master = -1;
+        let mut slave = -1;
+        let opened = unsafe {
+            libc::openpty(&mut master,
+                          &mut slave,
+                          ptr::null_mut(),
+                          ptr::null_mut(),
+                          ptr::null_mut())
+        };
+        assert_eq!(opened, 0, "openpty failed");
+        let name = unsafe { CStr::from_ptr(libc::ttyname(slave)) }.to_owned();
+        let path = name.to_str().unwrap().to_owned();
+
+        let mut watcher = Watcher::new().unwrap();
+        assert!(watcher.add_device(&path).is_ok(), "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+        assert!(watcher.poll(None).is_none(), "event before revoke");
+
+        assert_eq!(unsafe { revoke(name.as_ptr()) }, 0, "revoke failed");
+        let ev = watcher.poll(Some(Duration::from_secs(1))).unwrap();
+        match ev.data {
+            EventData::Vnode(Vnode::Revoke) => (),
+            _ => panic!("unexpected event: {:?}", ev),
+        };
+
+        match ev.ident {
+            Ident::Filename(_, ref name) => assert!(*name == path),
+            _ => panic!("unexpected ident: {:?}", ev.ident),
+        };
+
+        unsafe {
+            libc::close(slave);
+            libc::close(master);
+        }
+    }
+
+    #[cfg(any(target_os = "freebsd", target_os = "macos"))]
+    #[test]
+    fn test_add_pgid() {
+        let mut child = Command::new("sh")
+            .args(["-c", "sleep 1 & wait"])
+            .process_group(0)
+            .spawn()
+            .unwrap();
+        let pgid = child.id() as libc::pid_t;
+        thread::sleep(Duration::from_millis(200));
+
+        let mut watcher = Watcher::new().unwrap();
+        let keys = watcher.add_pgid(pgid).unwrap();
+        assert_eq!(keys.len(), 2, "group members missing");
+        assert!(watcher.watch().is_ok(), "watch failed");
+
+        let mut exited = Vec::new();
+        while exited.len() < 2 {
+            let ev = watcher.poll(Some(Duration::from_secs(5))).unwrap();
+            match (ev.ident, ev.data) {
+                (Ident::Pid(pid), EventData::Proc(Proc::Exit(_))) => exited.push(pid),
+                (ident, data) => panic!("unexpected event: {:?} {:?}", ident, data),
+            }
+        }
+
+        assert!(exited.contains(&pgid), "leader exit missing");
+        assert!(exited.iter().any(|&pid| pid != pgid), "member exit missing");
+        assert!(child.wait().unwrap().success());
+    }
+
+    #[test]
+    fn test_last_watch_errors() {
+        let (mut tx, rx) = UnixStream::pair().unwrap();
+        let mut watcher = Watcher::new().unwrap();
+
+        // a closed fd would be pruned before registering, a missing pid isn't
+        assert!(watcher.add_read(rx.into_raw_fd()).is_ok(), "add failed");
+        assert!(watcher.add_pid(i32::MAX, EventFilter::EVFILT_PROC, NOTE_EXIT).is_ok(),
+                "add failed");
+        assert!(watcher.watch().is_err(), "missing pid accepted");
+        assert!(watcher.last_watch_errors().len() == 1,
+                "errors: {:?}",
+                watcher.last_watch_errors());
+        match watcher.last_watch_errors()[0] {
+            (Ident::Pid(i32::MAX), ref err) => assert!(err.raw_os_error() == Some(libc::ESRCH)),
+            ref other => panic!("unexpected error: {:?}", other),
+        };
+
+        assert!(tx.write_all(b"foo").is_ok(), "write failed");
+        assert!(watcher.poll(Some(Duration::from_secs(1))).is_some(), "good fd not registered");
+    }
+
+    #[test]
+    fn test_drop_keeps_borrowed_fds() {
+        let (mut tx, rx) = UnixStream::pair().unwrap();
+        let fd = rx.as_raw_fd();
+
+        {
+            let mut watcher = Watcher::new().unwrap();
+            assert!(watcher.add_fd(fd, EventFilter::EVFILT_VNODE, NOTE_WRITE).is_ok(),
+                    "add failed");
+            assert!(watcher.watch().is_err(), "vnode watch on a socket registered");
+        }
+
+        assert!(unsafe { libc::fcntl(fd, libc::F_GETFD) } != -1, "borrowed fd closed");
+        assert!(tx.write_all(b"foo").is_ok(), "write failed");
+        let mut buf = [0u8; 3];
+        assert!((&rx).read_exact(&mut buf).is_ok(), "read failed");
+    }
+
+    #[test]
+    fn test_poll_iter() {
+        let (mut senders, sources): (Vec<UnixStream>, Vec<UnixStream>) =
+            (0..5).map(|_| UnixStream::pair().unwrap()).unzip();
+        let mut watcher = Watcher::new().unwrap();
+
+        assert!(watcher.add_all(&sources, EventFilter::EVFILT_READ, FilterFlag::empty()).is_ok(),
+                "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+        for tx in senders.iter_mut().take(3) {
+            assert!(tx.write_all(b"foo").is_ok(), "write failed");
+        }
+
+        let events = watcher.poll_iter(Some(Duration::from_secs(1))).unwrap();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events.count(), 3);
+        assert_eq!(watcher.poll_iter(None).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_watch_once() {
+        let filename = "/tmp/testing_watch_once.txt";
+        let mut file = fs::File::create(filename).unwrap();
+        let mut watcher = Watcher::new().unwrap();
+
+        assert!(watcher.add_filename(filename, EventFilter::EVFILT_VNODE, NOTE_WRITE).is_ok(),
+                "add failed");
+        assert!(watcher.watch_once(None).unwrap().is_none(), "event before the write");
+
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            file.write_all(b"foo")
+        });
+        let ev = watcher.watch_once(Some(Duration::from_secs(1))).unwrap().unwrap();
+        assert!(writer.join().unwrap().is_ok(), "write failed");
+        match ev.data {
+            EventData::Vnode(Vnode::Write { .. }) => (),
+            _ => panic!("unexpected event: {:?}", ev),
+        };
+    }
+
+    #[test]
+    fn test_proc_exec() {
+        let mut child = Command::new("sh").args(["-c", "sleep 0.2; exec true"]).spawn().unwrap();
+        let pid = child.id() as libc::pid_t;
+        let mut watcher = Watcher::new().unwrap();
+
+        assert!(watcher.add_pid(pid, EventFilter::EVFILT_PROC, NOTE_EXIT | NOTE_EXEC).is_ok(),
+                "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+
+        let ev = watcher.poll(Some(Duration::from_secs(5))).unwrap();
+        match ev.data {
+            EventData::Proc(Proc::Exec) => (),
+            _ => panic!("unexpected event: {:?}", ev),
+        };
+
+        assert!(child.wait().unwrap().success());
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn test_proc_track() {
+        let mut child = Command::new("sh").args(["-c", "sleep 0.2; true & wait"]).spawn().unwrap();
+        let pid = child.id() as libc::pid_t;
+        let mut watcher = Watcher::new().unwrap();
+
+        assert!(watcher.add_pid(pid, EventFilter::EVFILT_PROC, NOTE_TRACK | NOTE_EXIT).is_ok(),
+                "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+
+        loop {
+            let ev = watcher.poll(Some(Duration::from_secs(5))).unwrap();
+            match ev.data {
+                EventData::Proc(Proc::Child(parent)) => {
+                    assert_eq!(parent, pid);
+                    break;
+                }
+                EventData::Proc(Proc::Exit(_)) => (),
+                _ => panic!("unexpected event: {:?}", ev),
+            };
+        }
+
+        assert!(child.wait().unwrap().success());
+    }
+
+    #[cfg(target_os = "macos")]
+    #[test]
+    fn test_proc_track_rejected() {
+        let mut watcher = Watcher::new().unwrap();
+        assert!(watcher.add_pid(1, EventFilter::EVFILT_PROC, NOTE_TRACK).is_err(),
+                "NOTE_TRACK accepted");
+    }
+
+    #[test]
+    fn test_wait_for() {
+        let mut first = Command::new("sleep").arg("0.1").spawn().unwrap();
+        let mut second = Command::new("sleep").arg("0.3").spawn().unwrap();
+        let first_pid = first.id() as libc::pid_t;
+        let second_pid = second.id() as libc::pid_t;
+        let mut watcher = Watcher::new().unwrap();
+
+        assert!(watcher.add_pid(first_pid, EventFilter::EVFILT_PROC, NOTE_EXIT).is_ok(),
+                "add failed");
+        assert!(watcher.add_pid(second_pid, EventFilter::EVFILT_PROC, NOTE_EXIT).is_ok(),
+                "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+
+        let ev = watcher.wait_for(&Ident::Pid(second_pid), Some(Duration::from_secs(5)))
+            .unwrap()
+            .unwrap();
+        assert!(ev.ident == Ident::Pid(second_pid));
+
+        let ev = watcher.poll(None).unwrap();
+        assert!(ev.ident == Ident::Pid(first_pid), "unexpected event: {:?}", ev);
+        match ev.data {
+            EventData::Proc(Proc::Exit(_)) => (),
+            _ => panic!("unexpected event: {:?}", ev),
+        };
+
+        assert!(first.wait().unwrap().success());
+        assert!(second.wait().unwrap().success());
+    }
+
+    #[test]
+    fn test_poll_matching() {
+        let mut watcher = Watcher::new().unwrap();
+        let (mut first, first_peer) = UnixStream::pair().unwrap();
+        let (mut second, mut second_peer) = UnixStream::pair().unwrap();
+        let first_fd = first_peer.as_raw_fd();
+        let second_fd = second_peer.as_raw_fd();
+
+        assert!(watcher.add_fd(first_fd, EventFilter::EVFILT_READ, FilterFlag::empty()).is_ok(),
+                "add failed");
+        assert!(watcher.add_fd(second_fd, EventFilter::EVFILT_READ, FilterFlag::empty()).is_ok(),
+                "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+
+        first.write_all(b"a").unwrap();
+        second.write_all(b"b").unwrap();
+
+        let is_second = |ev: &Event| ev.ident == Ident::Fd(second_fd);
+        let ev = watcher.poll_matching(is_second, Some(Duration::from_secs(1))).unwrap();
+        assert!(ev.is_some(), "no event for the second stream");
+
+        let mut buf = [0; 1];
+        second_peer.read_exact(&mut buf).unwrap();
+        assert!(watcher.poll_matching(is_second, None).unwrap().is_none());
+
+        // The first stream's event was held back, not dropped.
+        let ev = watcher.poll(None).unwrap();
+        assert!(ev.ident == Ident::Fd(first_fd), "unexpected event: {:?}", ev);
+    }
+
+    #[derive(Default)]
+    struct Counters {
+        reads: AtomicUsize,
+        syscalls: AtomicUsize,
+        errors: AtomicUsize,
+    }
+
+    impl Metrics for Counters {
+        fn on_event(&self, filter: EventFilter) {
+            if filter == EventFilter::EVFILT_READ {
+                self.reads.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        fn on_syscall(&self) {
+            self.syscalls.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn on_error(&self, _err: &io::Error) {
+            self.errors.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_metrics() {
+        let counters = Arc::new(Counters::default());
+        let mut watcher = Watcher::new().unwrap();
+        watcher.metrics(counters.clone());
+        let (mut stream, peer) = UnixStream::pair().unwrap();
+
+        assert!(watcher.add_fd(peer.as_raw_fd(), EventFilter::EVFILT_READ, FilterFlag::empty())
+                    .is_ok(),
+                "add failed");
+        assert!(watcher.add_pid(i32::MAX, EventFilter::EVFILT_PROC, NOTE_EXIT).is_ok(),
+                "add failed");
+        assert!(watcher.watch().is_err(), "missing pid registered");
+        assert_eq!(counters.errors.load(Ordering::SeqCst), 1);
+
+        stream.write_all(b"x").unwrap();
+        assert!(watcher.poll(Some(Duration::from_secs(1))).is_some(), "no event");
+        assert_eq!(counters.reads.load(Ordering::SeqCst), 1);
+        assert!(counters.syscalls.load(Ordering::SeqCst) >= 2);
+    }
+
+    #[test]
+    fn test_timer_overrun() {
+        for &clear in &[true, false] {
+            let mut watcher = Watcher::new().unwrap();
+            if !clear {
+                watcher.disable_clears();
+            }
+
+            assert!(watcher.add_timer(1, Duration::from_millis(10)).is_ok(), "add failed");
+            assert!(watcher.watch().is_ok(), "watch failed");
+
+            thread::sleep(Duration::from_millis(100));
+            let ev = watcher.poll(None).unwrap();
+            let missed = ev.timer_overrun().unwrap();
+            assert!(missed > 1, "slow drain reported {} expiries", missed);
+
+            // the timer re-armed and its count started over
+            let ev = watcher.poll(Some(Duration::from_secs(1))).unwrap();
+            let count = ev.timer_overrun().unwrap();
+            assert!(count >= 1 && count < missed, "unexpected count {}", count);
+        }
+    }
+
+    #[test]
+    fn test_apply_changes() {
+        let counters = Arc::new(Counters::default());
+        let mut watcher = Watcher::new().unwrap();
+        watcher.metrics(counters.clone());
+        let (mut tx_a, rx_a) = UnixStream::pair().unwrap();
+        let (mut tx_b, rx_b) = UnixStream::pair().unwrap();
+        let (mut tx_c, rx_c) = UnixStream::pair().unwrap();
+
+        let a = watcher.add_read(rx_a.as_raw_fd()).unwrap();
+        let b = watcher.add_read(rx_b.as_raw_fd()).unwrap();
+        assert!(watcher.watch().is_ok(), "watch failed");
+        let c = watcher.add_read(rx_c.as_raw_fd()).unwrap();
+
+        let syscalls = counters.syscalls.load(Ordering::SeqCst);
+        assert!(watcher.apply_changes(&[(c, EV_ADD), (a, EV_DELETE), (b, EV_DISABLE)]).is_ok(),
+                "changes failed");
+        assert_eq!(counters.syscalls.load(Ordering::SeqCst), syscalls + 1);
+        assert!(watcher.apply_changes(&[(b, EV_CLEAR)]).is_err(), "bogus change accepted");
+
+        for tx in &mut [&mut tx_a, &mut tx_b, &mut tx_c] {
+            tx.write_all(b"x").unwrap();
+        }
+        let ev = watcher.poll(Some(Duration::from_secs(1))).unwrap();
+        assert!(ev.ident == Ident::Fd(rx_c.as_raw_fd()), "unexpected event: {:?}", ev);
+        assert!(watcher.poll(Some(Duration::from_millis(50))).is_none(), "extra event");
+    }
+
+    #[test]
+    fn test_event_filter() {
+        let mut watcher = Watcher::new().unwrap();
+        let (mut tx, rx) = UnixStream::pair().unwrap();
+
+        assert!(watcher.add_read(rx.as_raw_fd()).is_ok(), "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+
+        tx.write_all(b"x").unwrap();
+        let ev = watcher.poll(Some(Duration::from_secs(1))).unwrap();
+        assert!(ev.filter() == EventFilter::EVFILT_READ, "unexpected event: {:?}", ev);
+
+        let kev = kevent {
+            ident: i32::MAX as usize,
+            filter: EventFilter::EVFILT_PROC,
+            flags: EV_ERROR,
+            fflags: FilterFlag::empty(),
+            data: libc::ESRCH as i64,
+            udata: to_udata(0),
+        };
+        let ev = Event::from_error(kev, &watcher);
+        assert!(ev.is_err() && ev.filter() == EventFilter::EVFILT_PROC);
+    }
+
+    #[test]
+    fn test_add_pipe() {
+        let mut watcher = Watcher::new().unwrap();
+        let (write_fd, _) = watcher.add_pipe().unwrap();
+        assert!(watcher.watch().is_ok(), "watch failed");
+
+        let flags = unsafe { libc::fcntl(write_fd, libc::F_GETFD) };
+        assert!(flags & libc::FD_CLOEXEC != 0, "write end inherited across exec");
+
+        let mut tx = unsafe { fs::File::from_raw_fd(write_fd) };
+        tx.write_all(b"wake").unwrap();
+
+        let ev = watcher.poll(Some(Duration::from_secs(1))).unwrap();
+        match ev.data {
+            EventData::ReadReady(4) => (),
+            _ => panic!("unexpected event: {:?}", ev),
+        };
+    }
+
+    #[test]
+    fn test_watch_and_collect() {
+        let counters = Arc::new(Counters::default());
+        let mut watcher = Watcher::new().unwrap();
+        watcher.metrics(counters.clone());
+        let (tx, _rx) = UnixStream::pair().unwrap();
+
+        assert!(watcher.add_write(tx.as_raw_fd()).is_ok(), "add failed");
+        let events = watcher.watch_and_collect(None).unwrap();
+        assert_eq!(counters.syscalls.load(Ordering::SeqCst), 1);
+        assert!(events.len() == 1, "events: {:?}", events);
+        match events[0].data {
+            EventData::WriteReady(_) => (),
+            _ => panic!("unexpected event: {:?}", events[0]),
+        };
+    }
+
+    #[cfg(target_os = "macos")]
+    #[test]
+    fn test_timer_leeway() {
+        let mut watcher = Watcher::new().unwrap();
+        let interval = Duration::from_millis(50);
+        let leeway = Duration::from_millis(20);
+
+        assert!(watcher.add_timer_leeway(1, interval, leeway).is_ok(), "add failed");
+        let start = Instant::now();
+        assert!(watcher.watch().is_ok(), "watch failed");
+
+        let ev = watcher.poll(Some(Duration::from_secs(1))).unwrap();
+        let elapsed = start.elapsed();
+        assert!(ev.timer_overrun().is_some(), "unexpected event: {:?}", ev);
+        assert!(elapsed >= interval - Duration::from_millis(5), "timer fired early");
+        // scheduling adds its own delay on top of the leeway
+        assert!(elapsed <= interval + leeway + Duration::from_millis(500),
+                "timer fired after its leeway: {:?}",
+                elapsed);
+
+        // later changes go through the same call as the first ones
+        assert!(watcher.add_timer_leeway(2, interval, leeway).is_ok(), "add failed");
+        assert!(watcher.watch().is_ok(), "rewatch failed");
+        match watcher.poll(Some(Duration::from_secs(1))) {
+            Some(Event { ident: Ident::Timer(_), .. }) => (),
+            other => panic!("unexpected event: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_ident_from() {
+        let file = fs::File::open("/dev/null").unwrap();
+        let fd = file.as_raw_fd();
+
+        assert!(Ident::from(fd) == Ident::Fd(fd));
+        assert!(Ident::from(&file) == Ident::Fd(fd));
+        assert!(Ident::from(&file).same_source(&Ident::Filename(fd, "/dev/null".to_string())));
+    }
+
+    #[test]
+    fn test_add_filename_rw() {
+        let mut watcher = Watcher::new().unwrap();
+        let filename = "/tmp/testing_add_filename_rw.txt";
+        let mut file = fs::File::create(filename).unwrap();
+
+        assert!(watcher.add_filename_rw(filename, NOTE_WRITE | NOTE_EXTEND).is_ok(),
+                "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+        let fds: Vec<_> = watcher.watched.borrow().iter().filter_map(|w| w.ident.as_fd()).collect();
+        assert!(fds.len() == 2 && fds[0] == fds[1], "fd not shared: {:?}", fds);
+
+        file.write_all(b"more").unwrap();
+        let mut filters = Vec::new();
+        while let Some(ev) = watcher.poll(Some(Duration::from_millis(200))) {
+            filters.push(ev.filter());
+        }
+
+        assert!(filters.contains(&EventFilter::EVFILT_VNODE), "no vnode event: {:?}", filters);
+        assert!(filters.contains(&EventFilter::EVFILT_READ), "no read event: {:?}", filters);
+        fs::remove_file(filename).unwrap();
+    }
+
+    #[test]
+    fn test_max_pending() {
+        let mut watcher = Watcher::new().unwrap();
+        watcher.max_pending(4);
+        let pairs: Vec<_> = (0..8).map(|_| UnixStream::pair().unwrap()).collect();
+
+        for &(_, ref rx) in &pairs {
+            assert!(watcher.add_read(rx.as_raw_fd()).is_ok(), "add failed");
+        }
+        assert!(watcher.watch().is_ok(), "watch failed");
+        for &(ref tx, _) in &pairs {
+            (&*tx).write_all(b"x").unwrap();
+        }
+
+        let never = |ev: &Event| ev.ident == Ident::Timer(42);
+        assert!(watcher.poll_matching(never, Some(Duration::from_secs(1))).is_err(),
+                "buffer grew past its cap");
+        assert!(watcher.pending.borrow().len() == 4);
+
+        // nothing was dropped, the rest stayed in the kernel
+        let mut seen = 0;
+        while watcher.poll(Some(Duration::from_millis(100))).is_some() {
+            seen += 1;
+        }
+        assert_eq!(seen, 8);
+    }
+
+    #[test]
+    fn test_socket_read_ready() {
+        let mut watcher = Watcher::new().unwrap();
+        let (mut tx, rx) = socketpair().unwrap();
+
+        assert!(watcher.add_read(rx.as_raw_fd()).is_ok(), "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+        assert!(watcher.poll(None).is_none(), "empty socket readable");
+
+        tx.write_all(b"abc").unwrap();
+        let ev = watcher.poll(Some(Duration::from_secs(1))).unwrap();
+        match ev.data {
+            EventData::ReadReady(3) => (),
+            _ => panic!("unexpected event: {:?}", ev),
+        };
+    }
+
+    #[test]
+    fn test_socket_write_ready() {
+        let mut watcher = Watcher::new().unwrap();
+        let (tx, _rx) = socketpair().unwrap();
+
+        assert!(watcher.add_write(tx.as_raw_fd()).is_ok(), "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+
+        let ev = watcher.poll(Some(Duration::from_secs(1))).unwrap();
+        match ev.data {
+            EventData::WriteReady(space) => assert!(space > 0),
+            _ => panic!("unexpected event: {:?}", ev),
+        };
+    }
+
+    #[test]
+    fn test_socket_eof() {
+        let mut watcher = Watcher::new().unwrap();
+        let (tx, rx) = socketpair().unwrap();
+
+        assert!(watcher.add_read(rx.as_raw_fd()).is_ok(), "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+
+        drop(tx);
+        let ev = watcher.poll(Some(Duration::from_secs(1))).unwrap();
+        match ev.data {
+            EventData::ReadReady(0) => (),
+            _ => panic!("unexpected event: {:?}", ev),
+        };
+    }
+
+    #[test]
+    fn test_iter_before_watch() {
+        let mut watcher = Watcher::new().unwrap();
+        let (_tx, rx) = socketpair().unwrap();
+
+        assert!(watcher.add_read(rx.as_raw_fd()).is_ok(), "add failed");
+        assert!(!watcher.is_started());
+
+        let events: Vec<Event> = watcher.iter().collect();
+        assert!(events.len() == 1 && events[0].is_err(), "events: {:?}", events);
+        match events[0].data {
+            EventData::Error(ref err) => assert!(err.kind() == io::ErrorKind::InvalidInput),
+            _ => unreachable!(),
+        };
+
+        assert!(watcher.watch().is_ok(), "watch failed");
+        assert!(watcher.is_started());
+    }
+
+    #[cfg(target_os = "dragonfly")]
+    #[test]
+    fn test_add_except() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let mut watcher = Watcher::new().unwrap();
+
+        assert!(watcher.add_except(server.as_raw_fd()).is_ok(), "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+
+        let sent = unsafe {
+            libc::send(client.as_raw_fd(), b"!".as_ptr() as *const c_void, 1, libc::MSG_OOB)
+        };
+        assert_eq!(sent, 1);
+
+        let ev = watcher.poll(Some(Duration::from_secs(1))).unwrap();
+        match ev.data {
+            EventData::Except => (),
+            _ => panic!("unexpected event: {:?}", ev),
+        };
+    }
+
+    #[test]
+    fn test_remove_by_key() {
+        let mut watcher = Watcher::new().unwrap();
+        let filename = "/tmp/testing_remove_by_key.txt";
+        let mut file = fs::File::create(filename).unwrap();
+
+        let (_, read) = watcher.add_filename_rw(filename, NOTE_WRITE).unwrap();
+        assert!(watcher.watch().is_ok(), "watch failed");
+        assert!(watcher.remove(read).is_ok(), "remove failed");
+        assert!(watcher.remove(read).is_err(), "removed twice");
+
+        file.write_all(b"more").unwrap();
+        let ev = watcher.poll(Some(Duration::from_secs(1))).unwrap();
+        assert!(ev.filter() == EventFilter::EVFILT_VNODE, "unexpected event: {:?}", ev);
+        assert!(watcher.poll(Some(Duration::from_millis(100))).is_none(), "read watch remained");
+        fs::remove_file(filename).unwrap();
+    }
+
+    #[test]
+    fn test_signal_count() {
+        let mut watcher = Watcher::new().unwrap();
+        unsafe { libc::signal(libc::SIGUSR2, libc::SIG_IGN) };
+
+        assert!(watcher.add_signal(libc::SIGUSR2).is_ok(), "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+
+        for _ in 0..3 {
+            assert_eq!(unsafe { libc::kill(libc::getpid(), libc::SIGUSR2) }, 0);
+        }
+
+        let ev = watcher.poll(Some(Duration::from_secs(1))).unwrap();
+        assert!(ev.ident == Ident::Signal(libc::SIGUSR2));
+        match ev.data {
+            EventData::Signal(3) => (),
+            _ => panic!("unexpected event: {:?}", ev),
+        };
+    }
+
+    #[test]
+    fn test_add_write_lowat() {
+        let mut watcher = Watcher::new().unwrap();
+        let (mut tx, mut rx) = socketpair().unwrap();
+
+        // fill the send buffer; the last few bytes of space don't reach the mark
+        let chunk = [0; 1024];
+        while tx.write(&chunk).is_ok() {}
+
+        assert!(watcher.add_write_lowat(tx.as_raw_fd(), 4096).is_ok(), "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+        assert!(watcher.poll(Some(Duration::from_millis(50))).is_none(), "woke below the mark");
+
+        let mut buf = [0; 1024];
+        while rx.read(&mut buf).is_ok() {}
+
+        let ev = watcher.poll(Some(Duration::from_secs(1))).unwrap();
+        match ev.data {
+            EventData::WriteReady(space) => assert!(space >= 4096, "space {}", space),
+            _ => panic!("unexpected event: {:?}", ev),
+        };
+    }
+
+    #[test]
+    fn test_watches() {
+        let mut watcher = Watcher::new().unwrap();
+        let (_tx, rx) = socketpair().unwrap();
+
+        assert!(watcher.add_read(rx.as_raw_fd()).is_ok(), "add failed");
+        assert!(watcher.add_pid(1, EventFilter::EVFILT_PROC, FilterFlag::empty()).is_ok(),
+                "add failed");
+
+        let watches: Vec<_> = watcher.watches().collect();
+        assert!(watches.len() == 2);
+        assert!(*watches[0].ident() == Ident::Fd(rx.as_raw_fd()));
+        assert!(watches[0].filter() == EventFilter::EVFILT_READ);
+        assert!(*watches[1].ident() == Ident::Pid(1));
+        assert!(watches[1].flags() == NOTE_EXIT);
+    }
+
+    #[cfg(not(target_os = "openbsd"))]
+    #[test]
+    fn test_require_ack() {
+        let mut watcher = Watcher::new().unwrap();
+        let (mut tx, mut rx) = socketpair().unwrap();
+        let ident = Ident::Fd(rx.as_raw_fd());
+
+        let key = watcher.add_read(rx.as_raw_fd()).unwrap();
+        assert!(watcher.require_ack(key).is_ok(), "require_ack failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+
+        tx.write_all(b"x").unwrap();
+        for _ in 0..3 {
+            let ev = watcher.poll(Some(Duration::from_secs(1))).unwrap();
+            assert!(ev.ident == ident, "unexpected event: {:?}", ev);
+        }
+
+        let mut buf = [0; 1];
+        rx.read_exact(&mut buf).unwrap();
+        assert!(watcher.ack(&ident, EventFilter::EVFILT_READ).is_ok(), "ack failed");
+        assert!(watcher.poll(Some(Duration::from_millis(50))).is_none(), "acked event returned");
+
+        tx.write_all(b"y").unwrap();
+        assert!(watcher.poll(Some(Duration::from_secs(1))).is_some(), "new data not reported");
+    }
+
+    #[cfg(target_os = "freebsd")]
+    #[test]
+    fn test_vnode_open_close() {
+        let mut watcher = Watcher::new().unwrap();
+        let filename = "/tmp/testing_vnode_open_close.txt";
+        fs::File::create(filename).unwrap();
+
+        assert!(watcher.add_filename(filename, EventFilter::EVFILT_VNODE, NOTE_OPEN | NOTE_CLOSE)
+                    .is_ok(),
+                "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+
+        // Poll in between, or the kernel merges both notes into one event
+        let file = fs::File::open(filename).unwrap();
+        let opened = watcher.poll(Some(Duration::from_secs(1))).unwrap();
+        match opened.data {
+            EventData::Vnode(Vnode::Open) => (),
+            _ => panic!("unexpected event: {:?}", opened),
+        };
+        drop(file);
+        let closed = watcher.poll(Some(Duration::from_secs(1))).unwrap();
+        match closed.data {
+            EventData::Vnode(Vnode::Close) => (),
+            _ => panic!("unexpected event: {:?}", closed),
+        };
+
+        fs::remove_file(filename).unwrap();
+    }
+
+    #[test]
+    fn test_tail() {
+        let filename = "/tmp/testing_tail.txt";
+        fs::write(filename, b"old\n").unwrap();
+        let mut log = fs::OpenOptions::new().append(true).open(filename).unwrap();
+
+        let mut watcher = Watcher::new().unwrap();
+        let mut tail = watcher.tail(filename).unwrap();
+
+        log.write_all(b"one\n").unwrap();
+        assert_eq!(tail.next().unwrap().unwrap(), b"one\n");
+        log.write_all(b"two\nthree\n").unwrap();
+        assert_eq!(tail.next().unwrap().unwrap(), b"two\nthree\n");
+
+        log.set_len(0).unwrap();
+        log.write_all(b"four\n").unwrap();
+        assert_eq!(tail.next().unwrap().unwrap(), b"four\n");
+
+        fs::remove_file(filename).unwrap();
+    }
+
+    #[test]
+    fn test_filename_at() {
+        let dirname = "/tmp/testing_filename_at";
+        fs::create_dir_all(dirname).unwrap();
+        let mut file = fs::File::create(format!("{}/file.txt", dirname)).unwrap();
+        let dir = fs::File::open(dirname).unwrap();
+
+        let mut watcher = Watcher::new().unwrap();
+        assert_eq!(watcher.add_filename_at(-1, "file.txt", EventFilter::EVFILT_VNODE, NOTE_WRITE)
+                       .unwrap_err()
+                       .kind(),
+                   io::ErrorKind::InvalidInput);
+        assert!(watcher.add_filename_at(dir.as_raw_fd(),
+                                        "file.txt",
+                                        EventFilter::EVFILT_VNODE,
+                                        NOTE_WRITE)
+                    .is_ok(),
+                "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+
+        file.write_all(b"foo").unwrap();
+        let ev = watcher.poll(Some(Duration::from_secs(1))).unwrap();
+        match ev.ident {
+            Ident::Filename(_, ref name) => assert_eq!(name, "file.txt"),
+            _ => panic!("unexpected ident: {:?}", ev.ident),
+        };
+        match ev.data {
+            EventData::Vnode(Vnode::Write { .. }) => (),
+            _ => panic!("unexpected event: {:?}", ev),
+        };
+
+        fs::remove_dir_all(dirname).unwrap();
+    }
+
+    #[test]
+    fn test_disable_drops_buffered() {
+        let mut watcher = Watcher::new().unwrap();
+        let (first_fd, first) = watcher.add_pipe().unwrap();
+        let (second_fd, _) = watcher.add_pipe().unwrap();
+        let mut first_tx = unsafe { fs::File::from_raw_fd(first_fd) };
+        let mut second_tx = unsafe { fs::File::from_raw_fd(second_fd) };
+        assert!(watcher.watch().is_ok(), "watch failed");
+
+        first_tx.write_all(b"a").unwrap();
+        second_tx.write_all(b"b").unwrap();
+        let second = watcher.watches().nth(1).unwrap().ident().clone();
+        assert!(watcher.wait_for(&second, Some(Duration::from_secs(1))).unwrap().is_some());
+
+        // the first pipe's event is buffered by now
+        assert!(watcher.disable(first).is_ok(), "disable failed");
+        assert!(watcher.poll(None).is_none(), "disabled watch delivered");
+
+        first_tx.write_all(b"c").unwrap();
+        assert!(watcher.enable(first).is_ok(), "enable failed");
+        match watcher.poll(Some(Duration::from_secs(1))).unwrap().data {
+            EventData::ReadReady(2) => (),
+            data => panic!("unexpected event: {:?}", data),
+        };
+        assert!(watcher.poll(None).is_none(), "activity reported twice");
+    }
+
+    #[test]
+    fn test_reapply_clear_toggle() {
+        let mut watcher = Watcher::new().unwrap();
+        let (write_fd, _) = watcher.add_pipe().unwrap();
+        let mut tx = unsafe { fs::File::from_raw_fd(write_fd) };
+        assert!(watcher.watch().is_ok(), "watch failed");
+
+        tx.write_all(b"unread").unwrap();
+        assert!(watcher.poll(Some(Duration::from_secs(1))).is_some());
+        assert!(watcher.poll(None).is_none(), "edge watch fired twice");
+
+        // level-triggered, the unread data shows up on every poll
+        assert!(watcher.disable_clears().reapply().is_ok(), "reapply failed");
+        assert!(watcher.poll(None).is_some());
+        assert!(watcher.poll(None).is_some());
+
+        // and back to edge-triggered: reported once, not lost or doubled
+        assert!(watcher.enable_clears().reapply().is_ok(), "reapply failed");
+        assert!(watcher.poll(None).is_some());
+        assert!(watcher.poll(None).is_none(), "edge watch fired twice");
+    }
+
+    #[test]
+    fn test_peek() {
+        let mut watcher = Watcher::new().unwrap();
+        let (write_fd, _) = watcher.add_pipe().unwrap();
+        let mut tx = unsafe { fs::File::from_raw_fd(write_fd) };
+        assert!(watcher.watch().is_ok(), "watch failed");
+        assert!(watcher.peek().unwrap().is_none(), "peeked at nothing");
+
+        tx.write_all(b"peek").unwrap();
+        let peeked = watcher.peek().unwrap().unwrap().ident.clone();
+        assert_eq!(watcher.peek().unwrap().unwrap().ident, peeked);
+
+        let ev = watcher.poll(None).unwrap();
+        assert_eq!(ev.ident, peeked);
+        match ev.data {
+            EventData::ReadReady(4) => (),
+            _ => panic!("unexpected event: {:?}", ev),
+        };
+        assert!(watcher.peek().unwrap().is_none(), "event delivered twice");
+    }
+
+    #[test]
+    fn test_exit_info() {
+        let mut watcher = Watcher::new().unwrap();
+        let mut child = Command::new("sh").arg("-c").arg("sleep 1; exit 3").spawn().unwrap();
+
+        assert!(watcher.add_pid(child.id() as libc::pid_t, EventFilter::EVFILT_PROC, NOTE_EXIT)
+                    .is_ok(),
+                "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+
+        let ev = watcher.poll(Some(Duration::from_secs(5))).unwrap();
+        let info = match ev.data {
+            EventData::Proc(ref exited) => exited.exit_info().expect("not an exit"),
+            _ => panic!("unexpected event: {:?}", ev),
+        };
+        assert_eq!(info.code, Some(3));
+        assert_eq!(info.signal, None);
+        #[cfg(target_os = "macos")]
+        assert_eq!(info.detail, None);
+
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn test_disown_fd() {
+        let filename = "/tmp/testing_disown_fd.txt";
+        fs::File::create(filename).unwrap();
+
+        let mut watcher = Watcher::new().unwrap();
+        assert!(watcher.add_filename(filename, EventFilter::EVFILT_VNODE, NOTE_WRITE).is_ok(),
+                "add failed");
+        let fd = watcher.watches().next().unwrap().ident().as_fd().unwrap();
+        assert!(watcher.disown_fd(-1).is_err(), "disowned an unwatched fd");
+        assert!(watcher.disown_fd(fd).is_ok(), "disown failed");
+        assert_eq!(watcher.is_owned(&Ident::Filename(0, filename.to_string())), Some(false));
+
+        drop(watcher);
+        assert!(unsafe { libc::fcntl(fd, libc::F_GETFD) } != -1, "disowned fd closed");
+
+        let mut watcher = Watcher::new().unwrap();
+        assert!(watcher.add_fd(fd, EventFilter::EVFILT_VNODE, NOTE_WRITE).is_ok(), "add failed");
+        assert!(watcher.take_ownership(fd).is_ok(), "take_ownership failed");
+        drop(watcher);
+        assert_eq!(unsafe { libc::fcntl(fd, libc::F_GETFD) }, -1, "owned fd left open");
+
+        fs::remove_file(filename).unwrap();
+    }
+
+    #[test]
+    fn test_syscall_count() {
+        let mut watcher = Watcher::new().unwrap();
+        let mut writers = Vec::new();
+        for _ in 0..50 {
+            let (write_fd, _) = watcher.add_pipe().unwrap();
+            writers.push(unsafe { fs::File::from_raw_fd(write_fd) });
+        }
+        assert!(watcher.watch().is_ok(), "watch failed");
+        assert_eq!(watcher.syscall_count(), 1);
+
+        for tx in writers.iter_mut() {
+            tx.write_all(b"x").unwrap();
+        }
+
+        // a single kevent(2) call drains all of them
+        assert_eq!(watcher.poll_all(Some(Duration::from_secs(1))).unwrap().len(), 50);
+        assert_eq!(watcher.syscall_count(), 2);
+    }
+
+    #[cfg(target_os = "freebsd")]
+    #[test]
+    fn test_close_write() {
+        let mut watcher = Watcher::new().unwrap();
+        let filename = "/tmp/testing_close_write.txt";
+        fs::File::create(filename).unwrap();
+
+        assert!(watcher.add_close_write(filename).is_ok(), "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+
+        let mut file = fs::OpenOptions::new().write(true).open(filename).unwrap();
+        file.write_all(b"part one").unwrap();
+        file.write_all(b"part two").unwrap();
+        assert!(watcher.poll(None).is_none(), "reported before the close");
+
+        drop(file);
+        let ev = watcher.poll(Some(Duration::from_secs(1))).unwrap();
+        match ev.data {
+            EventData::Vnode(Vnode::CloseWrite) => (),
+            _ => panic!("unexpected event: {:?}", ev),
+        };
+
+        // closing a read-only descriptor doesn't count
+        drop(fs::File::open(filename).unwrap());
+        assert!(watcher.poll(None).is_none(), "reported a read-only close");
+
+        fs::remove_file(filename).unwrap();
+    }
+
+    #[test]
+    fn test_poll_grouped() {
+        let mut watcher = Watcher::new().unwrap();
+        let (mut left, right) = socketpair().unwrap();
+        let (other_fd, _) = watcher.add_pipe().unwrap();
+        let mut other = unsafe { fs::File::from_raw_fd(other_fd) };
+
+        assert!(watcher.add_read(right.as_raw_fd()).is_ok(), "add failed");
+        assert!(watcher.add_write(right.as_raw_fd()).is_ok(), "add failed");
+        // A timer numbered like the fd is a group of its own
+        assert!(watcher.add_timer(right.as_raw_fd(), Duration::from_millis(10)).is_ok(),
+                "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+
+        left.write_all(b"ping").unwrap();
+        other.write_all(b"pong").unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        let groups = watcher.poll_grouped(Some(Duration::from_secs(1))).unwrap();
+        assert_eq!(groups.len(), 3);
+        assert_eq!(groups[&GroupKey(Ident::Timer(right.as_raw_fd()))].len(), 1);
+        let filters: Vec<EventFilter> = groups[&GroupKey(Ident::Fd(right.as_raw_fd()))]
+            .iter()
+            .map(|ev| ev.filter())
+            .collect();
+        assert_eq!(filters.len(), 2);
+        assert!(filters.contains(&EventFilter::EVFILT_READ) &&
+                filters.contains(&EventFilter::EVFILT_WRITE));
+    }
+
+    #[test]
+    fn test_poll_max_timeout() {
+        let mut watcher = Watcher::new().unwrap();
+        let (write_fd, _) = watcher.add_pipe().unwrap();
+        let mut tx = unsafe { fs::File::from_raw_fd(write_fd) };
+        assert!(watcher.watch().is_ok(), "watch failed");
+
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            tx.write_all(b"late").unwrap();
+        });
+
+        let start = Instant::now();
+        let ev = watcher.poll(Some(Duration::new(u64::MAX, 999_999_999))).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(150), "returned right away");
+        match ev.data {
+            EventData::ReadReady(4) => (),
+            _ => panic!("unexpected event: {:?}", ev),
+        };
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn test_add_fifo() {
+        let filename = "/tmp/testing_add_fifo";
+        let _ = fs::remove_file(filename);
+        let cpath = std::ffi::CString::new(filename).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(cpath.as_ptr(), 0o600) }, 0);
+
+        let mut watcher = Watcher::new().unwrap();
+        assert_eq!(watcher.add_fifo(filename, EventFilter::EVFILT_WRITE, FilterFlag::empty())
+                       .unwrap_err()
+                       .kind(),
+                   io::ErrorKind::NotConnected);
+        assert!(watcher.add_fifo(filename, EventFilter::EVFILT_READ, FilterFlag::empty()).is_ok(),
+                "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+
+        let mut writer = fs::OpenOptions::new().write(true).open(filename).unwrap();
+        writer.write_all(b"fifo").unwrap();
+        let ev = watcher.poll(Some(Duration::from_secs(1))).unwrap();
+        match ev.data {
+            EventData::ReadReady(4) => (),
+            _ => panic!("unexpected event: {:?}", ev),
+        };
+
+        let fd = ev.ident.as_fd().unwrap();
+        let mut buf = [0u8; 4];
+        assert_eq!(unsafe { libc::read(fd, buf.as_mut_ptr() as *mut c_void, 4) }, 4);
+        drop(writer);
+        let ev = watcher.poll(Some(Duration::from_secs(1))).unwrap();
+        match ev.data {
+            EventData::ReadReady(0) => (),
+            _ => panic!("unexpected event: {:?}", ev),
+        };
+
+        // now that there is a reader, the write end opens as well
+        let mut other = Watcher::new().unwrap();
+        assert!(other.add_fifo(filename, EventFilter::EVFILT_WRITE, FilterFlag::empty()).is_ok(),
+                "add writer failed");
+
+        fs::remove_file(filename).unwrap();
+    }
+
+    #[test]
+    fn test_run() {
+        let mut watcher = Watcher::new().unwrap();
+        let (first_fd, _) = watcher.add_pipe().unwrap();
+        let (second_fd, _) = watcher.add_pipe().unwrap();
+        let (wake_fd, wake) = watcher.add_pipe().unwrap();
+        unsafe { fs::File::from_raw_fd(first_fd) }.write_all(b"a").unwrap();
+        unsafe { fs::File::from_raw_fd(second_fd) }.write_all(b"b").unwrap();
+
+        // breaking on the first event leaves the other one buffered
+        let mut seen = 0;
+        watcher.run(|_| {
+                   seen += 1;
+                   ControlFlow::Break(())
+               })
+               .unwrap();
+        assert_eq!(seen, 1);
+        assert!(watcher.poll(None).is_some(), "remaining event lost");
+
+        let waker = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            unsafe { fs::File::from_raw_fd(wake_fd) }.write_all(b"stop").unwrap();
+        });
+        let wake_ident = watcher.watches().find(|w| w.key == wake.id).unwrap().ident().clone();
+        watcher.run(|ev| {
+                   if ev.ident == wake_ident {
+                       ControlFlow::Break(())
+                   } else {
+                       ControlFlow::Continue(())
+                   }
+               })
+               .unwrap();
+        waker.join().unwrap();
+    }
+
+    #[test]
+    fn test_partial_read_edge_and_level() {
+        let mut edge = Watcher::new().unwrap();
+        let (mut tx, mut rx) = socketpair().unwrap();
+        assert!(edge.add_read(rx.as_raw_fd()).is_ok(), "add failed");
+        assert!(edge.watch().is_ok(), "watch failed");
+
+        tx.write_all(b"12345678").unwrap();
+        assert!(edge.poll(Some(Duration::from_secs(1))).is_some());
+        let mut half = [0u8; 4];
+        rx.read_exact(&mut half).unwrap();
+
+        // the pitfall: the unread half doesn't re-fire an edge watch
+        assert!(edge.poll(Some(Duration::from_millis(100))).is_none(), "edge watch re-fired");
+        assert_eq!(drain_fully(&mut rx).unwrap(), b"5678");
+
+        let mut level = Watcher::new().unwrap();
+        let (mut tx, mut rx) = socketpair().unwrap();
+        assert!(level.add_read_level(rx.as_raw_fd()).is_ok(), "add failed");
+        assert!(level.watch().is_ok(), "watch failed");
+
+        tx.write_all(b"12345678").unwrap();
+        assert!(level.poll(Some(Duration::from_secs(1))).is_some());
+        rx.read_exact(&mut half).unwrap();
+        match level.poll(None).unwrap().data {
+            EventData::ReadReady(4) => (),
+            data => panic!("unexpected event: {:?}", data),
+        };
+    }
+
+    #[test]
+    fn test_tail_truncated_below_offset() {
+        let filename = "/tmp/testing_tail_truncated.txt";
+        fs::write(filename, b"").unwrap();
+        let mut log = fs::OpenOptions::new().append(true).open(filename).unwrap();
+
+        let mut watcher = Watcher::new().unwrap();
+        let mut tail = watcher.tail(filename).unwrap();
+
+        log.write_all(b"first line\n").unwrap();
+        assert_eq!(tail.next().unwrap().unwrap(), b"first line\n");
+        assert_eq!(tail.offset(), 11);
+
+        // shrunk below what was read, but not emptied
+        log.set_len(3).unwrap();
+        log.write_all(b"\n").unwrap();
+        assert_eq!(tail.next().unwrap().unwrap(), b"fir\n");
+        assert_eq!(tail.offset(), 4);
+
+        tail.set_offset(0);
+        log.write_all(b"again\n").unwrap();
+        assert_eq!(tail.next().unwrap().unwrap(), b"fir\nagain\n");
+
+        fs::remove_file(filename).unwrap();
+    }
+
+    #[test]
+    fn test_new_out_of_descriptors() {
+        let watcher = Watcher::new().unwrap();
+        let flags = unsafe { libc::fcntl(watcher.queue, libc::F_GETFD) };
+        assert!(flags & libc::FD_CLOEXEC != 0, "queue inherited across exec");
+
+        // lowering the limit would starve the other tests, so do it in a child
+        if !is_child("test_new_out_of_descriptors") {
+            assert!(run_in_child("test_new_out_of_descriptors"), "EMFILE not explained");
+            return;
+        }
+
+        let mut limit: libc::rlimit = unsafe { std::mem::zeroed() };
+        assert_eq!(unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) }, 0);
+        limit.rlim_cur = 0;
+        assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) }, 0);
+        let err = Watcher::new().expect_err("queue created without descriptors");
+        assert!(err.to_string().contains("RLIMIT_NOFILE"), "unexpected error: {}", err);
+        let cause = err.get_ref()
+            .and_then(|e| e.downcast_ref::<QueueCreationError>())
+            .expect("not a QueueCreationError");
+        assert!(cause.out_of_descriptors());
+        assert_eq!(cause.os_error().raw_os_error(), Some(libc::EMFILE));
+    }
+
+    #[test]
+    fn test_fds_not_inherited() {
+        if is_child("test_fds_not_inherited") {
+            for fd in env::var("KQUEUE_CHILD_FDS").unwrap().split(',') {
+                let fd: i32 = fd.parse().unwrap();
+                assert_eq!(unsafe { libc::fcntl(fd, libc::F_GETFD) }, -1,
+                           "fd {} inherited across exec",
+                           fd);
+            }
+            return;
+        }
+
+        let filename = "/tmp/testing_fds_not_inherited.txt";
+        fs::File::create(filename).unwrap();
+
+        let mut watcher = Watcher::new().unwrap();
+        assert!(watcher.add_filename(filename, EventFilter::EVFILT_VNODE, NOTE_WRITE).is_ok(),
+                "add failed");
+        let clone = watcher.try_clone().unwrap();
+        let fds = [watcher.queue,
+                   clone.queue,
+                   watcher.watches().next().unwrap().ident().as_fd().unwrap()];
+
+        let fds: Vec<String> = fds.iter().map(|fd| fd.to_string()).collect();
+        let mut child = child_test("test_fds_not_inherited");
+        child.env("KQUEUE_CHILD_FDS", fds.join(","));
+        assert!(child_passed(&mut child), "fds inherited across exec");
+
+        fs::remove_file(filename).unwrap();
+    }
+
+    #[test]
+    fn test_add_dir_contents() {
+        let dirname = "/tmp/testing_add_dir_contents";
+        let _ = fs::remove_dir_all(dirname);
+        fs::create_dir_all(format!("{}/subdir", dirname)).unwrap();
+        for name in ["a.txt", "b.txt", "c.txt"].iter() {
+            fs::File::create(format!("{}/{}", dirname, name)).unwrap();
+        }
+        // a dangling link can't be opened
+        std::os::unix::fs::symlink("/nonexistent", format!("{}/d.txt", dirname)).unwrap();
+
+        let mut watcher = Watcher::new().unwrap();
+        let (keys, failed) =
+            watcher.add_dir_contents(dirname, EventFilter::EVFILT_VNODE, NOTE_WRITE).unwrap();
+        assert_eq!(keys.len(), 3);
+        assert_eq!(failed.len(), 1);
+        assert!(failed[0].0.ends_with("d.txt"));
+        assert_eq!(failed[0].1.kind(), io::ErrorKind::NotFound);
+        assert!(watcher.last_watch_errors().is_empty(), "failures mixed into watch errors");
+        assert!(watcher.watch().is_ok(), "watch failed");
+
+        fs::write(format!("{}/b.txt", dirname), b"changed").unwrap();
+        let ev = watcher.poll(Some(Duration::from_secs(1))).unwrap();
+        match ev.ident {
+            Ident::Filename(_, ref name) => assert!(name.ends_with("/b.txt")),
+            _ => panic!("unexpected ident: {:?}", ev.ident),
+        };
+
+        fs::remove_dir_all(dirname).unwrap();
+    }
+
+    #[test]
+    fn test_add_tree() {
+        let root = "/tmp/testing_add_tree";
+        let _ = fs::remove_dir_all(root);
+        fs::create_dir_all(format!("{}/a", root)).unwrap();
+
+        let mut watcher = Watcher::new().unwrap();
+        assert!(watcher.add_tree(root, FilterFlag::empty()).is_ok(), "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+        assert_eq!(watcher.watches().count(), 2);
+
+        let from = |name: String| {
+            move |ev: &Event| match ev.ident {
+                Ident::Filename(_, ref path) => *path == name,
+                _ => false,
+            }
+        };
+        let timeout = Some(Duration::from_secs(1));
+
+        // nested directories created at once are all picked up
+        fs::create_dir_all(format!("{}/a/b/c", root)).unwrap();
+        assert!(watcher.poll_matching(from(format!("{}/a", root)), timeout).unwrap().is_some());
+        assert_eq!(watcher.watches().count(), 4);
+
+        fs::File::create(format!("{}/a/b/c/file", root)).unwrap();
+        assert!(watcher.poll_matching(from(format!("{}/a/b/c", root)), timeout)
+                    .unwrap()
+                    .is_some(),
+                "no event from the new directory");
+
+        fs::remove_dir_all(format!("{}/a/b", root)).unwrap();
+        assert!(watcher.poll_matching(from(format!("{}/a", root)), timeout).unwrap().is_some());
+        assert_eq!(watcher.watches().count(), 2);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_quiet_start() {
+        let mut watcher = Watcher::new().unwrap();
+        let (mut tx, rx) = socketpair().unwrap();
+        tx.write_all(b"stale").unwrap();
+
+        watcher.quiet_start();
+        assert!(watcher.add_read(rx.as_raw_fd()).is_ok(), "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+        assert!(watcher.poll(Some(Duration::from_millis(100))).is_none(),
+                "buffered data reported");
+
+        tx.write_all(b"fresh").unwrap();
+        match watcher.poll(Some(Duration::from_secs(1))).unwrap().data {
+            EventData::ReadReady(10) => (),
+            data => panic!("unexpected event: {:?}", data),
+        };
+
+        // once started, watching again leaves ready events alone
+        let (mut other_tx, other_rx) = socketpair().unwrap();
+        tx.write_all(b"live").unwrap();
+        other_tx.write_all(b"ready").unwrap();
+        assert!(watcher.add_read(other_rx.as_raw_fd()).is_ok(), "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+        let mut sizes: Vec<usize> = watcher.poll_all(Some(Duration::from_secs(1)))
+            .unwrap()
+            .into_iter()
+            .map(|ev| match ev.data {
+                EventData::ReadReady(size) => size,
+                data => panic!("unexpected event: {:?}", data),
+            })
+            .collect();
+        sizes.sort_unstable();
+        assert_eq!(sizes, vec![5, 14]);
+    }
+
+    #[test]
+    fn test_connect_refused() {
+        // find a port nobody listens on
+        let port = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().port()
+        };
+
+        let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_STREAM, 0) };
+        assert!(fd != -1, "socket failed");
+        let socket = unsafe { fs::File::from_raw_fd(fd) };
+        unsafe { libc::fcntl(fd, libc::F_SETFL, libc::O_NONBLOCK) };
+
+        let mut addr: libc::sockaddr_in = unsafe { std::mem::zeroed() };
+        addr.sin_family = libc::AF_INET as libc::sa_family_t;
+        addr.sin_port = port.to_be();
+        addr.sin_addr.s_addr = u32::from(std::net::Ipv4Addr::LOCALHOST).to_be();
+        let ret = unsafe {
+            libc::connect(fd,
+                          &addr as *const _ as *const libc::sockaddr,
+                          std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t)
+        };
+        let err = io::Error::last_os_error();
+        if ret == 0 || err.raw_os_error() != Some(libc::EINPROGRESS) {
+            // the kernel refused it right away, nothing left to wait for
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+            return;
+        }
+
+        let mut watcher = Watcher::new().unwrap();
+        assert!(watcher.add_write(socket.as_raw_fd()).is_ok(), "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+
+        let ev = watcher.poll(Some(Duration::from_secs(1))).unwrap();
+        match ev.connect_result() {
+            Some(Err(ref err)) if err.kind() == io::ErrorKind::ConnectionRefused => (),
+            _ => panic!("unexpected event: {:?}", ev),
+        };
+    }
+
+    #[test]
+    fn test_add_owned_fd() {
+        let filename = "/tmp/testing_add_owned_fd.txt";
+        let kept = fs::File::create(filename).unwrap();
+        let owned = OwnedFd::from(fs::File::open(filename).unwrap());
+        let fd = owned.as_raw_fd();
+
+        let mut watcher = Watcher::new().unwrap();
+        assert!(watcher.add_owned_fd(owned, EventFilter::EVFILT_VNODE, NOTE_WRITE).is_ok(),
+                "add failed");
+        assert!(watcher.add_fd(kept.as_raw_fd(), EventFilter::EVFILT_VNODE, NOTE_WRITE).is_ok(),
+                "add failed");
+        assert_eq!(watcher.is_owned(&Ident::Fd(fd)), Some(true));
+
+        drop(watcher);
+        assert_eq!(unsafe { libc::fcntl(fd, libc::F_GETFD) }, -1, "owned fd left open");
+        assert!(unsafe { libc::fcntl(kept.as_raw_fd(), libc::F_GETFD) } != -1,
+                "borrowed fd closed");
+
+        fs::remove_file(filename).unwrap();
+    }
+
+    extern "C" fn ignore_signal(_: libc::c_int) {}
+
+    #[test]
+    fn test_eintr_policy() {
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = ignore_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            assert_eq!(libc::sigaction(libc::SIGUSR1, &action, ptr::null_mut()), 0);
+        }
+
+        // interrupts this thread's wait `count` times, 100ms apart
+        let interrupt = |count: usize| {
+            let target = unsafe { libc::pthread_self() } as usize;
+            thread::spawn(move || for _ in 0..count {
+                thread::sleep(Duration::from_millis(100));
+                unsafe { libc::pthread_kill(target as libc::pthread_t, libc::SIGUSR1) };
+            })
+        };
+        let wait = Some(Duration::from_millis(500));
+
+        let mut watcher = Watcher::new().unwrap();
+        let (_tx, rx) = socketpair().unwrap();
+        assert!(watcher.add_read(rx.as_raw_fd()).is_ok(), "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+
+        let signals = interrupt(1);
+        assert_eq!(watcher.poll_all(wait).unwrap_err().kind(), io::ErrorKind::Interrupted);
+        signals.join().unwrap();
+
+        watcher.eintr_policy(EintrPolicy::Retry);
+        let signals = interrupt(2);
+        let start = Instant::now();
+        assert!(watcher.poll_all(wait).unwrap().is_empty());
+        assert!(start.elapsed() >= Duration::from_millis(450), "retry cut the wait short");
+        signals.join().unwrap();
+
+        watcher.eintr_policy(EintrPolicy::RetryLimited(1));
+        let signals = interrupt(2);
+        assert_eq!(watcher.poll_all(wait).unwrap_err().kind(), io::ErrorKind::Interrupted);
+        signals.join().unwrap();
+    }
+
+    #[test]
+    fn test_shutdown_token() {
+        let mut watcher = Watcher::new().unwrap();
+        let (_tx, rx) = socketpair().unwrap();
+        assert!(watcher.add_read(rx.as_raw_fd()).is_ok(), "add failed");
+        let token = watcher.shutdown_token().unwrap();
+        assert!(watcher.watch().is_ok(), "watch failed");
+
+        // a token taken after watch() works as well
+        let late = watcher.shutdown_token().unwrap();
+        late.signal().unwrap();
+        match watcher.poll(Some(Duration::from_secs(1))).unwrap().data {
+            EventData::Shutdown => (),
+            data => panic!("unexpected event: {:?}", data),
+        };
+
+        let iterating = thread::spawn(move || watcher.iter().count());
+        thread::sleep(Duration::from_millis(100));
+        token.signal().unwrap();
+        assert_eq!(iterating.join().unwrap(), 0);
+
+        // without the runtime ignoring it, SIGPIPE would kill the process
+        let previous = unsafe { libc::signal(libc::SIGPIPE, libc::SIG_DFL) };
+        let result = token.signal();
+        unsafe { libc::signal(libc::SIGPIPE, previous) };
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn test_reused_fd() {
+        let old_name = "/tmp/testing_reused_fd_old.txt";
+        let new_name = "/tmp/testing_reused_fd_new.txt";
+        fs::File::create(old_name).unwrap();
+        let mut new_file = fs::File::create(new_name).unwrap();
+
+        let mut watcher = Watcher::new().unwrap();
+        assert!(watcher.add_filename(old_name, EventFilter::EVFILT_VNODE, NOTE_WRITE).is_ok(),
+                "add failed");
+        let fd = watcher.watches().next().unwrap().ident().as_fd().unwrap();
+
+        // close the watched fd behind the watcher's back, reusing its number
+        let reopened = fs::File::open(new_name).unwrap();
+        assert_eq!(unsafe { libc::dup2(reopened.as_raw_fd(), fd) }, fd);
+        let owned = unsafe { OwnedFd::from_raw_fd(fd) };
+        assert!(watcher.add_owned_fd(owned, EventFilter::EVFILT_VNODE, NOTE_WRITE).is_ok(),
+                "add failed");
+        assert_eq!(watcher.watches().count(), 1);
+        assert!(watcher.watch().is_ok(), "watch failed");
+
+        new_file.write_all(b"x").unwrap();
+        let ev = watcher.poll(Some(Duration::from_secs(1))).unwrap();
+        match ev.ident {
+            Ident::Fd(ident_fd) => assert_eq!(ident_fd, fd),
+            ident => panic!("event for stale ident {:?}", ident),
+        }
+
+        drop(watcher);
+        assert_eq!(unsafe { libc::fcntl(fd, libc::F_GETFD) }, -1, "reused fd left open");
+        fs::remove_file(old_name).unwrap();
+        fs::remove_file(new_name).unwrap();
+    }
+
+    #[test]
+    fn test_add_process() {
+        let mut watcher = Watcher::new().unwrap();
+        let mut running = Command::new("sh").arg("-c").arg("sleep 1; exit 3").spawn().unwrap();
+        let mut exited = Command::new("sh").arg("-c").arg("exit 4").spawn().unwrap();
+        // let it become a zombie, which the kernel refuses on some platforms
+        thread::sleep(Duration::from_millis(200));
+
+        assert!(watcher.add_process(&mut running).is_ok(), "add failed");
+        assert!(watcher.add_process(&mut exited).is_ok(), "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+
+        let mut codes = Vec::new();
+        for _ in 0..2 {
+            let ev = watcher.poll(Some(Duration::from_secs(5))).unwrap();
+            let code = match ev.data {
+                EventData::Proc(ref exited) => exited.exit_info().expect("not an exit").code,
+                _ => panic!("unexpected event: {:?}", ev),
+            };
+            codes.push((ev.ident, code));
+        }
+        assert!(codes.contains(&(Ident::Pid(exited.id() as libc::pid_t), Some(4))),
+                "exits: {:?}",
+                codes);
+        assert!(codes.contains(&(Ident::Pid(running.id() as libc::pid_t), Some(3))),
+                "exits: {:?}",
+                codes);
+
+        assert_eq!(exited.wait().unwrap().code(), Some(4));
+        assert_eq!(running.wait().unwrap().code(), Some(3));
+    }
+
+    #[test]
+    fn test_poll_into() {
+        let mut watcher = Watcher::new().unwrap();
+        let (mut first_tx, first_rx) = UnixStream::pair().unwrap();
+        let (mut second_tx, second_rx) = UnixStream::pair().unwrap();
+        assert!(watcher.add_read_level(first_rx.as_raw_fd()).is_ok(), "add failed");
+        assert!(watcher.add_read_level(second_rx.as_raw_fd()).is_ok(), "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+
+        let mut buf = Vec::with_capacity(8);
+        let storage = buf.as_ptr();
+        assert!(watcher.poll_into(&mut buf, None).unwrap().is_empty());
+
+        first_tx.write_all(b"x").unwrap();
+        second_tx.write_all(b"x").unwrap();
+        for _ in 0..3 {
+            let events = watcher.poll_into(&mut buf, Some(Duration::from_secs(1))).unwrap();
+            assert_eq!(events.len(), 2, "events: {:?}", events);
+        }
+
+        // the same allocation is reused every time
+        assert_eq!(buf.as_ptr(), storage);
+        assert_eq!(buf.capacity(), 8);
+
+        // a full buffer leaves the rest for the next call
+        let mut small = Vec::with_capacity(1);
+        assert_eq!(watcher.poll_into(&mut small, None).unwrap().len(), 1);
+        assert_eq!(small.capacity(), 1);
+    }
+
+    #[test]
+    fn test_sort_events_by_ident() {
+        let mut watcher = Watcher::new().unwrap();
+        watcher.sort_events_by_ident();
+        let mut pairs: Vec<(UnixStream, UnixStream)> =
+            (0..6).map(|_| UnixStream::pair().unwrap()).collect();
+        // registered (and made ready) in descending fd order
+        for &(_, ref rx) in pairs.iter().rev() {
+            assert!(watcher.add_read_level(rx.as_raw_fd()).is_ok(), "add failed");
+        }
+        assert!(watcher.watch().is_ok(), "watch failed");
+        for &mut (ref mut tx, _) in pairs.iter_mut().rev() {
+            tx.write_all(b"x").unwrap();
+        }
+
+        let mut fds: Vec<i32> = pairs.iter().map(|&(_, ref rx)| rx.as_raw_fd()).collect();
+        fds.sort();
+        let idents = |events: &[Event]| -> Vec<i32> {
+            events.iter().map(|ev| ev.ident.as_fd().unwrap()).collect()
+        };
+        assert_eq!(idents(&watcher.poll_all(Some(Duration::from_secs(1))).unwrap()), fds);
+
+        let mut buf = Vec::with_capacity(16);
+        assert_eq!(idents(watcher.poll_into(&mut buf, Some(Duration::from_secs(1))).unwrap()),
+                   fds);
+    }
+
+    #[test]
+    fn test_wait_readable() {
+        let (mut first_tx, first_rx) = UnixStream::pair().unwrap();
+        let (_second_tx, second_rx) = UnixStream::pair().unwrap();
+        let fds = [first_rx.as_raw_fd(), second_rx.as_raw_fd()];
+
+        assert!(wait_readable(&fds, None).unwrap().is_empty());
+        first_tx.write_all(b"x").unwrap();
+        assert_eq!(wait_readable(&fds, Some(Duration::from_secs(1))).unwrap(),
+                   vec![first_rx.as_raw_fd()]);
+        // level-triggered and stateless: still readable on the next call
+        assert_eq!(wait_readable(&fds, None).unwrap(), vec![first_rx.as_raw_fd()]);
+    }
+
+    #[test]
+    fn test_watch_data() {
+        let mut watcher = Watcher::new().unwrap();
+        let (mut tx, rx) = UnixStream::pair().unwrap();
+        let (_other_tx, other_rx) = UnixStream::pair().unwrap();
+        assert!(watcher.watch_fd(rx.as_raw_fd()).read().token(42).submit().is_ok(),
+                "add failed");
+        assert!(watcher.add_read(other_rx.as_raw_fd()).is_ok(), "add failed");
+
+        let ident = Ident::Fd(rx.as_raw_fd());
+        assert_eq!(watcher.watch_data(&ident, EventFilter::EVFILT_READ), Some(42));
+        assert_eq!(watcher.watch_data(&ident, EventFilter::EVFILT_WRITE), None);
+        assert_eq!(watcher.watch_data(&Ident::Fd(other_rx.as_raw_fd()), EventFilter::EVFILT_READ),
+                   Some(0));
+
+        assert!(watcher.watch().is_ok(), "watch failed");
+        tx.write_all(b"x").unwrap();
+        let ev = watcher.poll(Some(Duration::from_secs(1))).unwrap();
+        assert_eq!(ev.token(), 42);
+    }
+
+    #[test]
+    fn test_vnode_level_vs_edge() {
+        let filename = "/tmp/testing_vnode_level_vs_edge.txt";
+        let mut file = fs::File::create(filename).unwrap();
+
+        let mut edge = Watcher::new().unwrap();
+        assert!(edge.add_filename(filename, EventFilter::EVFILT_VNODE, NOTE_WRITE).is_ok(),
+                "add failed");
+        assert!(edge.watch().is_ok(), "watch failed");
+        let mut level = Watcher::new().unwrap();
+        assert!(level.add_filename_level(filename, NOTE_WRITE).is_ok(), "add failed");
+        assert!(level.watch().is_ok(), "watch failed");
+
+        file.write_all(b"first").unwrap();
+        file.write_all(b"second").unwrap();
+
+        // both writes coalesce into one event, after which the edge watch
+        // stays quiet while the level watch keeps reporting
+        for watcher in &[&edge, &level] {
+            match watcher.poll(Some(Duration::from_secs(1))) {
+                Some(Event { data: EventData::Vnode(Vnode::Write { .. }), .. }) => (),
+                other => panic!("unexpected event: {:?}", other),
+            }
+        }
+        assert!(edge.poll(Some(Duration::from_millis(50))).is_none(), "writes not coalesced");
+        match level.poll(Some(Duration::from_millis(50))) {
+            Some(Event { data: EventData::Vnode(Vnode::Write { .. }), .. }) => (),
+            other => panic!("level watch forgot the write: {:?}", other),
+        }
+
+        fs::remove_file(filename).unwrap();
+    }
+
+    #[test]
+    fn test_watch_config_round_trip() {
+        let filename = "/tmp/testing_watch_config.txt";
+        fs::File::create(filename).unwrap();
+        let (_tx, rx) = UnixStream::pair().unwrap();
+
+        let mut watcher = Watcher::new().unwrap();
+        assert!(watcher.add_filename(filename, EventFilter::EVFILT_VNODE, NOTE_WRITE).is_ok(),
+                "add failed");
+        assert!(watcher.add_read(rx.as_raw_fd()).is_ok(), "add failed");
+        assert!(watcher.add_pid(1, EventFilter::EVFILT_PROC, NOTE_EXIT).is_ok(), "add failed");
+
+        // fds are left out
+        let cfg = watcher.export_watch_config();
+        assert_eq!(cfg.files.len(), 1);
+        assert_eq!(cfg.files[0].path, filename);
+        assert_eq!(cfg.pids.len(), 1);
+        assert_eq!(cfg.pids[0].pid, 1);
+
+        let mut restored = Watcher::new().unwrap();
+        assert!(restored.import_watch_config(&cfg).is_ok(), "import failed");
+        assert!(restored.watch().is_ok(), "watch failed");
+        assert_eq!(restored.export_watch_config(), cfg);
+
+        let mut bogus = cfg.clone();
+        bogus.files[0].filter = EventFilter::EVFILT_PROC as i16;
+        assert_eq!(restored.import_watch_config(&bogus).unwrap_err().kind(),
+                   io::ErrorKind::InvalidInput);
+
+        fs::remove_file(filename).unwrap();
+    }
+
+    #[test]
+    fn test_tap() {
+        let mut watcher = Watcher::new().unwrap();
+        let (mut tx, rx) = UnixStream::pair().unwrap();
+        assert!(watcher.add_read(rx.as_raw_fd()).is_ok(), "add failed");
+        let token = watcher.shutdown_token().unwrap();
+        assert!(watcher.watch().is_ok(), "watch failed");
+
+        let seen = Arc::new(AtomicUsize::new(0));
+        let counter = seen.clone();
+        watcher.set_tap(Some(Box::new(move |_: &Event| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        })));
+
+        tx.write_all(b"x").unwrap();
+        assert!(watcher.poll(Some(Duration::from_secs(1))).is_some(), "no event");
+        assert!(watcher.poll(None).is_none(), "extra event");
+        assert_eq!(seen.load(Ordering::SeqCst), 1);
+
+        tx.write_all(b"x").unwrap();
+        assert!(watcher.iter().next().is_some(), "no event");
+        token.signal().unwrap();
+        assert_eq!(watcher.iter().count(), 0);
+        // the shutdown ending the iterator went past the tap as well
+        assert_eq!(seen.load(Ordering::SeqCst), 3);
+
+        watcher.set_tap(None);
+        tx.write_all(b"x").unwrap();
+        assert!(watcher.poll(Some(Duration::from_secs(1))).is_some(), "no event");
+        assert_eq!(seen.load(Ordering::SeqCst), 3);
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn test_proc_notes_combined() {
+        let mut child = Command::new("sh").args(["-c", "sleep 0.3; true & wait"]).spawn().unwrap();
+        let pid = child.id() as libc::pid_t;
+        let mut watcher = Watcher::new().unwrap();
+
+        let notes = NOTE_TRACK | NOTE_FORK | NOTE_EXIT;
+        assert!(watcher.add_pid(pid, EventFilter::EVFILT_PROC, notes).is_ok(), "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+        // not polling meanwhile lets the fork and the exit coalesce
+        assert!(child.wait().unwrap().success());
+
+        let events = watcher.poll_all(Some(Duration::from_secs(1))).unwrap();
+        let parent = events.iter()
+            .find(|ev| ev.ident == Ident::Pid(pid))
+            .expect("no event for the parent");
+        let parent_notes = parent.proc_notes();
+        assert!(parent_notes.iter().any(|note| matches!(*note, Proc::Exit(_))),
+                "notes: {:?}",
+                parent_notes);
+        assert!(parent_notes.iter().any(|note| matches!(*note, Proc::Fork)),
+                "notes: {:?}",
+                parent_notes);
+
+        // the tracked child got a watch of its own
+        assert!(events.iter()
+                    .filter(|ev| ev.ident != Ident::Pid(pid))
+                    .any(|ev| ev.proc_notes().iter().any(|note| matches!(*note, Proc::Child(_)))),
+                "events: {:?}",
+                events);
+    }
+
+    #[test]
+    fn test_watch_intents() {
+        let filename = "/tmp/testing_watch_intents.txt";
+        let renamed = "/tmp/testing_watch_intents_renamed.txt";
+        let mut file = fs::File::create(filename).unwrap();
+
+        let expect = |watcher: &Watcher, what: &str| -> Vnode {
+            match watcher.poll(Some(Duration::from_secs(1))) {
+                Some(Event { data: EventData::Vnode(vnode), .. }) => vnode,
+                other => panic!("{}: unexpected event: {:?}", what, other),
+            }
+        };
+        let mut modifications = Watcher::new().unwrap();
+        assert!(modifications.watch_modifications(filename).is_ok(), "add failed");
+        assert!(modifications.watch().is_ok(), "watch failed");
+        let mut deletions = Watcher::new().unwrap();
+        assert!(deletions.watch_deletions(filename).is_ok(), "add failed");
+        assert!(deletions.watch().is_ok(), "watch failed");
+        let mut metadata = Watcher::new().unwrap();
+        assert!(metadata.watch_metadata(filename).is_ok(), "add failed");
+        assert!(metadata.watch().is_ok(), "watch failed");
+
+        file.write_all(b"x").unwrap();
+        match expect(&modifications, "write") {
+            Vnode::Write { .. } | Vnode::Extend { .. } => (),
+            other => panic!("write: unexpected vnode event: {:?}", other),
+        }
+        assert!(deletions.poll(None).is_none(), "write reported as deletion");
+        assert!(metadata.poll(None).is_none(), "write reported as metadata change");
 
-    pub fn iter(&self) -> EventIter {
-        EventIter { watcher: self }
-    }
-}
+        let mut perms = fs::metadata(filename).unwrap().permissions();
+        perms.set_readonly(true);
+        fs::set_permissions(filename, perms).unwrap();
+        match expect(&metadata, "chmod") {
+            Vnode::Attrib => (),
+            other => panic!("chmod: unexpected vnode event: {:?}", other),
+        }
+        assert!(deletions.poll(None).is_none(), "chmod reported as deletion");
 
-impl Drop for Watcher {
-    fn drop(&mut self) {
-        unsafe { libc::close(self.queue) };
-        for watched in &self.watched {
-            match watched.ident {
-                Ident::Fd(fd) => unsafe { libc::close(fd) },
-                Ident::Filename(fd, _) => unsafe { libc::close(fd) },
-                _ => continue,
-            };
+        fs::rename(filename, renamed).unwrap();
+        match expect(&deletions, "rename") {
+            Vnode::Rename => (),
+            other => panic!("rename: unexpected vnode event: {:?}", other),
         }
+
+        fs::remove_file(renamed).unwrap();
     }
-}
 
-fn find_file_ident(watcher: &Watcher, fd: RawFd) -> Option<Ident> {
-    for watched in &watcher.watched {
-        match watched.ident.clone() {
-            Ident::Fd(ident_fd) => {
-                if fd == ident_fd {
-                    return Some(Ident::Fd(fd));
-                } else {
-                    continue;
-                }
-            }
-            Ident::Filename(ident_fd, ident_str) => {
-                if fd == ident_fd {
-                    return Some(Ident::Filename(ident_fd, ident_str));
-                } else {
-                    continue;
-                }
-            }
-            _ => continue,
-        }
+    #[test]
+    fn test_retry_policy() {
+        let transient = || io::Error::from_raw_os_error(libc::ENOMEM);
+        let policy = RetryPolicy {
+            retries: 2,
+            backoff: Duration::from_millis(1),
+        };
+        assert!(policy.retries_after(&transient(), 0));
+        assert!(policy.retries_after(&io::Error::from_raw_os_error(libc::EAGAIN), 1));
+        assert!(!policy.retries_after(&transient(), 2), "retried too often");
+        assert!(!policy.retries_after(&io::Error::from_raw_os_error(libc::EBADF), 0),
+                "retried a permanent error");
+        assert!(!RetryPolicy::default().retries_after(&transient(), 0));
+
+        // permanent failures still surface at once
+        let mut watcher = Watcher::new().unwrap();
+        watcher.retry_policy(RetryPolicy {
+            retries: 3,
+            backoff: Duration::from_secs(1),
+        });
+        assert!(watcher.add_pid(i32::MAX, EventFilter::EVFILT_PROC, NOTE_EXIT).is_ok(),
+                "add failed");
+        let started = Instant::now();
+        assert!(watcher.watch().is_err(), "missing pid accepted");
+        assert!(started.elapsed() < Duration::from_secs(1), "permanent error retried");
     }
 
-    None
-}
+    #[test]
+    fn test_watch_set_empty() {
+        let mut first = Command::new("sleep").arg("0.1").spawn().unwrap();
+        let mut second = Command::new("sleep").arg("0.2").spawn().unwrap();
+        let mut watcher = Watcher::new().unwrap();
 
-fn get_event(watcher: &Watcher, timeout: Option<Duration>) -> Option<Event> {
-    let mut kev = kevent {
-        ident: 0,
-        data: 0,
-        filter: EventFilter::EVFILT_SYSCOUNT,
-        fflags: FilterFlag::empty(),
-        flags: EventFlag::empty(),
-        udata: ptr::null_mut(),
-    };
+        for child in &[&first, &second] {
+            assert!(watcher.add_pid(child.id() as libc::pid_t, EventFilter::EVFILT_PROC, NOTE_EXIT)
+                        .is_ok(),
+                    "add failed");
+        }
+        assert!(watcher.watch().is_ok(), "watch failed");
 
-    let tspec = match timeout {
-        Some(ts) => {
-            &timespec {
-                tv_sec: ts.as_secs() as i64,
-                tv_nsec: ts.subsec_nanos() as i64,
+        for _ in 0..2 {
+            match watcher.poll(Some(Duration::from_secs(5))) {
+                Some(Event { data: EventData::Proc(Proc::Exit(_)), .. }) => (),
+                other => panic!("unexpected event: {:?}", other),
             }
         }
-        None => ptr::null(),
-    };
+        match watcher.poll(Some(Duration::from_secs(5))) {
+            Some(Event { data: EventData::WatchSetEmpty, .. }) => (),
+            other => panic!("set not reported empty: {:?}", other),
+        }
+        assert_eq!(watcher.watches().count(), 0);
+        assert!(watcher.poll(None).is_none(), "reported empty twice");
 
-    let ret = unsafe { kevent(watcher.queue, ptr::null(), 0, &mut kev, 1, tspec) };
-    match ret {
-        -1 => Some(Event::from_error(kev, watcher)),
-        0 => None,  // timeout expired
-        _ => Some(Event::new(kev, watcher)),
+        first.wait().unwrap();
+        second.wait().unwrap();
     }
-}
 
-// OS specific
-// TODO: Events can have more than one filter flag
-impl Event {
-    pub fn new(ev: kevent, watcher: &Watcher) -> Event {
-        let data = match ev.filter {
-            EventFilter::EVFILT_READ => EventData::ReadReady(ev.data as usize),
-            EventFilter::EVFILT_WRITE => EventData::WriteReady(ev.data as usize),
-            EventFilter::EVFILT_SIGNAL => EventData::Signal(ev.data as usize),
-            EventFilter::EVFILT_TIMER => EventData::Timer(ev.data as usize),
-            EventFilter::EVFILT_PROC => {
-                let inner = if ev.fflags.contains(NOTE_EXIT) {
-                    Proc::Exit(ev.data as usize)
-                } else if ev.fflags.contains(NOTE_FORK) {
-                    Proc::Fork
-                } else if ev.fflags.contains(NOTE_EXEC) {
-                    Proc::Exec
-                } else if ev.fflags.contains(NOTE_TRACK) {
-                    Proc::Track(ev.data as libc::pid_t)
-                } else if ev.fflags.contains(NOTE_CHILD) {
-                    Proc::Child(ev.data as libc::pid_t)
-                } else {
-                    panic!("not supported: {:?}", ev.fflags)
-                };
+    #[test]
+    fn test_final_event() {
+        let mut watcher = Watcher::new().unwrap();
+        let (mut tx, rx) = UnixStream::pair().unwrap();
+        let (mut other_tx, other_rx) = UnixStream::pair().unwrap();
+        assert!(watcher.watch_fd(rx.as_raw_fd()).read().oneshot().submit().is_ok(),
+                "submit failed");
+        assert!(watcher.add_read(other_rx.as_raw_fd()).is_ok(), "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
 
-                EventData::Proc(inner)
-            }
-            EventFilter::EVFILT_VNODE => {
-                let inner = if ev.fflags.contains(NOTE_DELETE) {
-                    Vnode::Delete
-                } else if ev.fflags.contains(NOTE_WRITE) {
-                    Vnode::Write
-                } else if ev.fflags.contains(NOTE_EXTEND) {
-                    Vnode::Extend
-                } else if ev.fflags.contains(NOTE_ATTRIB) {
-                    Vnode::Attrib
-                } else if ev.fflags.contains(NOTE_LINK) {
-                    Vnode::Link
-                } else if ev.fflags.contains(NOTE_RENAME) {
-                    Vnode::Rename
-                } else if ev.fflags.contains(NOTE_REVOKE) {
-                    Vnode::Revoke
-                } else {
-                    panic!("not supported")
-                };
+        other_tx.write_all(b"foo").unwrap();
+        let ev = watcher.poll(Some(Duration::from_secs(1))).unwrap();
+        assert!(!ev.is_final(), "persistent watch reported final");
 
-                EventData::Vnode(inner)
-            }
-            _ => panic!("not supported"),
-        };
+        tx.write_all(b"foo").unwrap();
+        let ev = watcher.wait_for(&Ident::Fd(rx.as_raw_fd()), Some(Duration::from_secs(1)))
+            .unwrap()
+            .unwrap();
+        assert!(ev.is_final(), "one-shot event not final");
+        assert_eq!(watcher.watches().count(), 1);
+    }
 
-        let ident = match ev.filter {
-            EventFilter::EVFILT_READ => find_file_ident(watcher, ev.ident as RawFd).unwrap(),
-            EventFilter::EVFILT_WRITE => find_file_ident(watcher, ev.ident as RawFd).unwrap(),
-            EventFilter::EVFILT_VNODE => find_file_ident(watcher, ev.ident as RawFd).unwrap(),
-            EventFilter::EVFILT_SIGNAL => Ident::Signal(ev.ident as i32),
-            EventFilter::EVFILT_TIMER => Ident::Timer(ev.ident as i32),
-            EventFilter::EVFILT_PROC => Ident::Pid(ev.ident as pid_t),
-            _ => panic!("not supported"),
+    #[test]
+    fn test_add_lines() {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let mut tx = unsafe { fs::File::from_raw_fd(fds[1]) };
+        let rx = unsafe { OwnedFd::from_raw_fd(fds[0]) };
+
+        let mut watcher = Watcher::new().unwrap();
+        assert!(watcher.add_lines(rx.as_raw_fd()).is_ok(), "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+
+        let line = |watcher: &Watcher| match watcher.poll(Some(Duration::from_secs(1))) {
+            Some(Event { data: EventData::Line(text), .. }) => text,
+            other => panic!("unexpected event: {:?}", other),
         };
 
-        Event {
-            ident: ident,
-            data: data,
+        tx.write_all(b"hel").unwrap();
+        assert!(watcher.poll(Some(Duration::from_millis(50))).is_none(), "partial line");
+        tx.write_all(b"lo\nwor").unwrap();
+        assert_eq!(line(&watcher), "hello");
+        tx.write_all(b"ld\n\nbye").unwrap();
+        assert_eq!(line(&watcher), "world");
+        assert_eq!(line(&watcher), "");
+        assert!(watcher.poll(Some(Duration::from_millis(50))).is_none(), "partial line");
+
+        drop(tx);
+        assert_eq!(line(&watcher), "bye");
+        match watcher.poll(Some(Duration::from_secs(1))) {
+            Some(Event { data: EventData::ReadReady(0), .. }) => (),
+            other => panic!("no end of file: {:?}", other),
         }
+
+        // a quiet start leaves lines already waiting unread
+        let (mut tx, rx) = UnixStream::pair().unwrap();
+        let mut quiet = Watcher::new().unwrap();
+        let key = quiet.add_lines(rx.as_raw_fd()).unwrap();
+        tx.write_all(b"early\n").unwrap();
+        assert!(quiet.quiet_start().watch().is_ok(), "watch failed");
+        assert!(quiet.poll(Some(Duration::from_millis(50))).is_none(), "ready line delivered");
+        let mut buf = [0u8; 6];
+        assert!((&rx).read_exact(&mut buf).is_ok(), "swallowed line was read");
+        assert_eq!(&buf, b"early\n");
+
+        tx.write_all(b"par").unwrap();
+        assert!(quiet.poll(Some(Duration::from_millis(50))).is_none(), "partial line");
+        assert!(quiet.remove(key).is_ok(), "remove failed");
+        assert!(quiet.lines.borrow().is_empty(), "line buffer outlived its watch");
     }
 
-    pub fn from_error(ev: kevent, watcher: &Watcher) -> Event {
-        let ident = match ev.filter {
-            EventFilter::EVFILT_READ => find_file_ident(watcher, ev.ident as RawFd).unwrap(),
-            EventFilter::EVFILT_WRITE => find_file_ident(watcher, ev.ident as RawFd).unwrap(),
-            EventFilter::EVFILT_VNODE => find_file_ident(watcher, ev.ident as RawFd).unwrap(),
-            EventFilter::EVFILT_SIGNAL => Ident::Signal(ev.ident as i32),
-            EventFilter::EVFILT_TIMER => Ident::Timer(ev.ident as i32),
-            EventFilter::EVFILT_PROC => Ident::Pid(ev.ident as pid_t),
-            _ => panic!("not supported"),
+    #[test]
+    fn test_reconcile_gone_delete() {
+        let mut watcher = Watcher::new().unwrap();
+        let (_first_tx, first_rx) = UnixStream::pair().unwrap();
+        let (mut second_tx, second_rx) = UnixStream::pair().unwrap();
+        let first_fd = first_rx.as_raw_fd();
+        let second_fd = second_rx.as_raw_fd();
+        assert!(watcher.add_read(first_fd).is_ok(), "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+
+        // the kernel forgets the watch behind the watcher's back
+        let gone = kevent {
+            ident: first_fd as usize,
+            filter: EventFilter::EVFILT_READ,
+            flags: EV_DELETE,
+            fflags: FilterFlag::empty(),
+            data: 0,
+            udata: to_udata(0),
         };
+        let ret = unsafe { watcher.sys_kevent(&[gone], ptr::null_mut(), 0, ptr::null()) };
+        assert_eq!(ret, 0);
 
-        Event {
-            data: EventData::Error(io::Error::last_os_error()),
-            ident: ident,
+        let desired = vec![(Ident::Fd(second_fd), EventFilter::EVFILT_READ, FilterFlag::empty())];
+        assert!(watcher.reconcile(desired.clone()).is_ok(), "stale delete failed reconcile");
+        assert!(watcher.reconcile(desired).is_ok(), "reconcile not idempotent");
+        second_tx.write_all(b"x").unwrap();
+        match watcher.poll(Some(Duration::from_secs(1))) {
+            Some(Event { ident: Ident::Fd(fd), .. }) if fd == second_fd => (),
+            other => panic!("unexpected event: {:?}", other),
         }
-    }
 
-    pub fn is_err(&self) -> bool {
-        match self.data {
-            EventData::Error(_) => true,
-            _ => false,
-        }
+        // other rejections still surface
+        let missing = vec![(Ident::Pid(i32::MAX), EventFilter::EVFILT_PROC, NOTE_EXIT)];
+        assert!(watcher.reconcile(missing).is_err(), "missing pid accepted");
     }
-}
 
-impl<'a> Iterator for EventIter<'a> {
-    type Item = Event;
+    #[test]
+    fn test_drop_during_unwind() {
+        let filename = "/tmp/testing_drop_unwind.txt";
+        fs::File::create(filename).unwrap();
 
-    // rather than call kevent(2) each time, we can likely optimize and
-    // call it once for like 100 items
-    fn next(&mut self) -> Option<Self::Item> {
-        if !self.watcher.started {
-            return None;
-        }
+        // the read and vnode watches share one owned fd
+        let result = panic::catch_unwind(|| {
+            let mut watcher = Watcher::new().unwrap();
+            watcher.add_filename_rw(filename, NOTE_WRITE).unwrap();
+            let (write_fd, _) = watcher.add_pipe().unwrap();
+            watcher.watch().unwrap();
+            unsafe { libc::close(write_fd) };
+            panic!("unwinding through a watcher");
+        });
+        assert!(result.is_err(), "closure didn't panic");
 
-        get_event(self.watcher, None)
+        fs::remove_file(filename).unwrap();
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use std::fs;
-    use std::io::Write;
-    use super::{Watcher, EventFilter, EventData, NOTE_WRITE, Vnode, Ident};
 
     #[test]
-    fn test_new_watcher() {
+    fn test_drop_closes_shared_fd_once() {
+        let (_tx, rx) = UnixStream::pair().unwrap();
+        // far above what the other tests open, so nothing reuses the number
+        // once the watcher closes it
+        let fd = unsafe { libc::fcntl(rx.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 900) };
+        assert!(fd >= 900, "dup failed");
+        drop(rx);
         let mut watcher = Watcher::new().unwrap();
-        let file = fs::File::create("testing.txt").unwrap();
-
-        assert!(watcher.add_file(&file, EventFilter::EVFILT_VNODE, NOTE_WRITE).is_ok(),
+        let owned = unsafe { OwnedFd::from_raw_fd(fd) };
+        assert!(watcher.add_owned_fd(owned, EventFilter::EVFILT_READ, FilterFlag::empty()).is_ok(),
                 "add failed");
+
+        let mut write = watcher.watched.borrow()[0].clone();
+        write.filter = EventFilter::EVFILT_WRITE;
+        watcher.watched.get_mut().push(write);
         assert!(watcher.watch().is_ok(), "watch failed");
+
+        assert_eq!(owned_fds(&watcher.watched.borrow()), vec![fd]);
+        drop(watcher);
+        assert_eq!(unsafe { libc::fcntl(fd, libc::F_GETFD) }, -1, "fd left open");
+        assert_eq!(io::Error::last_os_error().raw_os_error(), Some(libc::EBADF));
     }
 
     #[test]
-    fn test_filename() {
-        let filename = "/tmp/testing.txt";
-        let mut watcher = match Watcher::new() {
-            Ok(wat) => wat,
-            Err(_) => panic!("new failed"),
-        };
+    fn test_arm_write() {
+        let (mut peer, sock) = UnixStream::pair().unwrap();
+        let fd = sock.as_raw_fd();
+        let mut watcher = Watcher::new().unwrap();
+        assert!(watcher.add_read(fd).is_ok(), "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+        assert!(watcher.poll(Some(Duration::from_millis(50))).is_none(), "readable too early");
 
-        {
-            assert!(fs::File::create(filename).is_ok(), "file creation failed");
-        };
+        let calls = watcher.syscall_count();
+        assert!(watcher.arm_write(fd).is_ok(), "arm failed");
+        assert_eq!(watcher.syscall_count(), calls + 1);
+        match watcher.poll(Some(Duration::from_secs(1))) {
+            Some(Event { data: EventData::WriteReady(_), .. }) => (),
+            other => panic!("not writable: {:?}", other),
+        }
 
-        assert!(watcher.add_filename(filename, EventFilter::EVFILT_VNODE, NOTE_WRITE).is_ok(),
-                "add failed");
+        let calls = watcher.syscall_count();
+        assert!(watcher.disarm_write(fd).is_ok(), "disarm failed");
+        assert_eq!(watcher.syscall_count(), calls + 1);
+        assert!(watcher.disarm_write(fd).is_ok(), "second disarm failed");
+        assert!(watcher.poll(Some(Duration::from_millis(50))).is_none(),
+                "write event after disarm");
+
+        // the read watch is left alone
+        peer.write_all(b"foo").unwrap();
+        match watcher.poll(Some(Duration::from_secs(1))) {
+            Some(Event { data: EventData::ReadReady(3), .. }) => (),
+            other => panic!("read watch lost: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_timers() {
+        let (mut tx, rx) = UnixStream::pair().unwrap();
+        let mut watcher = Watcher::new().unwrap();
+        assert!(watcher.add_read(rx.as_raw_fd()).is_ok(), "add failed");
+        assert!(watcher.add_timer(7, Duration::from_millis(100)).is_ok(), "add failed");
         assert!(watcher.watch().is_ok(), "watch failed");
 
-        let mut new_file = match fs::OpenOptions::new().write(true).open(filename) {
-            Ok(fil) => fil,
-            Err(_) => panic!("open failed"),
-        };
+        tx.write_all(b"foo").unwrap();
+        let (id, expirations) = watcher.timers().next().unwrap().unwrap();
+        assert_eq!(id, 7);
+        assert!(expirations >= 1);
 
-        assert!(new_file.write_all(b"foo").is_ok(), "write failed");
-        let ev = watcher.iter().next().unwrap();
-        match ev.data {
-            EventData::Vnode(Vnode::Write) => assert!(true),
-            _ => assert!(false),
-        };
+        // the read event was skipped, not lost
+        match watcher.poll(None) {
+            Some(Event { data: EventData::ReadReady(3), .. }) => (),
+            other => panic!("read event lost: {:?}", other),
+        }
+        assert!(Watcher::new().unwrap().signals().next().is_none(), "unstarted watcher iterated");
 
-        match ev.ident {
-            Ident::Filename(_, name) => assert!(name == filename),
-            _ => assert!(false),
-        };
+        // error events of the timers aren't skipped
+        let err = io::Error::from_raw_os_error(libc::EINVAL);
+        watcher.pending.borrow_mut().push_back(Event::from_parts(Ident::Timer(7),
+                                                                 EventData::Error(err),
+                                                                 EventFilter::EVFILT_TIMER));
+        let err = watcher.timers().next().unwrap().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
     }
 
     #[test]
-    fn test_file() {
-        let filename = "/tmp/testing.txt";
-        let mut watcher = match Watcher::new() {
-            Ok(wat) => wat,
-            Err(_) => panic!("new failed"),
+    fn test_vnode_sizes() {
+        let filename = "/tmp/testing_vnode_sizes.txt";
+        let mut file = fs::File::create(filename).unwrap();
+        let mut watcher = Watcher::new().unwrap();
+        let mut skipping = Watcher::new().unwrap();
+        for watcher in &mut [&mut watcher, &mut skipping] {
+            assert!(watcher.add_filename(filename, EventFilter::EVFILT_VNODE, NOTE_WRITE).is_ok(),
+                    "add failed");
+        }
+        assert!(watcher.watch().is_ok(), "watch failed");
+        assert!(skipping.skip_vnode_sizes().watch().is_ok(), "watch failed");
+
+        let size = |watcher: &Watcher| match watcher.poll(Some(Duration::from_secs(1))) {
+            Some(Event { data: EventData::Vnode(Vnode::Write { new_size }), .. }) => new_size,
+            other => panic!("unexpected event: {:?}", other),
         };
 
-        let file_res = fs::File::create(filename);
-        assert!(file_res.is_ok(), "file creation failed");
-        let mut file = file_res.unwrap();
+        file.write_all(b"foo").unwrap();
+        assert_eq!(size(&watcher), 3);
+        assert_eq!(size(&skipping), 0);
+        file.write_all(b"barbaz").unwrap();
+        assert_eq!(size(&watcher), 9);
 
-        assert!(watcher.add_file(&file, EventFilter::EVFILT_VNODE, NOTE_WRITE).is_ok(),
-                "add failed");
+        fs::remove_file(filename).unwrap();
+    }
+
+    #[test]
+    fn test_remove_keeps_other_buffered() {
+        let (mut tx, rx) = UnixStream::pair().unwrap();
+        let fd = rx.as_raw_fd();
+        let mut watcher = Watcher::new().unwrap();
+        let read = watcher.add_read(fd).unwrap();
+        let write = watcher.add_write(fd).unwrap();
         assert!(watcher.watch().is_ok(), "watch failed");
-        assert!(file.write_all(b"foo").is_ok(), "write failed");
-        let ev = watcher.iter().next().unwrap();
-        match ev.data {
-            EventData::Vnode(Vnode::Write) => assert!(true),
-            _ => assert!(false),
-        };
+        tx.write_all(b"foo").unwrap();
+        thread::sleep(Duration::from_millis(50));
 
-        match ev.ident {
-            Ident::Fd(_) => assert!(true),
-            _ => assert!(false),
+        // one event is handed out, the other one stays buffered
+        let first = watcher.poll(Some(Duration::from_secs(1))).unwrap();
+        let (gone, left) = if first.filter() == EventFilter::EVFILT_READ {
+            (read, EventFilter::EVFILT_WRITE)
+        } else {
+            (write, EventFilter::EVFILT_READ)
         };
+        assert!(watcher.remove(gone).is_ok(), "remove failed");
+        match watcher.poll(None) {
+            Some(ref ev) if ev.filter() == left => (),
+            other => panic!("buffered event of the other watch lost: {:?}", other),
+        }
     }
 
     #[test]
-    fn test_delete_filename() {
-        let filename = "/tmp/testing.txt";
-        let mut watcher = match Watcher::new() {
-            Ok(wat) => wat,
-            Err(_) => panic!("new failed"),
-        };
+    fn test_clone_replace_keeps_original_fd() {
+        let filename = "/tmp/testing_clone_replace.txt";
+        let tmpname = "/tmp/testing_clone_replace.txt.tmp";
+        fs::File::create(filename).unwrap();
+        let mut watcher = Watcher::new().unwrap();
+        assert!(watcher.watch_path_persistent(filename, NOTE_WRITE).is_ok(), "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+        let old_fd = watcher.watched.borrow()[0].ident.as_fd().unwrap();
 
-        {
-            assert!(fs::File::create(filename).is_ok(), "file creation failed");
-        };
+        let clone = watcher.try_clone().unwrap();
+        fs::File::create(tmpname).unwrap();
+        fs::rename(tmpname, filename).unwrap();
+        match clone.poll(Some(Duration::from_secs(1))) {
+            Some(Event { data: EventData::Vnode(Vnode::Replace), .. }) => (),
+            other => panic!("unexpected event: {:?}", other),
+        }
 
-        assert!(watcher.add_filename(filename, EventFilter::EVFILT_VNODE, NOTE_WRITE).is_ok(),
-                "add failed");
+        // the clone owns the fd it opened and left the original's alone
+        assert!(unsafe { libc::fcntl(old_fd, libc::F_GETFD) } != -1, "original's fd closed");
+        assert_eq!(clone.is_owned(&Ident::Filename(0, filename.to_string())), Some(true));
+        fs::remove_file(filename).unwrap();
+    }
+
+    #[test]
+    fn test_clone_create_keeps_original_fd() {
+        let filename = "/tmp/testing_clone_create.txt";
+        let _ = fs::remove_file(filename);
+        let mut watcher = Watcher::new().unwrap();
+        assert!(watcher.watch_path_create(filename, NOTE_WRITE).is_ok(), "add failed");
         assert!(watcher.watch().is_ok(), "watch failed");
-        assert!(watcher.remove_filename(filename, EventFilter::EVFILT_VNODE).is_ok(),
-                "delete failed");
+        let dir_fd = watcher.watched.borrow()[0].ident.as_fd().unwrap();
+
+        let clone = watcher.try_clone().unwrap();
+        fs::File::create(filename).unwrap();
+        match clone.poll(Some(Duration::from_secs(1))) {
+            Some(Event { data: EventData::Vnode(Vnode::Create), .. }) => (),
+            other => panic!("unexpected event: {:?}", other),
+        }
+
+        // the clone owns the file's fd and left the original's directory alone
+        assert!(unsafe { libc::fcntl(dir_fd, libc::F_GETFD) } != -1, "original's fd closed");
+        assert_eq!(clone.is_owned(&Ident::Filename(0, filename.to_string())), Some(true));
+        fs::remove_file(filename).unwrap();
     }
 
     #[test]
-    fn test_dupe() {
-        let filename = "/tmp/testing.txt";
-        let mut watcher = match Watcher::new() {
-            Ok(wat) => wat,
-            Err(_) => panic!("new failed"),
+    fn test_clone_tree_keeps_original_fds() {
+        let root = "/tmp/testing_clone_tree";
+        let _ = fs::remove_dir_all(root);
+        fs::create_dir_all(format!("{}/a/b", root)).unwrap();
+        let mut watcher = Watcher::new().unwrap();
+        assert!(watcher.add_tree(root, FilterFlag::empty()).is_ok(), "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+        let sub = format!("{}/a/b", root);
+        let sub_fd = watcher.watched
+            .borrow()
+            .iter()
+            .find(|w| w.ident == Ident::Filename(0, sub.clone()))
+            .and_then(|w| w.ident.as_fd())
+            .unwrap();
+
+        let mut clone = watcher.try_clone().unwrap();
+        fs::remove_dir(&sub).unwrap();
+        let parent = format!("{}/a", root);
+        let from_parent = |ev: &Event| match ev.ident {
+            Ident::Filename(_, ref path) => *path == parent,
+            _ => false,
         };
+        assert!(clone.poll_matching(from_parent, Some(Duration::from_secs(1))).unwrap().is_some());
 
-        {
-            assert!(fs::File::create(filename).is_ok(), "file creation failed");
+        // the clone forgot the removed directory but left its fd to the original
+        assert_eq!(clone.watches().count(), 2);
+        assert!(unsafe { libc::fcntl(sub_fd, libc::F_GETFD) } != -1, "original's fd closed");
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_unknown_filter_decoded() {
+        let watcher = Watcher::new().unwrap();
+        let kev = kevent {
+            ident: 7,
+            filter: EventFilter::EVFILT_SYSCOUNT,
+            flags: EventFlag::empty(),
+            fflags: NOTE_WRITE,
+            data: 42,
+            udata: to_udata(0),
+        };
+        let ev = Event::new(kev, &watcher);
+        assert!(ev.ident == Ident::Fd(7), "unexpected ident: {:?}", ev.ident);
+        match ev.data {
+            EventData::Other { notes, data: 42 } if notes == NOTE_WRITE.bits() => (),
+            _ => panic!("unexpected event: {:?}", ev),
         };
+    }
 
-        assert!(watcher.add_filename(filename, EventFilter::EVFILT_VNODE, NOTE_WRITE).is_ok(),
-                "add failed");
-        assert!(watcher.add_filename(filename, EventFilter::EVFILT_VNODE, NOTE_WRITE).is_ok(),
-                "second add failed");
+    #[test]
+    fn test_clone_polls_watch_added_later() {
+        let mut watcher = Watcher::new().unwrap();
+        assert!(watcher.watch().is_ok(), "watch failed");
+        let clone = watcher.try_clone().unwrap();
 
-        assert!(watcher.watched.len() == 1);
+        let (mut tx, rx) = UnixStream::pair().unwrap();
+        assert!(watcher.add_read(rx.as_raw_fd()).is_ok(), "add failed");
+        assert!(watcher.watch().is_ok(), "watch failed");
+        tx.write_all(b"x").unwrap();
+
+        // the clone doesn't know the watch but still gets its event
+        let ev = clone.poll(Some(Duration::from_secs(1))).unwrap();
+        assert!(ev.ident == Ident::Fd(rx.as_raw_fd()), "unexpected ident: {:?}", ev.ident);
+        match ev.data {
+            EventData::ReadReady(1) => (),
+            _ => panic!("unexpected event: {:?}", ev),
+        };
     }
 }